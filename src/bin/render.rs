@@ -0,0 +1,127 @@
+// Render a Standard MIDI File through BasicSynth to a WAV file, for
+// auditioning patches and golden-file regression tests. All channels play
+// the same synth, with the default MidiMapping.
+//
+// Usage: render <input.mid> <output.wav> [--sample-rate HZ] [--tail SECONDS]
+//        [--float]
+
+use std::env;
+use std::process;
+
+use audio_tools::midi::{MidiEvent, MidiMapping};
+use audio_tools::processor::Processor;
+use audio_tools::smf::{Event, Smf};
+use audio_tools::synth::BasicSynth;
+use audio_tools::wav::{SampleFormat, Wav};
+
+const BLOCK_SIZE: usize = 512;
+
+struct Options {
+    input: String,
+    output: String,
+    sample_rate: u32,
+    // Time rendered after the last event, for releases and effect tails
+    tail_seconds: f64,
+    format: SampleFormat,
+}
+
+fn usage() -> ! {
+    eprintln!(
+        "usage: render <input.mid> <output.wav> [--sample-rate HZ] [--tail SECONDS] [--float]"
+    );
+    process::exit(2);
+}
+
+fn parse_options() -> Options {
+    let mut args = env::args().skip(1);
+    let mut paths = Vec::new();
+    let mut options = Options {
+        input: String::new(),
+        output: String::new(),
+        sample_rate: 44100,
+        tail_seconds: 2.0,
+        format: SampleFormat::Pcm16,
+    };
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--sample-rate" => {
+                options.sample_rate = args
+                    .next()
+                    .and_then(|value| value.parse().ok())
+                    .filter(|rate| *rate > 0)
+                    .unwrap_or_else(|| usage());
+            }
+            "--tail" => {
+                options.tail_seconds = args
+                    .next()
+                    .and_then(|value| value.parse().ok())
+                    .filter(|seconds: &f64| *seconds >= 0.0)
+                    .unwrap_or_else(|| usage());
+            }
+            "--float" => options.format = SampleFormat::Float32,
+            _ if arg.starts_with("--") => usage(),
+            _ => paths.push(arg),
+        }
+    }
+    if paths.len() != 2 {
+        usage();
+    }
+    options.output = paths.pop().unwrap();
+    options.input = paths.pop().unwrap();
+    options
+}
+
+fn main() {
+    let options = parse_options();
+    let smf = Smf::open(&options.input).unwrap_or_else(|error| {
+        eprintln!("{}: {}", options.input, error);
+        process::exit(1);
+    });
+    let events = smf.timed_events();
+    let end_seconds =
+        events.last().map(|event| event.seconds).unwrap_or(0.0) + options.tail_seconds;
+    let sample_rate = options.sample_rate;
+    let total = (end_seconds * sample_rate as f64).ceil() as usize;
+
+    let mapping = MidiMapping::new();
+    let mut synth = BasicSynth::new();
+    synth.prepare(sample_rate, BLOCK_SIZE);
+    let mut output = vec![0.0; total];
+    let mut position = 0;
+    for event in events.iter() {
+        // MIDI Tuning Standard SysEx messages retune the synth, and have no
+        // message.
+        let message = match &event.event {
+            Event::Midi {
+                status,
+                data1,
+                data2,
+            } => match MidiEvent::from_channel_message(*status, *data1, *data2)
+                .and_then(|midi_event| mapping.map(&midi_event))
+            {
+                Some(message) => Some(message),
+                None => continue,
+            },
+            Event::SysEx(_) => None,
+            _ => continue,
+        };
+        // Render up to the event, then apply it.
+        let event_position = ((event.seconds * sample_rate as f64).round() as usize).min(total);
+        synth.process_block(&mut output[position..event_position], sample_rate);
+        position = event_position;
+        match (message, &event.event) {
+            (Some(message), _) => synth.send(message),
+            (None, Event::SysEx(data)) => {
+                synth.apply_mts(data);
+            }
+            _ => {}
+        }
+    }
+    synth.process_block(&mut output[position..], sample_rate);
+
+    let wav = Wav::from_mono(&output, sample_rate, options.format);
+    if let Err(error) = wav.save(&options.output) {
+        eprintln!("{}: {}", options.output, error);
+        process::exit(1);
+    }
+}
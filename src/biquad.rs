@@ -1,5 +1,25 @@
 use std::f32::consts::PI;
 
+use crate::processor::Processor;
+
+// Filter responses supported by the biquad. Coefficient formulas follow the
+// RBJ Audio EQ Cookbook. Gain (in dB) is only used by the Peaking, LowShelf
+// and HighShelf types, and is ignored by the others.
+#[derive(Clone, Copy, PartialEq)]
+pub enum FilterType {
+    Lowpass,
+    Highpass,
+    // Constant skirt gain, peak gain = Q
+    BandpassSkirt,
+    // Constant 0 dB peak gain
+    BandpassPeak,
+    Notch,
+    Allpass,
+    Peaking,
+    LowShelf,
+    HighShelf,
+}
+
 pub struct Biquad {
     coeffs: Vec<f32>,
     buffer: Vec<f32>,
@@ -7,9 +27,11 @@ pub struct Biquad {
 }
 
 struct Memo {
+    filter_type: FilterType,
     sample_rate: u32,
     freq: f32,
     q: f32,
+    gain_db: f32,
 }
 
 enum Coeffs {
@@ -28,45 +50,329 @@ enum Buffer {
 }
 
 impl Biquad {
-    // Implements the direct form biquad filter from Pirkle 2019, p.252 & p.270
-    pub fn new(sr: u32) -> Biquad {
-        Biquad {
+    // Implements the direct form biquad filter from Pirkle 2019, p.252 & p.270.
+    // Following Pirkle, the a coefficients are the feed-forward (numerator)
+    // terms and the b coefficients are the feedback (denominator) terms, with
+    // everything normalized so that b0 == 1.
+    pub fn new(filter_type: FilterType, sr: u32) -> Biquad {
+        let mut biquad = Biquad {
             buffer: vec![0.0; 4],
             coeffs: vec![0.0; 5],
             memo: Memo {
+                filter_type,
                 sample_rate: sr,
                 freq: 500.0,
-                q: 100.0,
+                q: 0.707,
+                gain_db: 0.0,
             },
+        };
+        biquad.calculate_coeffs();
+        biquad
+    }
+    pub fn set_filter_type(&mut self, filter_type: FilterType) {
+        if filter_type != self.memo.filter_type {
+            self.memo.filter_type = filter_type;
+            self.calculate_coeffs();
         }
     }
-    pub fn tick(&mut self, input: f32, freq: f32, q: f32, sample_rate: u32) -> f32 {
-        if freq != self.memo.freq || q != self.memo.q || sample_rate != self.memo.sample_rate {
-            self.calculate_coeffs(freq, q, sample_rate);
+    // Store parameters for processing through the Processor trait.
+    pub fn set_params(&mut self, freq: f32, q: f32, gain_db: f32) {
+        if freq != self.memo.freq || q != self.memo.q || gain_db != self.memo.gain_db {
             self.memo.freq = freq;
             self.memo.q = q;
+            self.memo.gain_db = gain_db;
+            self.calculate_coeffs();
+        }
+    }
+    pub fn tick(&mut self, input: f32, freq: f32, q: f32, gain_db: f32, sample_rate: u32) -> f32 {
+        if freq != self.memo.freq
+            || q != self.memo.q
+            || gain_db != self.memo.gain_db
+            || sample_rate != self.memo.sample_rate
+        {
+            self.memo.freq = freq;
+            self.memo.q = q;
+            self.memo.gain_db = gain_db;
             self.memo.sample_rate = sample_rate;
+            self.calculate_coeffs();
         };
         let output: f32 = self.coeffs[Coeffs::A0 as usize] * input
             + self.coeffs[Coeffs::A1 as usize] * self.buffer[Buffer::XZ1 as usize]
             + self.coeffs[Coeffs::A2 as usize] * self.buffer[Buffer::XZ2 as usize]
             - self.coeffs[Coeffs::B1 as usize] * self.buffer[Buffer::YZ1 as usize]
-            - self.coeffs[Coeffs::A2 as usize] * self.buffer[Buffer::YZ2 as usize];
+            - self.coeffs[Coeffs::B2 as usize] * self.buffer[Buffer::YZ2 as usize];
+
+        // Update state registers
+        self.buffer[Buffer::XZ2 as usize] = self.buffer[Buffer::XZ1 as usize];
+        self.buffer[Buffer::XZ1 as usize] = input;
+        self.buffer[Buffer::YZ2 as usize] = self.buffer[Buffer::YZ1 as usize];
+        self.buffer[Buffer::YZ1 as usize] = output;
+
         output
     }
-    pub fn calculate_coeffs(&mut self, freq: f32, q: f32, sample_rate: u32) {
-        let theta_c = 2.0 * PI * freq / sample_rate as f32;
-        let d = 1.0 / q;
-        let beta_numerator = 1.0 - ((d / 2.0) * theta_c.sin());
-        let beta_denominator = 1.0 + ((d / 2.0) * theta_c.sin());
-        let beta = 0.5 * (beta_numerator / beta_denominator);
-        let gamma = (0.5 + beta) * theta_c.cos();
-        let alpha = (0.5 + beta - gamma) / 2.0;
-
-        self.coeffs[Coeffs::A0 as usize] = alpha;
-        self.coeffs[Coeffs::A1 as usize] = 2.0 * alpha;
-        self.coeffs[Coeffs::A2 as usize] = alpha;
-        self.coeffs[Coeffs::B1 as usize] = -2.0 * gamma;
-        self.coeffs[Coeffs::B2 as usize] = 2.0 * beta;
+    fn calculate_coeffs(&mut self) {
+        let w0 = 2.0 * PI * self.memo.freq / self.memo.sample_rate as f32;
+        let cos_w0 = w0.cos();
+        let alpha = w0.sin() / (2.0 * self.memo.q);
+        // Amplitude for the peaking and shelving types
+        let a = f32::powf(10.0, self.memo.gain_db / 40.0);
+
+        // Unnormalized (b0, b1, b2, a0, a1, a2) in cookbook notation, where b
+        // is the numerator. These are renamed to Pirkle's convention below.
+        let (b0, b1, b2, a0, a1, a2) = match self.memo.filter_type {
+            FilterType::Lowpass => (
+                (1.0 - cos_w0) / 2.0,
+                1.0 - cos_w0,
+                (1.0 - cos_w0) / 2.0,
+                1.0 + alpha,
+                -2.0 * cos_w0,
+                1.0 - alpha,
+            ),
+            FilterType::Highpass => (
+                (1.0 + cos_w0) / 2.0,
+                -(1.0 + cos_w0),
+                (1.0 + cos_w0) / 2.0,
+                1.0 + alpha,
+                -2.0 * cos_w0,
+                1.0 - alpha,
+            ),
+            FilterType::BandpassSkirt => (
+                self.memo.q * alpha,
+                0.0,
+                -self.memo.q * alpha,
+                1.0 + alpha,
+                -2.0 * cos_w0,
+                1.0 - alpha,
+            ),
+            FilterType::BandpassPeak => (
+                alpha,
+                0.0,
+                -alpha,
+                1.0 + alpha,
+                -2.0 * cos_w0,
+                1.0 - alpha,
+            ),
+            FilterType::Notch => (
+                1.0,
+                -2.0 * cos_w0,
+                1.0,
+                1.0 + alpha,
+                -2.0 * cos_w0,
+                1.0 - alpha,
+            ),
+            FilterType::Allpass => (
+                1.0 - alpha,
+                -2.0 * cos_w0,
+                1.0 + alpha,
+                1.0 + alpha,
+                -2.0 * cos_w0,
+                1.0 - alpha,
+            ),
+            FilterType::Peaking => (
+                1.0 + alpha * a,
+                -2.0 * cos_w0,
+                1.0 - alpha * a,
+                1.0 + alpha / a,
+                -2.0 * cos_w0,
+                1.0 - alpha / a,
+            ),
+            FilterType::LowShelf => {
+                let two_sqrt_a_alpha = 2.0 * a.sqrt() * alpha;
+                (
+                    a * ((a + 1.0) - (a - 1.0) * cos_w0 + two_sqrt_a_alpha),
+                    2.0 * a * ((a - 1.0) - (a + 1.0) * cos_w0),
+                    a * ((a + 1.0) - (a - 1.0) * cos_w0 - two_sqrt_a_alpha),
+                    (a + 1.0) + (a - 1.0) * cos_w0 + two_sqrt_a_alpha,
+                    -2.0 * ((a - 1.0) + (a + 1.0) * cos_w0),
+                    (a + 1.0) + (a - 1.0) * cos_w0 - two_sqrt_a_alpha,
+                )
+            }
+            FilterType::HighShelf => {
+                let two_sqrt_a_alpha = 2.0 * a.sqrt() * alpha;
+                (
+                    a * ((a + 1.0) + (a - 1.0) * cos_w0 + two_sqrt_a_alpha),
+                    -2.0 * a * ((a - 1.0) + (a + 1.0) * cos_w0),
+                    a * ((a + 1.0) + (a - 1.0) * cos_w0 - two_sqrt_a_alpha),
+                    (a + 1.0) - (a - 1.0) * cos_w0 + two_sqrt_a_alpha,
+                    2.0 * ((a - 1.0) - (a + 1.0) * cos_w0),
+                    (a + 1.0) - (a - 1.0) * cos_w0 - two_sqrt_a_alpha,
+                )
+            }
+        };
+
+        self.coeffs[Coeffs::A0 as usize] = b0 / a0;
+        self.coeffs[Coeffs::A1 as usize] = b1 / a0;
+        self.coeffs[Coeffs::A2 as usize] = b2 / a0;
+        self.coeffs[Coeffs::B1 as usize] = a1 / a0;
+        self.coeffs[Coeffs::B2 as usize] = a2 / a0;
+    }
+}
+
+impl Processor for Biquad {
+    fn prepare(&mut self, sample_rate: u32, _max_block: usize) {
+        self.memo.sample_rate = sample_rate;
+        self.calculate_coeffs();
+    }
+    fn reset(&mut self) {
+        for z in self.buffer.iter_mut() {
+            *z = 0.0;
+        }
+    }
+    fn process(&mut self, input: f32) -> f32 {
+        let (freq, q, gain_db) = (self.memo.freq, self.memo.q, self.memo.gain_db);
+        self.tick(input, freq, q, gain_db, self.memo.sample_rate)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: u32 = 48000;
+    // Test tones are whole numbers of cycles in this many samples, so their
+    // level can be measured exactly by correlation.
+    const MEASURE_SAMPLES: usize = 4096;
+    const SETTLE_SAMPLES: usize = 48000;
+
+    // The cookbook filters are bilinear transforms of these analog prototypes,
+    // warped so that the centre frequency maps exactly, with s normalized to
+    // the centre frequency.
+    fn analog_response(
+        filter_type: FilterType,
+        freq: f64,
+        center: f64,
+        q: f64,
+        gain_db: f64,
+    ) -> f64 {
+        let sr = SAMPLE_RATE as f64;
+        let warped =
+            (std::f64::consts::PI * freq / sr).tan() / (std::f64::consts::PI * center / sr).tan();
+        // s = jw, so s^2 = -w^2. Each polynomial is (real, imaginary).
+        let a = 10f64.powf(gain_db / 40.0);
+        let w = warped;
+        let s2 = -w * w;
+        let (num, den) = match filter_type {
+            FilterType::Lowpass => ((1.0, 0.0), (s2 + 1.0, w / q)),
+            FilterType::Highpass => ((s2, 0.0), (s2 + 1.0, w / q)),
+            FilterType::BandpassSkirt => ((0.0, w), (s2 + 1.0, w / q)),
+            FilterType::BandpassPeak => ((0.0, w / q), (s2 + 1.0, w / q)),
+            FilterType::Notch => ((s2 + 1.0, 0.0), (s2 + 1.0, w / q)),
+            FilterType::Allpass => ((s2 + 1.0, -w / q), (s2 + 1.0, w / q)),
+            FilterType::Peaking => ((s2 + 1.0, w * a / q), (s2 + 1.0, w / (a * q))),
+            FilterType::LowShelf => (
+                (a * (s2 + a), a * a.sqrt() * w / q),
+                (a * s2 + 1.0, a.sqrt() * w / q),
+            ),
+            FilterType::HighShelf => (
+                (a * (a * s2 + 1.0), a * a.sqrt() * w / q),
+                (s2 + a, a.sqrt() * w / q),
+            ),
+        };
+        let magnitude = |(re, im): (f64, f64)| (re * re + im * im).sqrt();
+        magnitude(num) / magnitude(den)
+    }
+
+    // Run a sine through the filter until it settles, then measure its level.
+    fn measured_response(
+        biquad: &mut Biquad,
+        bin: usize,
+        center: f32,
+        q: f32,
+        gain_db: f32,
+    ) -> f64 {
+        let w = 2.0 * std::f64::consts::PI * bin as f64 / MEASURE_SAMPLES as f64;
+        let mut re = 0.0;
+        let mut im = 0.0;
+        for n in 0..SETTLE_SAMPLES + MEASURE_SAMPLES {
+            let input = (w * n as f64).sin() as f32;
+            let output = biquad.tick(input, center, q, gain_db, SAMPLE_RATE) as f64;
+            if n >= SETTLE_SAMPLES {
+                re += output * (w * n as f64).cos();
+                im += output * (w * n as f64).sin();
+            }
+        }
+        2.0 * (re * re + im * im).sqrt() / MEASURE_SAMPLES as f64
+    }
+
+    fn to_db(magnitude: f64) -> f64 {
+        20.0 * magnitude.max(1e-12).log10()
+    }
+
+    fn check_response(filter_type: FilterType, center: f32, q: f32, gain_db: f32) {
+        // From about 47Hz to 17kHz
+        for bin in [4, 11, 43, 85, 150, 256, 341, 512, 900, 1500].iter() {
+            let mut biquad = Biquad::new(filter_type, SAMPLE_RATE);
+            let freq = *bin as f64 * SAMPLE_RATE as f64 / MEASURE_SAMPLES as f64;
+            let expected = to_db(analog_response(
+                filter_type,
+                freq,
+                center as f64,
+                q as f64,
+                gain_db as f64,
+            ));
+            let measured = to_db(measured_response(&mut biquad, *bin, center, q, gain_db));
+            if expected < -40.0 {
+                // Deep in the stopband, only check that the tone is rejected.
+                assert!(measured < -30.0, "{} Hz: {} dB", freq, measured);
+            } else {
+                assert!(
+                    (measured - expected).abs() < 0.05,
+                    "{} Hz: measured {} dB, expected {} dB",
+                    freq,
+                    measured,
+                    expected
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn lowpass_matches_cookbook() {
+        check_response(FilterType::Lowpass, 1000.0, 0.707, 0.0);
+        check_response(FilterType::Lowpass, 3000.0, 4.0, 0.0);
+    }
+
+    #[test]
+    fn highpass_matches_cookbook() {
+        check_response(FilterType::Highpass, 1000.0, 0.707, 0.0);
+        check_response(FilterType::Highpass, 300.0, 2.0, 0.0);
+    }
+
+    #[test]
+    fn bandpass_skirt_matches_cookbook() {
+        check_response(FilterType::BandpassSkirt, 1000.0, 2.0, 0.0);
+    }
+
+    #[test]
+    fn bandpass_peak_matches_cookbook() {
+        check_response(FilterType::BandpassPeak, 1000.0, 2.0, 0.0);
+    }
+
+    #[test]
+    fn notch_matches_cookbook() {
+        check_response(FilterType::Notch, 1000.0, 1.0, 0.0);
+    }
+
+    #[test]
+    fn allpass_matches_cookbook() {
+        check_response(FilterType::Allpass, 1000.0, 0.707, 0.0);
+    }
+
+    #[test]
+    fn peaking_matches_cookbook() {
+        check_response(FilterType::Peaking, 1000.0, 1.0, 6.0);
+        check_response(FilterType::Peaking, 2000.0, 3.0, -12.0);
+    }
+
+    #[test]
+    fn low_shelf_matches_cookbook() {
+        check_response(FilterType::LowShelf, 500.0, 0.707, 6.0);
+        check_response(FilterType::LowShelf, 500.0, 0.707, -9.0);
+    }
+
+    #[test]
+    fn high_shelf_matches_cookbook() {
+        check_response(FilterType::HighShelf, 4000.0, 0.707, 6.0);
+        check_response(FilterType::HighShelf, 4000.0, 0.707, -9.0);
     }
 }
@@ -0,0 +1,239 @@
+// Analytic oscillators band-limited with PolyBLEP and PolyBLAMP corrections.
+// These are a cheap alternative to wavetables for waveforms that would need a
+// table per parameter value, such as pulse waves with modulated pulse width
+// and hard-synced saws.
+//
+// Like OscReader, phase is normalized to the range 0.0 to 1.0, and is public
+// so callers can modulate or sync it. Each function increments the phase and
+// returns the next sample.
+//
+// See: Valimaki & Huovilainen, "Antialiasing Oscillators in Subtractive
+// Synthesis", IEEE Signal Processing Magazine, 2007.
+
+#[derive(Clone)]
+pub struct BlepOsc {
+    pub phase: f32,
+    // Phase of the master oscillator for sync_saw
+    master_phase: f32,
+    // sync_saw corrects the sample before each reset, so it runs one sample
+    // behind.
+    delayed: f32,
+    memo: Memo,
+    master_memo: Memo,
+}
+
+#[derive(Clone)]
+struct Memo {
+    frequency: f32,
+    sample_rate: u32,
+    phase_inc: f32,
+}
+
+impl Memo {
+    fn new() -> Memo {
+        Memo {
+            frequency: 0.0,
+            sample_rate: 44100,
+            phase_inc: 0.0,
+        }
+    }
+    fn update(&mut self, freq: f32, sr: u32) {
+        if freq != self.frequency || sr != self.sample_rate {
+            self.frequency = freq;
+            self.sample_rate = sr;
+            self.phase_inc = self.frequency / self.sample_rate as f32;
+        }
+    }
+}
+
+impl Default for BlepOsc {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BlepOsc {
+    pub fn new() -> BlepOsc {
+        BlepOsc {
+            phase: 0.0,
+            master_phase: 0.0,
+            delayed: 0.0,
+            memo: Memo::new(),
+            master_memo: Memo::new(),
+        }
+    }
+    pub fn saw(&mut self, freq: f32, sr: u32) -> f32 {
+        let dt = self.advance(freq, sr);
+        2.0 * self.phase - 1.0 - poly_blep(self.phase, dt)
+    }
+    // pulse_width is the fraction of the cycle spent high, from 0.0 to 1.0.
+    pub fn square(&mut self, freq: f32, pulse_width: f32, sr: u32) -> f32 {
+        let dt = self.advance(freq, sr);
+        let pulse_width = pulse_width.clamp(0.01, 0.99);
+        let naive = if self.phase < pulse_width { 1.0 } else { -1.0 };
+        naive + poly_blep(self.phase, dt) - poly_blep(wrap(self.phase - pulse_width), dt)
+    }
+    pub fn triangle(&mut self, freq: f32, sr: u32) -> f32 {
+        let dt = self.advance(freq, sr);
+        // Rises from -1.0 at phase 0.0 to 1.0 at phase 0.5. The slope changes by
+        // 8.0 per cycle, or 8.0 * dt per sample, at each corner.
+        let naive = 1.0 - 4.0 * (self.phase - 0.5).abs();
+        naive + 8.0 * dt * (poly_blamp(self.phase, dt) - poly_blamp(wrap(self.phase - 0.5), dt))
+    }
+    // A saw at freq, hard-synced to a master oscillator at master_freq. The
+    // output is delayed by one sample. Both oscillators only run forwards, so
+    // negative frequencies are treated as 0.0.
+    pub fn sync_saw(&mut self, master_freq: f32, freq: f32, sr: u32) -> f32 {
+        self.master_memo.update(master_freq.max(0.0), sr);
+        self.memo.update(freq.max(0.0), sr);
+        let master_dt = self.master_memo.phase_inc;
+        let dt = self.memo.phase_inc;
+        self.master_phase += master_dt;
+        self.phase += dt;
+
+        let mut correction = 0.0;
+        if self.master_phase >= 1.0 {
+            self.master_phase -= 1.0;
+            // Time since the reset, in samples
+            let d = self.master_phase / master_dt;
+            let phase_at_reset = wrap(self.phase - d * dt);
+            self.phase = d * dt;
+            correction = self.apply_step(-2.0 * phase_at_reset, d);
+        } else if self.phase >= 1.0 {
+            self.phase -= 1.0;
+            let d = self.phase / dt;
+            correction = self.apply_step(-2.0, d);
+        }
+
+        let output = self.delayed;
+        self.delayed = 2.0 * self.phase - 1.0 + correction;
+        output
+    }
+    // Correct the delayed sample for a step of the given height that happened
+    // d samples ago, returning the correction for the current sample.
+    fn apply_step(&mut self, height: f32, d: f32) -> f32 {
+        self.delayed += height * 0.5 * d * d;
+        height * 0.5 * (2.0 * d - d * d - 1.0)
+    }
+    fn advance(&mut self, freq: f32, sr: u32) -> f32 {
+        self.memo.update(freq, sr);
+        self.phase = wrap(self.phase + self.memo.phase_inc);
+        self.memo.phase_inc.abs()
+    }
+}
+
+fn wrap(phase: f32) -> f32 {
+    let wrapped = phase - phase.floor();
+    // Tiny negative phases can round up to 1.0.
+    if wrapped >= 1.0 {
+        0.0
+    } else {
+        wrapped
+    }
+}
+
+// Two-sample polynomial approximation of the band-limited step residual, for
+// a step of -2.0 at phase 0.0.
+pub fn poly_blep(phase: f32, dt: f32) -> f32 {
+    if phase < dt {
+        let x = phase / dt;
+        x + x - x * x - 1.0
+    } else if phase > 1.0 - dt {
+        let x = (phase - 1.0) / dt;
+        x * x + x + x + 1.0
+    } else {
+        0.0
+    }
+}
+
+// Two-sample polynomial approximation of the band-limited ramp residual, for
+// a change of slope of 1.0 per sample at phase 0.0. Callers scale it by the
+// actual change of slope per sample.
+pub fn poly_blamp(phase: f32, dt: f32) -> f32 {
+    if phase < dt {
+        let x = phase / dt - 1.0;
+        -x * x * x / 6.0
+    } else if phase > 1.0 - dt {
+        let x = (phase - 1.0) / dt + 1.0;
+        x * x * x / 6.0
+    } else {
+        0.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: u32 = 44100;
+    // 100 samples per cycle
+    const FREQ: f32 = 441.0;
+
+    fn render(length: usize, mut next: impl FnMut() -> f32) -> Vec<f32> {
+        (0..length).map(|_| next()).collect()
+    }
+
+    fn mean(samples: &[f32]) -> f32 {
+        samples.iter().sum::<f32>() / samples.len() as f32
+    }
+
+    fn peak(samples: &[f32]) -> f32 {
+        samples
+            .iter()
+            .fold(0.0, |peak: f32, sample| peak.max(sample.abs()))
+    }
+
+    #[test]
+    fn no_dc_offset_and_bounded_peaks() {
+        let mut saw = BlepOsc::new();
+        let mut square = BlepOsc::new();
+        let mut triangle = BlepOsc::new();
+        let outputs = [
+            render(1000, || saw.saw(FREQ, SAMPLE_RATE)),
+            render(1000, || square.square(FREQ, 0.5, SAMPLE_RATE)),
+            render(1000, || triangle.triangle(FREQ, SAMPLE_RATE)),
+        ];
+        for output in outputs.iter() {
+            assert!(mean(output).abs() < 1e-3, "mean {}", mean(output));
+            assert!(peak(output) > 0.9 && peak(output) <= 1.0 + 1e-3);
+        }
+    }
+
+    #[test]
+    fn pulse_width_sets_the_duty_cycle() {
+        for &pulse_width in &[0.1, 0.25, 0.75] {
+            let mut osc = BlepOsc::new();
+            let output = render(1000, || osc.square(FREQ, pulse_width, SAMPLE_RATE));
+            let high = output.iter().filter(|sample| **sample > 0.0).count();
+            assert!((high as f32 / 1000.0 - pulse_width).abs() < 0.02);
+            assert!((mean(&output) - (2.0 * pulse_width - 1.0)).abs() < 0.01);
+        }
+    }
+
+    #[test]
+    fn sync_saw_resets_on_the_master_period() {
+        let mut osc = BlepOsc::new();
+        let output = render(1000, || osc.sync_saw(FREQ, 1234.5, SAMPLE_RATE));
+        // The slave runs 2.8 cycles per master cycle, so the output repeats
+        // with the master's period.
+        for i in 100..900 {
+            assert!((output[i] - output[i + 100]).abs() < 1e-3, "sample {}", i);
+        }
+        assert!(peak(&output) <= 1.0 + 1e-3);
+    }
+
+    #[test]
+    fn sync_saw_ignores_negative_frequencies() {
+        let mut osc = BlepOsc::new();
+        for _ in 0..1000 {
+            let sample = osc.sync_saw(FREQ, -1234.5, SAMPLE_RATE);
+            assert!(sample.is_finite() && sample.abs() <= 1.0);
+            assert!(osc.phase >= 0.0 && osc.phase < 1.0);
+        }
+        let mut osc = BlepOsc::new();
+        for _ in 0..1000 {
+            osc.sync_saw(-FREQ, 1234.5, SAMPLE_RATE);
+            assert!(osc.phase >= 0.0 && osc.phase < 1.0);
+        }
+    }
+}
@@ -0,0 +1,296 @@
+use crate::delay::{pan_gains, CircularBuffer};
+use crate::interpolation::{Interpolator, Linear};
+use crate::osc::{linear_interpolate, OscReader};
+use crate::processor::Processor;
+use crate::wavetable::{make_sine_table, Wavetable};
+
+// Chorus and flanger effects. Both read from a CircularBuffer at a delay that
+// is swept by a sine LFO, so they use fractional-delay interpolation. Linear
+// interpolation is the default, and is usually fine at these short delays.
+
+const LFO_TABLE_SIZE: usize = 1024;
+
+#[derive(Clone, Copy)]
+pub struct ChorusParams {
+    // LFO rate in Hz
+    pub rate: f32,
+    // Delay at the centre of the sweep
+    pub delay_seconds: f32,
+    // Distance the delay sweeps either side of delay_seconds
+    pub depth_seconds: f32,
+    // 0.0 puts every voice in the centre, 1.0 spreads them from hard left to
+    // hard right.
+    pub spread: f32,
+    pub wetdry: f32,
+}
+
+impl Default for ChorusParams {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ChorusParams {
+    pub fn new() -> ChorusParams {
+        ChorusParams {
+            rate: 0.8,
+            delay_seconds: 0.015,
+            depth_seconds: 0.003,
+            spread: 1.0,
+            wetdry: 0.5,
+        }
+    }
+}
+
+// A multi-voice stereo chorus. Every voice reads from the same delay line,
+// with its own LFO phase and pan position, so voices drift against each other
+// and across the stereo field.
+pub struct Chorus<I: Interpolator + Clone = Linear> {
+    buffer: CircularBuffer,
+    interpolators: Vec<I>,
+    lfos: Vec<OscReader>,
+    lfo_table: Wavetable,
+    // Parameters for processing through the Processor trait
+    params: ChorusParams,
+    sample_rate: u32,
+}
+
+impl Chorus {
+    pub fn new(buffer_size: usize, voices: usize) -> Chorus {
+        Chorus::with_interpolator(buffer_size, voices, Linear)
+    }
+}
+
+impl<I: Interpolator + Clone> Chorus<I> {
+    pub fn with_interpolator(buffer_size: usize, voices: usize, interpolator: I) -> Chorus<I> {
+        let voices = voices.max(1);
+        let mut chorus = Chorus {
+            buffer: CircularBuffer::new(buffer_size),
+            interpolators: vec![interpolator; voices],
+            lfos: vec![OscReader::new(); voices],
+            lfo_table: make_sine_table(LFO_TABLE_SIZE),
+            params: ChorusParams::new(),
+            sample_rate: 44100,
+        };
+        chorus.reset_lfos();
+        chorus
+    }
+    pub fn voices(&self) -> usize {
+        self.lfos.len()
+    }
+    // Store parameters for processing through the Processor trait.
+    pub fn set_params(&mut self, params: ChorusParams) {
+        self.params = params;
+    }
+    // The delay line is mono, so stereo input is summed.
+    pub fn tick(
+        &mut self,
+        left: f32,
+        right: f32,
+        params: &ChorusParams,
+        sample_rate: u32,
+    ) -> (f32, f32) {
+        let input = (left + right) * 0.5;
+        let voices = self.lfos.len();
+        let mut wet_l = 0.0;
+        let mut wet_r = 0.0;
+        for (i, (lfo, interpolator)) in self
+            .lfos
+            .iter_mut()
+            .zip(self.interpolators.iter_mut())
+            .enumerate()
+        {
+            lfo.increment(params.rate, sample_rate);
+            let modulation = lfo.read(&self.lfo_table, linear_interpolate);
+            let delay_seconds = params.delay_seconds + params.depth_seconds * modulation;
+            let delay_samples = (delay_seconds * sample_rate as f32).max(1.0);
+            let output = interpolator.read(&self.buffer, delay_samples);
+            let position = if voices > 1 {
+                i as f32 / (voices - 1) as f32 * 2.0 - 1.0
+            } else {
+                0.0
+            };
+            let (gain_l, gain_r) = pan_gains(position * params.spread);
+            wet_l += output * gain_l;
+            wet_r += output * gain_r;
+        }
+        self.buffer.write(input);
+        // Keep the wet level roughly constant as voices are added.
+        let norm = 1.0 / (voices as f32).sqrt();
+        (
+            left * (1.0 - params.wetdry) + wet_l * norm * params.wetdry,
+            right * (1.0 - params.wetdry) + wet_r * norm * params.wetdry,
+        )
+    }
+    // Spread the LFO phases evenly across a cycle.
+    fn reset_lfos(&mut self) {
+        let voices = self.lfos.len();
+        for (i, lfo) in self.lfos.iter_mut().enumerate() {
+            lfo.phase = i as f32 / voices as f32;
+        }
+    }
+}
+
+impl<I: Interpolator + Clone> Processor for Chorus<I> {
+    fn prepare(&mut self, sample_rate: u32, _max_block: usize) {
+        self.sample_rate = sample_rate;
+    }
+    fn reset(&mut self) {
+        self.buffer.clear();
+        for interpolator in self.interpolators.iter_mut() {
+            interpolator.reset();
+        }
+        self.reset_lfos();
+    }
+    fn process(&mut self, input: f32) -> f32 {
+        let (left, right) = self.process_stereo(input, input);
+        (left + right) * 0.5
+    }
+    fn process_stereo(&mut self, left: f32, right: f32) -> (f32, f32) {
+        let params = self.params;
+        self.tick(left, right, &params, self.sample_rate)
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct FlangerParams {
+    // LFO rate in Hz
+    pub rate: f32,
+    // Shortest delay of the sweep. For through-zero flanging, this is the
+    // delay of the dry signal instead.
+    pub delay_seconds: f32,
+    // Length of the sweep above delay_seconds. For through-zero flanging,
+    // the sweep is centred on delay_seconds, and the depth is limited to it.
+    pub depth_seconds: f32,
+    // -1.0 to 1.0. Negative feedback gives a hollower sound.
+    pub feedback: f32,
+    pub wetdry: f32,
+    pub through_zero: bool,
+}
+
+impl Default for FlangerParams {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FlangerParams {
+    pub fn new() -> FlangerParams {
+        FlangerParams {
+            rate: 0.2,
+            delay_seconds: 0.001,
+            depth_seconds: 0.004,
+            feedback: 0.5,
+            wetdry: 0.5,
+            through_zero: false,
+        }
+    }
+}
+
+// A mono flanger. Through-zero flanging delays the dry signal as well, so that
+// the swept delay can pass through it, cancelling completely at the crossing
+// like two tape machines with one flange held back.
+pub struct Flanger<I: Interpolator = Linear> {
+    buffer: CircularBuffer,
+    // The input alone, for the delayed dry signal in through-zero mode.
+    dry_buffer: CircularBuffer,
+    interpolator: I,
+    lfo: OscReader,
+    lfo_table: Wavetable,
+    // Parameters for processing through the Processor trait
+    params: FlangerParams,
+    sample_rate: u32,
+}
+
+impl Flanger {
+    pub fn new(buffer_size: usize) -> Flanger {
+        Flanger::with_interpolator(buffer_size, Linear)
+    }
+}
+
+impl<I: Interpolator> Flanger<I> {
+    pub fn with_interpolator(buffer_size: usize, interpolator: I) -> Flanger<I> {
+        Flanger {
+            buffer: CircularBuffer::new(buffer_size),
+            dry_buffer: CircularBuffer::new(buffer_size),
+            interpolator,
+            lfo: OscReader::new(),
+            lfo_table: make_sine_table(LFO_TABLE_SIZE),
+            params: FlangerParams::new(),
+            sample_rate: 44100,
+        }
+    }
+    // Store parameters for processing through the Processor trait.
+    pub fn set_params(&mut self, params: FlangerParams) {
+        self.params = params;
+    }
+    pub fn tick(&mut self, input_sample: f32, params: &FlangerParams, sample_rate: u32) -> f32 {
+        self.lfo.increment(params.rate, sample_rate);
+        let modulation = self.lfo.read(&self.lfo_table, linear_interpolate);
+        let sr = sample_rate as f32;
+        let (dry, delay_seconds) = if params.through_zero {
+            let depth = params.depth_seconds.min(params.delay_seconds);
+            // The dry delay is fixed, so it doesn't need interpolating.
+            let dry_samples = (params.delay_seconds * sr).round() as usize;
+            (
+                self.dry_buffer.read(dry_samples.max(1)),
+                params.delay_seconds + depth * modulation,
+            )
+        } else {
+            (
+                input_sample,
+                params.delay_seconds + params.depth_seconds * (modulation + 1.0) * 0.5,
+            )
+        };
+        let delay_samples = (delay_seconds * sr).max(1.0);
+        let wet = self.interpolator.read(&self.buffer, delay_samples);
+        let feedback = params.feedback.clamp(-0.99, 0.99);
+        self.buffer.write(input_sample + wet * feedback);
+        self.dry_buffer.write(input_sample);
+        dry * (1.0 - params.wetdry) + wet * params.wetdry
+    }
+}
+
+impl<I: Interpolator> Processor for Flanger<I> {
+    fn prepare(&mut self, sample_rate: u32, _max_block: usize) {
+        self.sample_rate = sample_rate;
+    }
+    fn reset(&mut self) {
+        self.buffer.clear();
+        self.dry_buffer.clear();
+        self.interpolator.reset();
+        self.lfo.phase = 0.0;
+    }
+    fn process(&mut self, input: f32) -> f32 {
+        let params = self.params;
+        self.tick(input, &params, self.sample_rate)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // With through-zero flanging and no wet signal, the output is the input
+    // delayed by delay_seconds, however much feedback there is.
+    #[test]
+    fn through_zero_dry_ignores_feedback() {
+        let sample_rate = 48000;
+        let params = FlangerParams {
+            feedback: 0.9,
+            wetdry: 0.0,
+            through_zero: true,
+            ..FlangerParams::new()
+        };
+        let delay = (params.delay_seconds * sample_rate as f32).round() as usize;
+        let mut flanger = Flanger::new(4096);
+        let input: Vec<f32> = (0..1000)
+            .map(|i| ((i * 7919) % 101) as f32 / 50.0 - 1.0)
+            .collect();
+        for (i, sample) in input.iter().enumerate() {
+            let output = flanger.tick(*sample, &params, sample_rate);
+            let expected = if i >= delay { input[i - delay] } else { 0.0 };
+            assert_eq!(output, expected, "sample {}", i);
+        }
+    }
+}
@@ -1,3 +1,222 @@
+// Purely functional conversions between time, tempo, frequency and gain
+// units. Durations in seconds are the common currency: tempo-synced times are
+// converted to seconds, and from there to samples or frequencies.
+
+// Time
+
 pub fn seconds_to_samples(seconds: f32, sample_rate: u32) -> f32 {
     sample_rate as f32 * seconds
 }
+
+pub fn samples_to_seconds(samples: f32, sample_rate: u32) -> f32 {
+    samples / sample_rate as f32
+}
+
+pub fn ms_to_seconds(ms: f32) -> f32 {
+    ms * 0.001
+}
+
+pub fn seconds_to_ms(seconds: f32) -> f32 {
+    seconds * 1000.0
+}
+
+pub fn ms_to_samples(ms: f32, sample_rate: u32) -> f32 {
+    seconds_to_samples(ms_to_seconds(ms), sample_rate)
+}
+
+pub fn samples_to_ms(samples: f32, sample_rate: u32) -> f32 {
+    seconds_to_ms(samples_to_seconds(samples, sample_rate))
+}
+
+// Frequency
+
+// Period of one cycle, in seconds.
+pub fn hz_to_period(hz: f32) -> f32 {
+    1.0 / hz
+}
+
+pub fn period_to_hz(seconds: f32) -> f32 {
+    1.0 / seconds
+}
+
+// Gain
+
+pub fn db_to_linear(db: f32) -> f32 {
+    10.0_f32.powf(db / 20.0)
+}
+
+// Silence converts to negative infinity.
+pub fn linear_to_db(gain: f32) -> f32 {
+    20.0 * gain.abs().log10()
+}
+
+// Pitch
+
+pub fn semitones_to_ratio(semitones: f32) -> f32 {
+    2.0_f32.powf(semitones / 12.0)
+}
+
+pub fn ratio_to_semitones(ratio: f32) -> f32 {
+    12.0 * ratio.log2()
+}
+
+pub fn cents_to_ratio(cents: f32) -> f32 {
+    2.0_f32.powf(cents / 1200.0)
+}
+
+pub fn ratio_to_cents(ratio: f32) -> f32 {
+    1200.0 * ratio.log2()
+}
+
+// Tempo
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NoteValue {
+    Whole,
+    Half,
+    Quarter,
+    Eighth,
+    Sixteenth,
+    ThirtySecond,
+    SixtyFourth,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NoteModifier {
+    Straight,
+    // One and a half times the length
+    Dotted,
+    // Three in the time of two
+    Triplet,
+}
+
+// A musical note length, such as a dotted eighth.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NoteLength {
+    pub value: NoteValue,
+    pub modifier: NoteModifier,
+}
+
+impl NoteLength {
+    pub fn new(value: NoteValue) -> NoteLength {
+        NoteLength {
+            value,
+            modifier: NoteModifier::Straight,
+        }
+    }
+    pub fn dotted(value: NoteValue) -> NoteLength {
+        NoteLength {
+            value,
+            modifier: NoteModifier::Dotted,
+        }
+    }
+    pub fn triplet(value: NoteValue) -> NoteLength {
+        NoteLength {
+            value,
+            modifier: NoteModifier::Triplet,
+        }
+    }
+    // Length in beats, where a beat is a quarter note.
+    pub fn beats(&self) -> f32 {
+        let beats = match self.value {
+            NoteValue::Whole => 4.0,
+            NoteValue::Half => 2.0,
+            NoteValue::Quarter => 1.0,
+            NoteValue::Eighth => 0.5,
+            NoteValue::Sixteenth => 0.25,
+            NoteValue::ThirtySecond => 0.125,
+            NoteValue::SixtyFourth => 0.0625,
+        };
+        match self.modifier {
+            NoteModifier::Straight => beats,
+            NoteModifier::Dotted => beats * 1.5,
+            NoteModifier::Triplet => beats * 2.0 / 3.0,
+        }
+    }
+}
+
+pub fn bpm_to_beat_seconds(bpm: f32) -> f32 {
+    60.0 / bpm
+}
+
+pub fn note_to_seconds(length: NoteLength, bpm: f32) -> f32 {
+    length.beats() * bpm_to_beat_seconds(bpm)
+}
+
+pub fn note_to_samples(length: NoteLength, bpm: f32, sample_rate: u32) -> f32 {
+    seconds_to_samples(note_to_seconds(length, bpm), sample_rate)
+}
+
+// Frequency of a cycle lasting one note length, for tempo-synced LFOs.
+pub fn note_to_hz(length: NoteLength, bpm: f32) -> f32 {
+    period_to_hz(note_to_seconds(length, bpm))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(a: f32, b: f32) {
+        assert!((a - b).abs() < 1e-4, "{} != {}", a, b);
+    }
+
+    #[test]
+    fn note_lengths() {
+        assert_close(NoteLength::new(NoteValue::Whole).beats(), 4.0);
+        assert_close(NoteLength::new(NoteValue::SixtyFourth).beats(), 0.0625);
+        assert_close(NoteLength::dotted(NoteValue::Eighth).beats(), 0.75);
+        assert_close(NoteLength::dotted(NoteValue::Quarter).beats(), 1.5);
+        assert_close(NoteLength::triplet(NoteValue::Quarter).beats(), 2.0 / 3.0);
+        // Three eighth-note triplets fill a quarter note.
+        assert_close(NoteLength::triplet(NoteValue::Eighth).beats() * 3.0, 1.0);
+        assert_eq!(
+            NoteLength::dotted(NoteValue::Half).modifier,
+            NoteModifier::Dotted
+        );
+
+        let quarter = NoteLength::new(NoteValue::Quarter);
+        assert_close(note_to_seconds(quarter, 120.0), 0.5);
+        assert_close(note_to_samples(quarter, 120.0, 48000), 24000.0);
+        assert_close(note_to_hz(quarter, 120.0), 2.0);
+        assert_close(
+            note_to_seconds(NoteLength::dotted(NoteValue::Eighth), 100.0),
+            0.45,
+        );
+    }
+
+    #[test]
+    fn time_round_trips() {
+        assert_close(seconds_to_samples(0.5, 44100), 22050.0);
+        assert_close(samples_to_seconds(22050.0, 44100), 0.5);
+        assert_close(ms_to_samples(10.0, 48000), 480.0);
+        assert_close(samples_to_ms(480.0, 48000), 10.0);
+        assert_close(period_to_hz(hz_to_period(440.0)), 440.0);
+    }
+
+    #[test]
+    fn db_round_trips() {
+        assert_close(db_to_linear(0.0), 1.0);
+        assert_close(db_to_linear(-20.0), 0.1);
+        assert_close(db_to_linear(6.0206), 2.0);
+        assert_close(linear_to_db(0.5), -6.0206);
+        assert_close(linear_to_db(-0.5), -6.0206);
+        assert_eq!(linear_to_db(0.0), f32::NEG_INFINITY);
+        for &db in &[-60.0, -12.5, 0.0, 3.0, 24.0] {
+            assert_close(linear_to_db(db_to_linear(db)), db);
+        }
+    }
+
+    #[test]
+    fn pitch_ratios() {
+        assert_close(semitones_to_ratio(12.0), 2.0);
+        assert_close(semitones_to_ratio(-12.0), 0.5);
+        assert_close(semitones_to_ratio(7.0), 1.498_307);
+        assert_close(ratio_to_semitones(1.5), 7.019_55);
+        assert_close(cents_to_ratio(1200.0), 2.0);
+        assert_close(cents_to_ratio(100.0), semitones_to_ratio(1.0));
+        assert_close(ratio_to_cents(1.5), 701.955);
+        for &cents in &[-700.0, -1.0, 0.0, 50.0, 2400.0] {
+            assert_close(ratio_to_cents(cents_to_ratio(cents)), cents);
+        }
+    }
+}
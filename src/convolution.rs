@@ -0,0 +1,289 @@
+use std::path::Path;
+
+use crate::fft::{Complex, Fft};
+use crate::processor::Processor;
+use crate::wav::{Wav, WavError};
+
+// Uniformly partitioned FFT convolution (overlap-save), for convolution
+// reverbs and cabinet simulation with measured impulse responses.
+//
+// The impulse response is split into partitions of block_size samples, and
+// each partition's spectrum is computed once. Every block_size input samples,
+// the input block is transformed, multiplied against every partition's
+// spectrum in a frequency-domain delay line, and transformed back. The cost
+// per block is fixed regardless of how the caller's buffers line up, and the
+// latency is block_size samples. Smaller blocks lower the latency at the cost
+// of more FFTs per second.
+pub struct Convolver {
+    block_size: usize,
+    fft: Fft,
+    // Spectrum of each impulse response partition
+    partitions: Vec<Vec<Complex>>,
+    // Spectra of the most recent input blocks, one per partition. The
+    // newest is at fdl_index.
+    fdl: Vec<Vec<Complex>>,
+    fdl_index: usize,
+    // The previous and current input blocks
+    input: Vec<f32>,
+    output: Vec<f32>,
+    // Position in the current block
+    position: usize,
+    // Scratch space for the summed output spectrum
+    accumulator: Vec<Complex>,
+    // Parameters for processing through the Processor trait
+    wetdry: f32,
+}
+
+impl Convolver {
+    // block_size is rounded up to a power of two. This allocates, so it
+    // doesn't belong on the audio thread.
+    pub fn new(impulse_response: &[f32], block_size: usize) -> Convolver {
+        let block_size = block_size.max(1).next_power_of_two();
+        let fft_size = block_size * 2;
+        let mut convolver = Convolver {
+            block_size,
+            fft: Fft::new(fft_size),
+            partitions: Vec::new(),
+            fdl: Vec::new(),
+            fdl_index: 0,
+            input: vec![0.0; fft_size],
+            output: vec![0.0; block_size],
+            position: 0,
+            accumulator: vec![Complex::default(); fft_size],
+            wetdry: 1.0,
+        };
+        convolver.set_impulse_response(impulse_response);
+        convolver
+    }
+    // Load the impulse response from the first channel of a WAV file. Stereo
+    // impulse responses need a Convolver per channel (see Wav::channel). The
+    // impulse response isn't resampled, so it should match the sample rate
+    // it will be used at.
+    pub fn from_wav<P: AsRef<Path>>(path: P, block_size: usize) -> Result<Convolver, WavError> {
+        let wav = Wav::open(path)?;
+        Ok(Convolver::new(&wav.channel(0), block_size))
+    }
+    // Replace the impulse response, clearing the convolution state. This
+    // allocates, so it doesn't belong on the audio thread.
+    pub fn set_impulse_response(&mut self, impulse_response: &[f32]) {
+        let fft_size = self.fft.size();
+        self.partitions = impulse_response
+            .chunks(self.block_size)
+            .map(|chunk| {
+                let mut spectrum = vec![Complex::default(); fft_size];
+                for (value, sample) in spectrum.iter_mut().zip(chunk.iter()) {
+                    value.re = *sample;
+                }
+                self.fft.forward(&mut spectrum);
+                spectrum
+            })
+            .collect();
+        self.fdl = vec![vec![Complex::default(); fft_size]; self.partitions.len()];
+        self.reset();
+    }
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+    // Delay between input and convolved output, in samples
+    pub fn latency(&self) -> usize {
+        self.block_size
+    }
+    // Store parameters for processing through the Processor trait. The dry
+    // signal isn't delayed to match the latency, so use a wetdry of 1.0 and
+    // mix externally when the two need to line up, e.g. for cabinets.
+    pub fn set_params(&mut self, wetdry: f32) {
+        self.wetdry = wetdry;
+    }
+    pub fn tick(&mut self, input_sample: f32) -> f32 {
+        let output = self.output[self.position];
+        self.input[self.block_size + self.position] = input_sample;
+        self.position += 1;
+        if self.position == self.block_size {
+            self.process_partitions();
+        }
+        output
+    }
+    // Convolve a block in place. Any block length works, and whole
+    // partitions are copied at a time.
+    pub fn process_block(&mut self, buffer: &mut [f32]) {
+        let mut start = 0;
+        while start < buffer.len() {
+            let count = (self.block_size - self.position).min(buffer.len() - start);
+            let block = &mut buffer[start..start + count];
+            let input = &mut self.input[self.block_size + self.position..][..count];
+            let output = &self.output[self.position..][..count];
+            for ((sample, input), output) in block.iter_mut().zip(input.iter_mut()).zip(output) {
+                *input = *sample;
+                *sample = *output;
+            }
+            self.position += count;
+            if self.position == self.block_size {
+                self.process_partitions();
+            }
+            start += count;
+        }
+    }
+    fn process_partitions(&mut self) {
+        self.position = 0;
+        if self.partitions.is_empty() {
+            for sample in self.output.iter_mut() {
+                *sample = 0.0;
+            }
+            return;
+        }
+        let count = self.partitions.len();
+        self.fdl_index = (self.fdl_index + 1) % count;
+        for (value, sample) in self.fdl[self.fdl_index].iter_mut().zip(self.input.iter()) {
+            *value = Complex::new(*sample, 0.0);
+        }
+        self.fft.forward(&mut self.fdl[self.fdl_index]);
+
+        for value in self.accumulator.iter_mut() {
+            *value = Complex::default();
+        }
+        for (p, partition) in self.partitions.iter().enumerate() {
+            // Input block from p blocks ago
+            let input = &self.fdl[(self.fdl_index + count - p) % count];
+            for ((sum, x), h) in self
+                .accumulator
+                .iter_mut()
+                .zip(input.iter())
+                .zip(partition.iter())
+            {
+                *sum += *x * *h;
+            }
+        }
+        self.fft.inverse(&mut self.accumulator);
+        // Overlap-save: the first half is circular wrap-around, and the
+        // second half is the convolved block.
+        for (sample, value) in self
+            .output
+            .iter_mut()
+            .zip(self.accumulator[self.block_size..].iter())
+        {
+            *sample = value.re;
+        }
+        // The current block becomes the previous block.
+        self.input.copy_within(self.block_size.., 0);
+    }
+}
+
+impl Processor for Convolver {
+    fn prepare(&mut self, _sample_rate: u32, _max_block: usize) {}
+    fn reset(&mut self) {
+        for spectrum in self.fdl.iter_mut() {
+            for value in spectrum.iter_mut() {
+                *value = Complex::default();
+            }
+        }
+        for sample in self.input.iter_mut().chain(self.output.iter_mut()) {
+            *sample = 0.0;
+        }
+        self.fdl_index = 0;
+        self.position = 0;
+    }
+    fn process(&mut self, input: f32) -> f32 {
+        input * (1.0 - self.wetdry) + self.tick(input) * self.wetdry
+    }
+    fn process_buffer(&mut self, buffer: &mut [f32]) {
+        if self.wetdry == 1.0 {
+            self.process_block(buffer);
+        } else {
+            for sample in buffer.iter_mut() {
+                *sample = self.process(*sample);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_signal(length: usize, seed: usize) -> Vec<f32> {
+        (0..length)
+            .map(|n| ((n * 7919 + seed * 104_729) % 101) as f32 / 50.0 - 1.0)
+            .collect()
+    }
+
+    fn direct_convolution(input: &[f32], impulse_response: &[f32]) -> Vec<f32> {
+        (0..input.len())
+            .map(|n| {
+                impulse_response
+                    .iter()
+                    .enumerate()
+                    .filter(|(k, _)| *k <= n)
+                    .map(|(k, h)| h * input[n - k])
+                    .sum()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn matches_direct_convolution() {
+        let block_size = 64;
+        let input = test_signal(2000, 1);
+        // Shorter than, equal to, and several blocks longer than a block
+        for length in [1, 5, 64, 64 * 3 + 17].iter() {
+            let impulse_response = test_signal(*length, 2);
+            let expected = direct_convolution(&input, &impulse_response);
+            let mut convolver = Convolver::new(&impulse_response, block_size);
+            let latency = convolver.latency();
+            for (n, sample) in input.iter().enumerate() {
+                let output = convolver.tick(*sample);
+                let expected = if n >= latency {
+                    expected[n - latency]
+                } else {
+                    0.0
+                };
+                assert!(
+                    (output - expected).abs() < 1e-3,
+                    "length {}, sample {}: {} != {}",
+                    length,
+                    n,
+                    output,
+                    expected
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn process_block_matches_tick() {
+        let impulse_response = test_signal(300, 3);
+        let input = test_signal(3000, 4);
+        let mut tick_convolver = Convolver::new(&impulse_response, 128);
+        let expected: Vec<f32> = input.iter().map(|x| tick_convolver.tick(*x)).collect();
+
+        let mut block_convolver = Convolver::new(&impulse_response, 128);
+        let mut output = input.clone();
+        let mut start = 0;
+        // Odd block lengths, crossing partition boundaries at varying points
+        for length in [1, 3, 7, 13, 127, 129, 255, 31].iter().cycle() {
+            if start >= output.len() {
+                break;
+            }
+            let end = (start + length).min(output.len());
+            block_convolver.process_block(&mut output[start..end]);
+            start = end;
+        }
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn latency_is_the_block_size() {
+        let convolver = Convolver::new(&[1.0], 100);
+        assert_eq!(convolver.block_size(), 128);
+        assert_eq!(convolver.latency(), 128);
+
+        // A unit impulse response is a pure delay of the latency.
+        let mut convolver = Convolver::new(&[1.0], 32);
+        let output: Vec<f32> = (0..100)
+            .map(|n| convolver.tick(if n == 0 { 1.0 } else { 0.0 }))
+            .collect();
+        let peak = (0..output.len())
+            .max_by(|a, b| output[*a].partial_cmp(&output[*b]).unwrap())
+            .unwrap();
+        assert_eq!(peak, convolver.latency());
+    }
+}
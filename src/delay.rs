@@ -1,24 +1,120 @@
+use std::error::Error;
+use std::fmt;
+
+use crate::conversion::{note_to_seconds, NoteLength};
+use crate::interpolation::{Interpolator, Linear};
+use crate::processor::{Param, Processor};
+use crate::svf::{FilterMode, SVF};
+
 // A circular buffer is a wrapper around vec that only supports writing into the
 // buffer at the next index (starting over at index 0 if the write index would
 // be out of bounds. This data structure is very useful in audio DSP for
 // creating delay and filter effects.
+//
+// Reads never panic, since a panic on the audio thread takes down the host.
+// Out-of-range reads are clamped to the buffer, and callers that need to know
+// about them can use try_read instead.
 pub struct CircularBuffer {
     buffer: Vec<f32>,
     write_index: usize,
+    // Set in power-of-two mode, where indices wrap with a bitmask instead of a
+    // comparison.
+    mask: Option<usize>,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BufferError {
+    // The requested delay is longer than the buffer.
+    OutOfRange { requested: usize, capacity: usize },
+    // The buffer has no capacity to read from.
+    Empty,
+}
+
+impl fmt::Display for BufferError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BufferError::OutOfRange {
+                requested,
+                capacity,
+            } => write!(
+                f,
+                "requested delay of {} samples is greater than buffer size {}",
+                requested, capacity
+            ),
+            BufferError::Empty => write!(f, "buffer is empty"),
+        }
+    }
+}
+
+impl Error for BufferError {}
+
 impl CircularBuffer {
     pub fn new(buffer_size: usize) -> CircularBuffer {
         CircularBuffer {
             buffer: vec![0.0; buffer_size],
             write_index: 0,
+            mask: None,
+        }
+    }
+    // Create a buffer with a capacity of at least min_size, rounded up to the
+    // next power of two.
+    pub fn with_power_of_two(min_size: usize) -> CircularBuffer {
+        let size = min_size.max(1).next_power_of_two();
+        CircularBuffer {
+            buffer: vec![0.0; size],
+            write_index: 0,
+            mask: Some(size - 1),
+        }
+    }
+    pub fn len(&self) -> usize {
+        self.buffer.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.buffer.is_empty()
+    }
+    pub fn clear(&mut self) {
+        for sample in self.buffer.iter_mut() {
+            *sample = 0.0;
+        }
+        self.write_index = 0;
+    }
+    // Change the capacity, keeping as many of the most recent samples as fit.
+    // In power-of-two mode, the new size is rounded up to the next power of
+    // two. This allocates, so it doesn't belong on the audio thread.
+    pub fn resize(&mut self, buffer_size: usize) {
+        let buffer_size = match self.mask {
+            Some(_) => buffer_size.max(1).next_power_of_two(),
+            None => buffer_size,
+        };
+        let kept = buffer_size.min(self.buffer.len());
+        let mut buffer = vec![0.0; buffer_size];
+        // Oldest kept sample first
+        for (i, sample) in buffer.iter_mut().take(kept).enumerate() {
+            *sample = self.read(kept - i);
+        }
+        self.buffer = buffer;
+        self.write_index = if buffer_size == 0 {
+            0
+        } else {
+            kept % buffer_size
+        };
+        if self.mask.is_some() {
+            self.mask = Some(buffer_size - 1);
         }
     }
     pub fn write(&mut self, value: f32) {
+        if self.buffer.is_empty() {
+            return;
+        }
         self.buffer[self.write_index] = value;
-        self.write_index += 1;
-        if self.write_index == self.buffer.len() {
-            self.write_index = 0;
+        match self.mask {
+            Some(mask) => self.write_index = (self.write_index + 1) & mask,
+            None => {
+                self.write_index += 1;
+                if self.write_index == self.buffer.len() {
+                    self.write_index = 0;
+                }
+            }
         }
     }
     // Callers read from the circular buffer at a specified distance from the
@@ -26,27 +122,43 @@ impl CircularBuffer {
     // where N is length_samples. Conversion from units such as seconds to
     // samples, or interpolation between multiple read values are higher-level
     // concerns, handled by callers.
+    //
+    // Lengths greater than the buffer size are clamped to the buffer size, and
+    // empty buffers read as silence.
     pub fn read(&self, length_samples: usize) -> f32 {
-        if length_samples > self.buffer.len() {
-            panic!("Requested delay length is greater than buffer size!");
+        if self.buffer.is_empty() {
+            return 0.0;
         }
-        // usize::min_value() == 0, so we can't subtract two of them and think
-        // about whether the result is negative. We convert our usizes to i32
-        // here to handle this.
-        let mut read_index = self.write_index as i32 - length_samples as i32;
-        if read_index < 0 {
-            read_index += self.buffer.len() as i32;
+        let length_samples = length_samples.min(self.buffer.len());
+        let read_index = match self.mask {
+            Some(mask) => self.write_index.wrapping_sub(length_samples) & mask,
+            None => {
+                if length_samples > self.write_index {
+                    self.write_index + self.buffer.len() - length_samples
+                } else {
+                    self.write_index - length_samples
+                }
+            }
+        };
+        self.buffer[read_index]
+    }
+    // Like read, but reports out-of-range lengths instead of clamping them.
+    pub fn try_read(&self, length_samples: usize) -> Result<f32, BufferError> {
+        if self.buffer.is_empty() {
+            return Err(BufferError::Empty);
         }
-
-        self.buffer[read_index as usize]
+        if length_samples > self.buffer.len() {
+            return Err(BufferError::OutOfRange {
+                requested: length_samples,
+                capacity: self.buffer.len(),
+            });
+        }
+        Ok(self.read(length_samples))
     }
 }
 
-// Interpolation
-
-pub fn discard(buf: CircularBuffer, length_samples: f32) -> f32 {
-    buf.read(length_samples as usize)
-}
+// Interpolation. See the interpolation module for the policies used by delay
+// effects.
 
 pub fn linear_interpolate(buf: &CircularBuffer, length_samples: &f32) -> f32 {
     let index = *length_samples as usize;
@@ -64,14 +176,21 @@ pub fn linear_interpolate(buf: &CircularBuffer, length_samples: &f32) -> f32 {
 // into a CircularBuffer. Reading and incrementing (i.e. writing) actions are
 // combined becuase the input value to the write operation depends on the
 // previous write operation whenever feedback designs are used.
-pub struct SimpleDelay {
+//
+// Delays are generic over their fractional-delay interpolation policy, which
+// defaults to linear interpolation.
+pub struct SimpleDelay<I: Interpolator = Linear> {
     buffer: CircularBuffer,
+    interpolator: I,
+    // Longest delay the buffer is sized for in prepare, if set
+    max_seconds: Option<f32>,
     memo: Memo,
 }
 
 struct Memo {
     delay_samples: f32,
     delay_seconds: f32,
+    feedback_amount: f32,
     sample_rate: u32,
 }
 
@@ -80,6 +199,7 @@ impl Memo {
         Memo {
             delay_samples: 22050.0,
             delay_seconds: 0.5,
+            feedback_amount: 0.5,
             sample_rate: 44100,
         }
     }
@@ -90,11 +210,31 @@ impl Memo {
 // accessors. Callers can provide parameter management structs if needed.
 impl SimpleDelay {
     pub fn new(buffer_size: usize) -> SimpleDelay {
+        SimpleDelay::with_interpolator(buffer_size, Linear)
+    }
+}
+
+impl<I: Interpolator> SimpleDelay<I> {
+    pub fn with_interpolator(buffer_size: usize, interpolator: I) -> SimpleDelay<I> {
         SimpleDelay {
             buffer: CircularBuffer::new(buffer_size),
+            interpolator,
+            max_seconds: None,
             memo: Memo::new(),
         }
     }
+    // Size the buffer for delays of up to max_seconds at the sample rate given
+    // to prepare, which does the allocation. Without this, the buffer keeps
+    // the size it was created with.
+    pub fn set_max_seconds(&mut self, max_seconds: f32) {
+        self.max_seconds = Some(max_seconds.max(0.0));
+    }
+    // Store parameters for processing through the Processor trait.
+    pub fn set_params(&mut self, delay_seconds: f32, feedback_amount: f32) {
+        let sample_rate = self.memo.sample_rate;
+        self.update_memo(delay_seconds, sample_rate);
+        self.memo.feedback_amount = feedback_amount;
+    }
     pub fn tick(
         &mut self,
         input_sample: f32,
@@ -103,9 +243,55 @@ impl SimpleDelay {
         sample_rate: u32,
     ) -> f32 {
         self.update_memo(delay_seconds, sample_rate);
-        // TODO: defer to external interpolation policy somehow.
-        let output = linear_interpolate(&self.buffer, &self.memo.delay_samples);
-        self.buffer.write(input_sample + (output * feedback_amount));
+        self.memo.feedback_amount = feedback_amount;
+        self.delay(input_sample)
+    }
+    // Tick with the delay time set to a note length at the given tempo.
+    pub fn tick_synced(
+        &mut self,
+        input_sample: f32,
+        bpm: f32,
+        length: NoteLength,
+        feedback_amount: f32,
+        sample_rate: u32,
+    ) -> f32 {
+        let delay_seconds = note_to_seconds(length, bpm);
+        self.tick(input_sample, delay_seconds, feedback_amount, sample_rate)
+    }
+    // Delay a block in place. Block parameters only check the memo once per
+    // block.
+    pub fn process_block(
+        &mut self,
+        buffer: &mut [f32],
+        delay_seconds: Param,
+        feedback_amount: Param,
+        sample_rate: u32,
+    ) {
+        if let (Param::Block(delay_seconds), Param::Block(feedback_amount)) =
+            (delay_seconds, feedback_amount)
+        {
+            self.update_memo(delay_seconds, sample_rate);
+            self.memo.feedback_amount = feedback_amount;
+            for sample in buffer.iter_mut() {
+                *sample = self.delay(*sample);
+            }
+        } else {
+            for (i, sample) in buffer.iter_mut().enumerate() {
+                *sample = self.tick(
+                    *sample,
+                    delay_seconds.at(i),
+                    feedback_amount.at(i),
+                    sample_rate,
+                );
+            }
+        }
+    }
+    fn delay(&mut self, input_sample: f32) -> f32 {
+        let output = self
+            .interpolator
+            .read(&self.buffer, self.memo.delay_samples);
+        self.buffer
+            .write(input_sample + (output * self.memo.feedback_amount));
         output
     }
     fn update_memo(&mut self, delay_seconds: f32, sample_rate: u32) {
@@ -116,3 +302,344 @@ impl SimpleDelay {
         }
     }
 }
+
+impl<I: Interpolator> Processor for SimpleDelay<I> {
+    fn prepare(&mut self, sample_rate: u32, _max_block: usize) {
+        if let Some(max_seconds) = self.max_seconds {
+            // Extra samples for the interpolator to read past the longest
+            // delay
+            let size = (max_seconds * sample_rate as f32).ceil() as usize + 4;
+            if size != self.buffer.len() {
+                self.buffer.resize(size);
+            }
+        }
+        let delay_seconds = self.memo.delay_seconds;
+        self.update_memo(delay_seconds, sample_rate);
+    }
+    fn reset(&mut self) {
+        self.buffer.clear();
+        self.interpolator.reset();
+    }
+    fn process(&mut self, input: f32) -> f32 {
+        self.delay(input)
+    }
+    fn process_buffer(&mut self, buffer: &mut [f32]) {
+        for sample in buffer.iter_mut() {
+            *sample = self.delay(*sample);
+        }
+    }
+}
+
+// Q of the lowpass filters in the feedback paths of the stereo delays
+const FEEDBACK_FILTER_Q: f32 = 0.707;
+
+// Keep feedback filter cutoffs below Nyquist, where the SVF is stable.
+fn clamp_cutoff(freq: f32, sample_rate: u32) -> f32 {
+    freq.clamp(20.0, sample_rate as f32 * 0.45)
+}
+
+// Equal-power gains for a pan position from -1.0 (left) to 1.0 (right).
+pub(crate) fn pan_gains(pan: f32) -> (f32, f32) {
+    let angle = (pan.clamp(-1.0, 1.0) + 1.0) * std::f32::consts::FRAC_PI_4;
+    (angle.cos(), angle.sin())
+}
+
+#[derive(Clone, Copy)]
+pub struct Tap {
+    pub delay_seconds: f32,
+    pub gain: f32,
+    // -1.0 (left) to 1.0 (right)
+    pub pan: f32,
+    // Amount of this tap's output fed back into the delay line
+    pub feedback: f32,
+}
+
+// A mono-in, stereo-out delay with any number of taps on one delay line. The
+// sum of the taps' feedback passes through a lowpass filter before it is
+// written back, so repeats get darker over time like a tape or analog delay.
+//
+// The taps are configuration rather than per-tick parameters, since their
+// number can vary. Setting them doesn't allocate as long as there are no more
+// than max_taps of them.
+pub struct MultiTapDelay<I: Interpolator + Clone = Linear> {
+    buffer: CircularBuffer,
+    filter: SVF,
+    // One interpolator per tap, since some policies have state
+    interpolators: Vec<I>,
+    taps: Vec<Tap>,
+    // Parameters for processing through the Processor trait
+    filter_freq: f32,
+    sample_rate: u32,
+}
+
+impl MultiTapDelay {
+    pub fn new(buffer_size: usize, max_taps: usize) -> MultiTapDelay {
+        MultiTapDelay::with_interpolator(buffer_size, max_taps, Linear)
+    }
+}
+
+impl<I: Interpolator + Clone> MultiTapDelay<I> {
+    pub fn with_interpolator(
+        buffer_size: usize,
+        max_taps: usize,
+        interpolator: I,
+    ) -> MultiTapDelay<I> {
+        MultiTapDelay {
+            buffer: CircularBuffer::new(buffer_size),
+            filter: SVF::new(44100),
+            interpolators: vec![interpolator; max_taps],
+            taps: Vec::with_capacity(max_taps),
+            filter_freq: 20000.0,
+            sample_rate: 44100,
+        }
+    }
+    // Taps beyond max_taps are ignored.
+    pub fn set_taps(&mut self, taps: &[Tap]) {
+        self.taps.clear();
+        let count = taps.len().min(self.interpolators.len());
+        self.taps.extend_from_slice(&taps[..count]);
+    }
+    // Store parameters for processing through the Processor trait.
+    pub fn set_params(&mut self, filter_freq: f32) {
+        self.filter_freq = filter_freq;
+    }
+    pub fn tick(&mut self, input_sample: f32, filter_freq: f32, sample_rate: u32) -> (f32, f32) {
+        let mut left = 0.0;
+        let mut right = 0.0;
+        let mut feedback = 0.0;
+        for (tap, interpolator) in self.taps.iter().zip(self.interpolators.iter_mut()) {
+            let delay_samples = tap.delay_seconds * sample_rate as f32;
+            let output = interpolator.read(&self.buffer, delay_samples);
+            let (gain_l, gain_r) = pan_gains(tap.pan);
+            left += output * tap.gain * gain_l;
+            right += output * tap.gain * gain_r;
+            feedback += output * tap.feedback;
+        }
+        let feedback = self.filter.process_sample(
+            feedback,
+            clamp_cutoff(filter_freq, sample_rate),
+            FEEDBACK_FILTER_Q,
+            FilterMode::Lowpass,
+            sample_rate,
+        );
+        self.buffer.write(input_sample + feedback);
+        (left, right)
+    }
+}
+
+impl<I: Interpolator + Clone> Processor for MultiTapDelay<I> {
+    fn prepare(&mut self, sample_rate: u32, _max_block: usize) {
+        self.sample_rate = sample_rate;
+    }
+    fn reset(&mut self) {
+        self.buffer.clear();
+        self.filter.reset();
+        for interpolator in self.interpolators.iter_mut() {
+            interpolator.reset();
+        }
+    }
+    fn process(&mut self, input: f32) -> f32 {
+        let (left, right) = self.process_stereo(input, input);
+        (left + right) * 0.5
+    }
+    // The delay line is mono, so stereo input is summed.
+    fn process_stereo(&mut self, left: f32, right: f32) -> (f32, f32) {
+        self.tick((left + right) * 0.5, self.filter_freq, self.sample_rate)
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct PingPongParams {
+    pub delay_seconds: f32,
+    // Amount of each channel's output fed back into the same channel
+    pub feedback: f32,
+    // Amount of each channel's output fed into the other channel
+    pub cross_feedback: f32,
+    // Cutoff of the lowpass filters in the feedback paths
+    pub filter_freq: f32,
+}
+
+impl Default for PingPongParams {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PingPongParams {
+    pub fn new() -> PingPongParams {
+        PingPongParams {
+            delay_seconds: 0.25,
+            feedback: 0.0,
+            cross_feedback: 0.6,
+            filter_freq: 20000.0,
+        }
+    }
+}
+
+// A stereo delay with a delay line per channel and cross-feedback between
+// them. With no same-channel feedback, a signal sent to one channel bounces
+// from side to side. Feed a mono source into the left input only for the
+// classic ping-pong effect.
+pub struct PingPongDelay<I: Interpolator + Clone = Linear> {
+    buffers: [CircularBuffer; 2],
+    filters: [SVF; 2],
+    interpolators: [I; 2],
+    // Parameters for processing through the Processor trait
+    params: PingPongParams,
+    sample_rate: u32,
+}
+
+impl PingPongDelay {
+    pub fn new(buffer_size: usize) -> PingPongDelay {
+        PingPongDelay::with_interpolator(buffer_size, Linear)
+    }
+}
+
+impl<I: Interpolator + Clone> PingPongDelay<I> {
+    pub fn with_interpolator(buffer_size: usize, interpolator: I) -> PingPongDelay<I> {
+        PingPongDelay {
+            buffers: [
+                CircularBuffer::new(buffer_size),
+                CircularBuffer::new(buffer_size),
+            ],
+            filters: [SVF::new(44100), SVF::new(44100)],
+            interpolators: [interpolator.clone(), interpolator],
+            params: PingPongParams::new(),
+            sample_rate: 44100,
+        }
+    }
+    // Store parameters for processing through the Processor trait.
+    pub fn set_params(&mut self, params: PingPongParams) {
+        self.params = params;
+    }
+    pub fn tick(
+        &mut self,
+        left: f32,
+        right: f32,
+        params: &PingPongParams,
+        sample_rate: u32,
+    ) -> (f32, f32) {
+        let delay_samples = params.delay_seconds * sample_rate as f32;
+        let filter_freq = clamp_cutoff(params.filter_freq, sample_rate);
+        let out_l = self.interpolators[0].read(&self.buffers[0], delay_samples);
+        let out_r = self.interpolators[1].read(&self.buffers[1], delay_samples);
+        let feedback_l = out_l * params.feedback + out_r * params.cross_feedback;
+        let feedback_r = out_r * params.feedback + out_l * params.cross_feedback;
+        let feedback_l = self.filters[0].process_sample(
+            feedback_l,
+            filter_freq,
+            FEEDBACK_FILTER_Q,
+            FilterMode::Lowpass,
+            sample_rate,
+        );
+        let feedback_r = self.filters[1].process_sample(
+            feedback_r,
+            filter_freq,
+            FEEDBACK_FILTER_Q,
+            FilterMode::Lowpass,
+            sample_rate,
+        );
+        self.buffers[0].write(left + feedback_l);
+        self.buffers[1].write(right + feedback_r);
+        (out_l, out_r)
+    }
+}
+
+impl<I: Interpolator + Clone> Processor for PingPongDelay<I> {
+    fn prepare(&mut self, sample_rate: u32, _max_block: usize) {
+        self.sample_rate = sample_rate;
+    }
+    fn reset(&mut self) {
+        for i in 0..2 {
+            self.buffers[i].clear();
+            self.filters[i].reset();
+            self.interpolators[i].reset();
+        }
+    }
+    fn process(&mut self, input: f32) -> f32 {
+        let (left, right) = self.process_stereo(input, 0.0);
+        (left + right) * 0.5
+    }
+    fn process_stereo(&mut self, left: f32, right: f32) -> (f32, f32) {
+        let params = self.params;
+        self.tick(left, right, &params, self.sample_rate)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn buffers(size: usize) -> Vec<CircularBuffer> {
+        vec![
+            CircularBuffer::new(size),
+            CircularBuffer::with_power_of_two(size),
+        ]
+    }
+
+    #[test]
+    fn reads_wrap_around() {
+        for mut buf in buffers(8) {
+            for i in 0..21 {
+                buf.write(i as f32);
+            }
+            for length in 1..=8 {
+                assert_eq!(buf.read(length), (21 - length) as f32);
+            }
+        }
+    }
+
+    #[test]
+    fn power_of_two_mode_rounds_up() {
+        assert_eq!(CircularBuffer::with_power_of_two(0).len(), 1);
+        assert_eq!(CircularBuffer::with_power_of_two(5).len(), 8);
+        assert_eq!(CircularBuffer::with_power_of_two(8).len(), 8);
+        let mut buf = CircularBuffer::with_power_of_two(5);
+        buf.resize(9);
+        assert_eq!(buf.len(), 16);
+    }
+
+    #[test]
+    fn out_of_range_reads() {
+        for mut buf in buffers(8) {
+            for i in 0..8 {
+                buf.write(i as f32);
+            }
+            assert_eq!(buf.try_read(8), Ok(0.0));
+            assert_eq!(
+                buf.try_read(9),
+                Err(BufferError::OutOfRange {
+                    requested: 9,
+                    capacity: 8
+                })
+            );
+            // read clamps to the oldest sample instead.
+            assert_eq!(buf.read(100), 0.0);
+        }
+        let empty = CircularBuffer::new(0);
+        assert_eq!(empty.try_read(1), Err(BufferError::Empty));
+        assert_eq!(empty.read(1), 0.0);
+    }
+
+    #[test]
+    fn resize_keeps_the_newest_samples() {
+        for mut buf in buffers(8) {
+            for i in 0..13 {
+                buf.write(i as f32);
+            }
+            buf.resize(4);
+            assert_eq!(buf.len(), 4);
+            for length in 1..=4 {
+                assert_eq!(buf.read(length), (13 - length) as f32);
+            }
+            buf.resize(16);
+            for length in 1..=4 {
+                assert_eq!(buf.read(length), (13 - length) as f32);
+            }
+            assert_eq!(buf.read(5), 0.0);
+            buf.write(13.0);
+            assert_eq!(buf.read(1), 13.0);
+            assert_eq!(buf.read(5), 9.0);
+        }
+    }
+}
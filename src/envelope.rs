@@ -1,12 +1,19 @@
+use crate::processor::Param;
+
 #[derive(Clone)]
 enum Stage {
     Attack,
+    // Held at the peak for as long as the gate is open
+    Sustain,
     Release,
 }
 
 #[derive(Clone)]
 pub struct EnvReader {
     pub is_active: bool,
+    // While the gate is open, the envelope holds at its peak after the attack
+    // stage. Closing the gate moves on to the release stage.
+    is_gated: bool,
     current_stage: Stage,
     memo: Memo,
     phase: f32,
@@ -21,10 +28,17 @@ struct Memo {
     release_phase_inc: f32,
 }
 
+impl Default for EnvReader {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl EnvReader {
     pub fn new() -> EnvReader {
         EnvReader {
             is_active: false,
+            is_gated: false,
             current_stage: Stage::Attack,
             phase: 0.0,
             memo: Memo {
@@ -36,27 +50,88 @@ impl EnvReader {
             },
         }
     }
-    pub fn read<F>(&self, table: &Vec<f32>, interpolate: F) -> f32
+    pub fn read<F>(&self, table: &[f32], interpolate: F) -> f32
     where
-        F: Fn(&EnvReader, &Vec<f32>) -> f32,
+        F: Fn(&EnvReader, &[f32]) -> f32,
     {
-        interpolate(&self, table)
+        interpolate(self, table)
     }
+    pub fn reset(&mut self) {
+        self.is_active = false;
+        self.is_gated = false;
+        self.current_stage = Stage::Attack;
+        self.phase = 0.0;
+    }
+    // Open the gate and (re)start the attack stage from the current level.
     pub fn start(&mut self) {
         self.is_active = true;
+        self.is_gated = true;
+        self.current_stage = Stage::Attack;
+    }
+    // Close the gate, releasing from the current level.
+    pub fn stop(&mut self) {
+        self.is_gated = false;
+        if self.is_active {
+            self.current_stage = Stage::Release;
+        }
+    }
+    // Start a one-shot envelope, which releases as soon as the attack stage
+    // completes.
+    pub fn trigger(&mut self) {
+        self.is_active = true;
+        self.is_gated = false;
         self.current_stage = Stage::Attack;
     }
     pub fn increment(&mut self, attack: f32, release: f32, sample_rate: u32) {
         self.update_memo(attack, release, sample_rate);
+        self.advance();
+    }
+    // Fill output with table reads, incrementing before each read. Once the
+    // envelope finishes, the rest of the block is filled with zeros.
+    pub fn process_block(
+        &mut self,
+        output: &mut [f32],
+        table: &[f32],
+        attack: Param,
+        release: Param,
+        sample_rate: u32,
+    ) {
+        if let (Param::Block(attack), Param::Block(release)) = (attack, release) {
+            self.update_memo(attack, release, sample_rate);
+            for sample in output.iter_mut() {
+                if self.is_active {
+                    self.advance();
+                    *sample = linear_interpolate(self, table);
+                } else {
+                    *sample = 0.0;
+                }
+            }
+        } else {
+            for (i, sample) in output.iter_mut().enumerate() {
+                if self.is_active {
+                    self.increment(attack.at(i), release.at(i), sample_rate);
+                    *sample = linear_interpolate(self, table);
+                } else {
+                    *sample = 0.0;
+                }
+            }
+        }
+    }
+    fn advance(&mut self) {
         match &self.current_stage {
             Stage::Attack => {
                 self.phase += self.memo.attack_phase_inc;
                 if self.phase >= 1.0 {
                     // Undo the increment if it's time to switch phase.
                     self.phase -= self.memo.attack_phase_inc;
-                    self.current_stage = Stage::Release;
+                    self.current_stage = if self.is_gated {
+                        Stage::Sustain
+                    } else {
+                        Stage::Release
+                    };
                 }
             }
+            Stage::Sustain => {}
             Stage::Release => {
                 self.phase -= self.memo.release_phase_inc;
                 if self.phase <= 0.0 {
@@ -86,7 +161,7 @@ impl EnvReader {
 }
 
 // TODO: Remove this code
-pub fn linear_interpolate(reader: &EnvReader, table: &Vec<f32>) -> f32 {
+pub fn linear_interpolate(reader: &EnvReader, table: &[f32]) -> f32 {
     // Envolopes are not circular tables, so in this interpolation policy, we
     // don't cycle around to the beginning of the table once we reach the end.
     let ex_phase = reader.phase * table.len() as f32;
@@ -99,3 +174,463 @@ pub fn linear_interpolate(reader: &EnvReader, table: &Vec<f32>) -> f32 {
         table[index] * (1.0 - fraction) + table[next_index] * fraction
     }
 }
+
+// Multi-segment envelope generator
+
+// Shape of an envelope segment, mapping progress through the segment onto
+// progress from the segment's start level to its target level.
+#[derive(Clone)]
+pub enum Curve {
+    Linear,
+    // Moves quickly at first and slows down as it approaches the target, like
+    // an analog RC envelope. Larger values give a steeper curve.
+    Exponential(f32),
+    // Moves slowly at first and speeds up as it approaches the target. Larger
+    // values give a steeper curve.
+    Logarithmic(f32),
+    // A table of normalized values, read from start to end with linear
+    // interpolation. The table should run from 0.0 to 1.0.
+    Table(Vec<f32>),
+}
+
+impl Curve {
+    fn shape(&self, progress: f32) -> f32 {
+        match self {
+            Curve::Linear => progress,
+            Curve::Exponential(k) => {
+                if *k <= 0.0 {
+                    progress
+                } else {
+                    (1.0 - (-k * progress).exp()) / (1.0 - (-k).exp())
+                }
+            }
+            Curve::Logarithmic(k) => {
+                if *k <= 0.0 {
+                    progress
+                } else {
+                    ((k * progress).exp() - 1.0) / (k.exp() - 1.0)
+                }
+            }
+            Curve::Table(table) => {
+                if table.is_empty() {
+                    return progress;
+                }
+                let ex_progress = progress * (table.len() - 1) as f32;
+                let index = ex_progress as usize;
+                let fraction = ex_progress - index as f32;
+                let next_index = (index + 1).min(table.len() - 1);
+                table[index] * (1.0 - fraction) + table[next_index] * fraction
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum LoopMode {
+    Off,
+    // While the gate is open, return to the attack stage at the end of the
+    // decay stage instead of sustaining. Useful as a modulation source.
+    AttackDecay,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum TriggerMode {
+    // Restart from the delay stage, rising from the current level.
+    Retrigger,
+    // Restart from the delay stage at zero. This can click if the envelope is
+    // still sounding.
+    Reset,
+    // Don't restart if the gate is already open, so that overlapping notes
+    // continue the same envelope.
+    Legato,
+}
+
+// Stage times are in seconds, and sustain is a level from 0.0 to 1.0.
+#[derive(Clone)]
+pub struct AdsrParams {
+    pub delay: f32,
+    pub attack: f32,
+    pub hold: f32,
+    pub decay: f32,
+    pub sustain: f32,
+    pub release: f32,
+    pub attack_curve: Curve,
+    pub decay_curve: Curve,
+    pub release_curve: Curve,
+    pub loop_mode: LoopMode,
+    pub trigger_mode: TriggerMode,
+}
+
+impl Default for AdsrParams {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AdsrParams {
+    pub fn new() -> AdsrParams {
+        AdsrParams {
+            delay: 0.0,
+            attack: 0.01,
+            hold: 0.0,
+            decay: 0.1,
+            sustain: 1.0,
+            release: 0.5,
+            attack_curve: Curve::Linear,
+            decay_curve: Curve::Exponential(5.0),
+            release_curve: Curve::Exponential(5.0),
+            loop_mode: LoopMode::Off,
+            trigger_mode: TriggerMode::Retrigger,
+        }
+    }
+}
+
+// Number of AdsrStage variants
+const ADSR_STAGES: usize = 6;
+
+#[derive(Clone, Copy, PartialEq)]
+enum AdsrStage {
+    Delay,
+    Attack,
+    Hold,
+    Decay,
+    Sustain,
+    Release,
+}
+
+// An envelope with delay, attack, hold, decay, sustain and release stages.
+// Parameters are provided on every tick like the rest of the crate, so they
+// can be modulated freely. The current level is public through level(), so
+// the envelope can be used as a modulation source as well as for amplitude.
+#[derive(Clone)]
+pub struct Adsr {
+    pub is_active: bool,
+    is_gated: bool,
+    stage: AdsrStage,
+    level: f32,
+    // Level at the start of the current stage
+    start_level: f32,
+    // Normalized progress through the current stage
+    progress: f32,
+    memo: AdsrMemo,
+}
+
+#[derive(Clone)]
+struct AdsrMemo {
+    sample_rate: u32,
+    stage_seconds: f32,
+    progress_inc: f32,
+}
+
+impl Default for Adsr {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Adsr {
+    pub fn new() -> Adsr {
+        Adsr {
+            is_active: false,
+            is_gated: false,
+            stage: AdsrStage::Delay,
+            level: 0.0,
+            start_level: 0.0,
+            progress: 0.0,
+            memo: AdsrMemo {
+                sample_rate: 44100,
+                stage_seconds: 0.0,
+                progress_inc: 1.0,
+            },
+        }
+    }
+    pub fn level(&self) -> f32 {
+        self.level
+    }
+    pub fn reset(&mut self) {
+        self.is_active = false;
+        self.is_gated = false;
+        self.stage = AdsrStage::Delay;
+        self.level = 0.0;
+        self.start_level = 0.0;
+        self.progress = 0.0;
+    }
+    // Open the gate, starting the envelope according to the trigger mode.
+    pub fn start(&mut self, params: &AdsrParams) {
+        if params.trigger_mode == TriggerMode::Legato && self.is_active && self.is_gated {
+            return;
+        }
+        if params.trigger_mode == TriggerMode::Reset {
+            self.level = 0.0;
+        }
+        self.is_active = true;
+        self.is_gated = true;
+        self.enter_stage(AdsrStage::Delay);
+    }
+    // Close the gate, releasing from the current level.
+    pub fn stop(&mut self) {
+        self.is_gated = false;
+        if self.is_active {
+            self.enter_stage(AdsrStage::Release);
+        }
+    }
+    // Advance the envelope by one sample and return the new level.
+    pub fn tick(&mut self, params: &AdsrParams, sample_rate: u32) -> f32 {
+        if !self.is_active {
+            return 0.0;
+        }
+        // Zero-length stages are skipped within the same tick. A looping
+        // envelope with every stage at zero would skip forever, so at most
+        // one pass through the stages is skipped per tick.
+        let mut skipped = 0;
+        let stage_seconds = loop {
+            let stage_seconds = match self.stage {
+                AdsrStage::Delay => params.delay,
+                AdsrStage::Attack => params.attack,
+                AdsrStage::Hold => params.hold,
+                AdsrStage::Decay => params.decay,
+                AdsrStage::Sustain => {
+                    self.level = params.sustain;
+                    return self.level;
+                }
+                AdsrStage::Release => params.release,
+            };
+            if stage_seconds > 0.0 || skipped == ADSR_STAGES {
+                break stage_seconds;
+            }
+            self.finish_stage(params);
+            skipped += 1;
+            if !self.is_active {
+                return self.level;
+            }
+        };
+        self.update_memo(stage_seconds, sample_rate);
+        self.progress += self.memo.progress_inc;
+        if self.progress >= 1.0 {
+            self.finish_stage(params);
+        } else {
+            self.level = match self.stage {
+                AdsrStage::Delay => self.start_level,
+                AdsrStage::Attack => self.segment(1.0, &params.attack_curve),
+                AdsrStage::Hold => 1.0,
+                AdsrStage::Decay => self.segment(params.sustain, &params.decay_curve),
+                AdsrStage::Sustain => params.sustain,
+                AdsrStage::Release => self.segment(0.0, &params.release_curve),
+            };
+        }
+        self.level
+    }
+    pub fn process_block(&mut self, output: &mut [f32], params: &AdsrParams, sample_rate: u32) {
+        for sample in output.iter_mut() {
+            *sample = self.tick(params, sample_rate);
+        }
+    }
+    fn segment(&self, target: f32, curve: &Curve) -> f32 {
+        self.start_level + (target - self.start_level) * curve.shape(self.progress)
+    }
+    fn enter_stage(&mut self, stage: AdsrStage) {
+        self.stage = stage;
+        self.start_level = self.level;
+        self.progress = 0.0;
+    }
+    fn finish_stage(&mut self, params: &AdsrParams) {
+        match self.stage {
+            AdsrStage::Delay => self.enter_stage(AdsrStage::Attack),
+            AdsrStage::Attack => {
+                self.level = 1.0;
+                self.enter_stage(AdsrStage::Hold);
+            }
+            AdsrStage::Hold => self.enter_stage(AdsrStage::Decay),
+            AdsrStage::Decay => {
+                self.level = params.sustain;
+                if params.loop_mode == LoopMode::AttackDecay && self.is_gated {
+                    self.enter_stage(AdsrStage::Attack);
+                } else if self.is_gated {
+                    self.enter_stage(AdsrStage::Sustain);
+                } else {
+                    self.enter_stage(AdsrStage::Release);
+                }
+            }
+            AdsrStage::Sustain => {}
+            AdsrStage::Release => {
+                self.level = 0.0;
+                self.is_active = false;
+            }
+        }
+    }
+    fn update_memo(&mut self, stage_seconds: f32, sample_rate: u32) {
+        if stage_seconds != self.memo.stage_seconds || sample_rate != self.memo.sample_rate {
+            self.memo.stage_seconds = stage_seconds;
+            self.memo.sample_rate = sample_rate;
+            // Zero-length stages are normally skipped in tick, and only get
+            // here when skipping is capped. They finish on this tick.
+            self.memo.progress_inc = if stage_seconds > 0.0 {
+                1.0 / (stage_seconds * sample_rate as f32)
+            } else {
+                1.0
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // One sample per millisecond, so stage times in samples are easy to read
+    const SAMPLE_RATE: u32 = 1000;
+
+    fn linear_params() -> AdsrParams {
+        AdsrParams {
+            attack: 0.1,
+            decay: 0.1,
+            sustain: 0.6,
+            release: 0.1,
+            attack_curve: Curve::Linear,
+            decay_curve: Curve::Linear,
+            release_curve: Curve::Linear,
+            ..AdsrParams::new()
+        }
+    }
+
+    fn run(adsr: &mut Adsr, params: &AdsrParams, samples: usize) -> Vec<f32> {
+        (0..samples)
+            .map(|_| adsr.tick(params, SAMPLE_RATE))
+            .collect()
+    }
+
+    #[test]
+    fn zero_length_stages_are_skipped() {
+        let params = AdsrParams {
+            attack: 0.0,
+            decay: 0.0,
+            release: 0.0,
+            ..linear_params()
+        };
+        let mut adsr = Adsr::new();
+        adsr.start(&params);
+        // Straight to sustain on the first tick
+        assert_eq!(adsr.tick(&params, SAMPLE_RATE), 0.6);
+        adsr.stop();
+        assert_eq!(adsr.tick(&params, SAMPLE_RATE), 0.0);
+        assert!(!adsr.is_active);
+    }
+
+    #[test]
+    fn stages_and_sustain() {
+        let params = linear_params();
+        let mut adsr = Adsr::new();
+        adsr.start(&params);
+        let levels = run(&mut adsr, &params, 400);
+        // Linear attack over 100 samples, then decay over 100
+        assert!((levels[49] - 0.5).abs() < 0.02);
+        assert!((levels[99] - 1.0).abs() < 1e-6);
+        assert!((levels[149] - 0.8).abs() < 0.02);
+        for level in levels[210..].iter() {
+            assert_eq!(*level, 0.6);
+        }
+        assert!(adsr.is_active);
+    }
+
+    #[test]
+    fn release_from_mid_attack() {
+        let params = linear_params();
+        let mut adsr = Adsr::new();
+        adsr.start(&params);
+        let attack = run(&mut adsr, &params, 50);
+        let peak = attack[49];
+        assert!((peak - 0.5).abs() < 0.02);
+        adsr.stop();
+        let release = run(&mut adsr, &params, 110);
+        // The release starts from the current level, not the peak or sustain.
+        assert!(release[0] < peak && release[0] > peak - 0.02);
+        assert!(release.windows(2).all(|pair| pair[1] <= pair[0]));
+        assert_eq!(release[109], 0.0);
+        assert!(!adsr.is_active);
+    }
+
+    #[test]
+    fn loop_mode_cycles() {
+        let params = AdsrParams {
+            attack: 0.01,
+            decay: 0.01,
+            sustain: 0.2,
+            loop_mode: LoopMode::AttackDecay,
+            ..linear_params()
+        };
+        let mut adsr = Adsr::new();
+        adsr.start(&params);
+        let levels = run(&mut adsr, &params, 100);
+        let peaks = levels.iter().filter(|level| **level == 1.0).count();
+        let troughs = levels.iter().filter(|level| **level == 0.2).count();
+        assert!(
+            peaks >= 4 && troughs >= 4,
+            "{} peaks, {} troughs",
+            peaks,
+            troughs
+        );
+        adsr.stop();
+        run(&mut adsr, &params, 110);
+        assert!(!adsr.is_active);
+    }
+
+    #[test]
+    fn trigger_modes() {
+        // Start, sustain, then release for 20 samples.
+        let released = |params: &AdsrParams| -> Adsr {
+            let mut adsr = Adsr::new();
+            adsr.start(params);
+            run(&mut adsr, params, 300);
+            adsr.stop();
+            run(&mut adsr, params, 20);
+            adsr
+        };
+
+        let params = linear_params();
+        let mut adsr = released(&params);
+        let level = adsr.level();
+        adsr.start(&params);
+        // Retrigger rises from the current level.
+        assert!(adsr.tick(&params, SAMPLE_RATE) > level);
+
+        let params = AdsrParams {
+            trigger_mode: TriggerMode::Reset,
+            ..linear_params()
+        };
+        let mut adsr = released(&params);
+        adsr.start(&params);
+        assert!(adsr.tick(&params, SAMPLE_RATE) < 0.02);
+
+        // Legato doesn't restart while the gate is open.
+        let params = AdsrParams {
+            trigger_mode: TriggerMode::Legato,
+            ..linear_params()
+        };
+        let mut adsr = Adsr::new();
+        adsr.start(&params);
+        run(&mut adsr, &params, 300);
+        adsr.start(&params);
+        assert_eq!(adsr.tick(&params, SAMPLE_RATE), 0.6);
+        let params = linear_params();
+        adsr.start(&params);
+        assert!(adsr.tick(&params, SAMPLE_RATE) > 0.6);
+    }
+
+    #[test]
+    fn curves() {
+        for curve in [
+            Curve::Linear,
+            Curve::Exponential(5.0),
+            Curve::Logarithmic(5.0),
+            Curve::Table(vec![0.0, 0.25, 1.0]),
+        ]
+        .iter()
+        {
+            assert!(curve.shape(0.0).abs() < 1e-6);
+            assert!((curve.shape(1.0) - 1.0).abs() < 1e-6);
+        }
+        assert!(Curve::Exponential(5.0).shape(0.5) > 0.5);
+        assert!(Curve::Logarithmic(5.0).shape(0.5) < 0.5);
+        assert!((Curve::Table(vec![0.0, 0.25, 1.0]).shape(0.5) - 0.25).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,511 @@
+use std::ops::{Add, AddAssign, Mul, Sub};
+
+use crate::constants::TWO_PI;
+
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+pub struct Complex {
+    pub re: f32,
+    pub im: f32,
+}
+
+impl Complex {
+    pub fn new(re: f32, im: f32) -> Complex {
+        Complex { re, im }
+    }
+    // Phase is in radians.
+    pub fn from_polar(magnitude: f32, phase: f32) -> Complex {
+        Complex {
+            re: magnitude * phase.cos(),
+            im: magnitude * phase.sin(),
+        }
+    }
+    pub fn conj(self) -> Complex {
+        Complex {
+            re: self.re,
+            im: -self.im,
+        }
+    }
+    pub fn norm(self) -> f32 {
+        (self.re * self.re + self.im * self.im).sqrt()
+    }
+    pub fn arg(self) -> f32 {
+        self.im.atan2(self.re)
+    }
+    pub fn scale(self, factor: f32) -> Complex {
+        Complex {
+            re: self.re * factor,
+            im: self.im * factor,
+        }
+    }
+}
+
+impl Add for Complex {
+    type Output = Complex;
+    fn add(self, other: Complex) -> Complex {
+        Complex::new(self.re + other.re, self.im + other.im)
+    }
+}
+
+impl AddAssign for Complex {
+    fn add_assign(&mut self, other: Complex) {
+        self.re += other.re;
+        self.im += other.im;
+    }
+}
+
+impl Sub for Complex {
+    type Output = Complex;
+    fn sub(self, other: Complex) -> Complex {
+        Complex::new(self.re - other.re, self.im - other.im)
+    }
+}
+
+impl Mul for Complex {
+    type Output = Complex;
+    fn mul(self, other: Complex) -> Complex {
+        Complex::new(
+            self.re * other.re - self.im * other.im,
+            self.re * other.im + self.im * other.re,
+        )
+    }
+}
+
+// An in-place, iterative radix-2 FFT for one power-of-two size. Twiddle
+// factors and the bit-reversal permutation are computed once in new, so the
+// transforms themselves don't allocate.
+pub struct Fft {
+    size: usize,
+    twiddles: Vec<Complex>,
+    bit_reverse: Vec<usize>,
+}
+
+impl Fft {
+    // Panics if size is not a power of two.
+    pub fn new(size: usize) -> Fft {
+        assert!(size.is_power_of_two(), "FFT size must be a power of two");
+        let twiddles = (0..size / 2)
+            .map(|k| Complex::from_polar(1.0, -TWO_PI * k as f32 / size as f32))
+            .collect();
+        let bits = size.trailing_zeros();
+        let bit_reverse = (0..size)
+            .map(|i| {
+                if bits == 0 {
+                    0
+                } else {
+                    i.reverse_bits() >> (usize::BITS - bits)
+                }
+            })
+            .collect();
+        Fft {
+            size,
+            twiddles,
+            bit_reverse,
+        }
+    }
+    pub fn size(&self) -> usize {
+        self.size
+    }
+    pub fn forward(&self, data: &mut [Complex]) {
+        self.transform(data, false);
+    }
+    // The inverse transform is scaled by 1 / size, so forward followed by
+    // inverse returns the original data.
+    pub fn inverse(&self, data: &mut [Complex]) {
+        self.transform(data, true);
+        let scale = 1.0 / self.size as f32;
+        for value in data.iter_mut() {
+            *value = value.scale(scale);
+        }
+    }
+    fn transform(&self, data: &mut [Complex], inverse: bool) {
+        assert_eq!(data.len(), self.size, "data length must match FFT size");
+        for i in 0..self.size {
+            let j = self.bit_reverse[i];
+            if i < j {
+                data.swap(i, j);
+            }
+        }
+        let mut half = 1;
+        while half < self.size {
+            // Twiddle index step for butterflies of this width
+            let step = self.size / (half * 2);
+            for start in (0..self.size).step_by(half * 2) {
+                for k in 0..half {
+                    let twiddle = self.twiddles[k * step];
+                    let twiddle = if inverse { twiddle.conj() } else { twiddle };
+                    let a = data[start + k];
+                    let b = data[start + k + half] * twiddle;
+                    data[start + k] = a + b;
+                    data[start + k + half] = a - b;
+                }
+            }
+            half *= 2;
+        }
+    }
+}
+
+// A forward and inverse FFT for real signals of a power-of-two size, computed
+// with a complex FFT of half the size. Spectra hold the size / 2 + 1 bins from
+// DC to Nyquist; the remaining bins are the complex conjugates of these.
+pub struct RealFft {
+    size: usize,
+    fft: Fft,
+    // e^(-2 pi i k / size) for the bins up to Nyquist
+    twiddles: Vec<Complex>,
+    scratch: Vec<Complex>,
+}
+
+impl RealFft {
+    // Panics if size is not a power of two of at least 2.
+    pub fn new(size: usize) -> RealFft {
+        assert!(
+            size >= 2 && size.is_power_of_two(),
+            "real FFT size must be a power of two of at least 2"
+        );
+        let half = size / 2;
+        RealFft {
+            size,
+            fft: Fft::new(half),
+            twiddles: (0..=half)
+                .map(|k| Complex::from_polar(1.0, -TWO_PI * k as f32 / size as f32))
+                .collect(),
+            scratch: vec![Complex::default(); half],
+        }
+    }
+    pub fn size(&self) -> usize {
+        self.size
+    }
+    // Number of bins in a spectrum, size / 2 + 1
+    pub fn bins(&self) -> usize {
+        self.size / 2 + 1
+    }
+    pub fn forward(&mut self, input: &[f32], output: &mut [Complex]) {
+        assert_eq!(input.len(), self.size, "input length must match FFT size");
+        assert_eq!(
+            output.len(),
+            self.bins(),
+            "output length must be size / 2 + 1"
+        );
+        let half = self.size / 2;
+        // Pack even samples into the real part and odd samples into the
+        // imaginary part.
+        for (k, value) in self.scratch.iter_mut().enumerate() {
+            *value = Complex::new(input[2 * k], input[2 * k + 1]);
+        }
+        self.fft.forward(&mut self.scratch);
+        for (k, bin) in output.iter_mut().enumerate() {
+            let z = self.scratch[k % half];
+            let z_mirror = self.scratch[(half - k) % half].conj();
+            // Spectra of the even and odd samples
+            let even = (z + z_mirror).scale(0.5);
+            let odd = (z - z_mirror).scale(0.5);
+            let odd = Complex::new(odd.im, -odd.re);
+            *bin = even + self.twiddles[k] * odd;
+        }
+    }
+    // The inverse is scaled by 1 / size, so forward followed by inverse
+    // returns the original signal.
+    pub fn inverse(&mut self, input: &[Complex], output: &mut [f32]) {
+        assert_eq!(
+            input.len(),
+            self.bins(),
+            "input length must be size / 2 + 1"
+        );
+        assert_eq!(output.len(), self.size, "output length must match FFT size");
+        let half = self.size / 2;
+        for (k, value) in self.scratch.iter_mut().enumerate() {
+            let x = input[k];
+            let x_mirror = input[half - k].conj();
+            let even = (x + x_mirror).scale(0.5);
+            let odd = (x - x_mirror).scale(0.5) * self.twiddles[k].conj();
+            // even + i * odd
+            *value = Complex::new(even.re - odd.im, even.im + odd.re);
+        }
+        self.fft.inverse(&mut self.scratch);
+        for (k, value) in self.scratch.iter().enumerate() {
+            output[2 * k] = value.re;
+            output[2 * k + 1] = value.im;
+        }
+    }
+}
+
+// Spectrum helpers
+
+pub fn magnitudes(spectrum: &[Complex], output: &mut [f32]) {
+    for (magnitude, bin) in output.iter_mut().zip(spectrum.iter()) {
+        *magnitude = bin.norm();
+    }
+}
+
+pub fn phases(spectrum: &[Complex], output: &mut [f32]) {
+    for (phase, bin) in output.iter_mut().zip(spectrum.iter()) {
+        *phase = bin.arg();
+    }
+}
+
+// Rebuild a spectrum from magnitudes and phases.
+pub fn from_polar(magnitudes: &[f32], phases: &[f32], output: &mut [Complex]) {
+    for ((bin, magnitude), phase) in output.iter_mut().zip(magnitudes).zip(phases) {
+        *bin = Complex::from_polar(*magnitude, *phase);
+    }
+}
+
+// Centre frequency of a bin in Hz
+pub fn bin_frequency(bin: usize, fft_size: usize, sample_rate: u32) -> f32 {
+    bin as f32 * sample_rate as f32 / fft_size as f32
+}
+
+// Window functions
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Window {
+    Rectangular,
+    Hann,
+    Hamming,
+    // Four-term Blackman-Harris, with sidelobes below -92dB
+    BlackmanHarris,
+    // Kaiser window with the given beta. Higher values trade a wider main
+    // lobe for lower sidelobes.
+    Kaiser(f32),
+}
+
+// Windows are periodic rather than symmetric, which is what overlap-add
+// needs for the overlapping windows to sum to a constant.
+pub fn make_window(window: Window, size: usize) -> Vec<f32> {
+    (0..size)
+        .map(|n| {
+            let x = n as f32 / size as f32;
+            match window {
+                Window::Rectangular => 1.0,
+                Window::Hann => 0.5 - 0.5 * (TWO_PI * x).cos(),
+                Window::Hamming => 0.54 - 0.46 * (TWO_PI * x).cos(),
+                Window::BlackmanHarris => {
+                    0.35875 - 0.48829 * (TWO_PI * x).cos() + 0.14128 * (2.0 * TWO_PI * x).cos()
+                        - 0.01168 * (3.0 * TWO_PI * x).cos()
+                }
+                Window::Kaiser(beta) => {
+                    let t = 2.0 * x - 1.0;
+                    bessel_i0(beta * (1.0 - t * t).max(0.0).sqrt()) / bessel_i0(beta)
+                }
+            }
+        })
+        .collect()
+}
+
+// Zeroth-order modified Bessel function of the first kind, by its power
+// series.
+fn bessel_i0(x: f32) -> f32 {
+    let mut sum = 1.0;
+    let mut term = 1.0;
+    let half = x * 0.5;
+    let mut k = 1.0;
+    while term > sum * 1e-9 {
+        term *= (half / k) * (half / k);
+        sum += term;
+        k += 1.0;
+    }
+    sum
+}
+
+// Short-time Fourier transform with overlap-add resynthesis. Frames of
+// fft_size samples are taken every hop_size samples, windowed, and
+// transformed with a RealFft. Resynthesis windows each frame again and
+// divides by the summed squared windows, so any window and hop that overlap
+// give perfect reconstruction of unmodified spectra.
+pub struct Stft {
+    fft: RealFft,
+    window: Vec<f32>,
+    hop_size: usize,
+    frame: Vec<f32>,
+}
+
+impl Stft {
+    // Panics if fft_size is not a power of two.
+    pub fn new(fft_size: usize, hop_size: usize, window: Window) -> Stft {
+        Stft {
+            fft: RealFft::new(fft_size),
+            window: make_window(window, fft_size),
+            hop_size: hop_size.clamp(1, fft_size),
+            frame: vec![0.0; fft_size],
+        }
+    }
+    pub fn fft_size(&self) -> usize {
+        self.fft.size()
+    }
+    pub fn hop_size(&self) -> usize {
+        self.hop_size
+    }
+    pub fn bins(&self) -> usize {
+        self.fft.bins()
+    }
+    pub fn window(&self) -> &[f32] {
+        &self.window
+    }
+    // Window and transform one frame of fft_size samples.
+    pub fn analyze_frame(&mut self, input: &[f32], spectrum: &mut [Complex]) {
+        for ((sample, input), window) in self.frame.iter_mut().zip(input).zip(&self.window) {
+            *sample = input * window;
+        }
+        self.fft.forward(&self.frame, spectrum);
+    }
+    // Inverse transform and window one frame, ready for overlap-add. The
+    // output is not normalized for the window overlap.
+    pub fn synthesize_frame(&mut self, spectrum: &[Complex], output: &mut [f32]) {
+        self.fft.inverse(spectrum, &mut self.frame);
+        for ((output, sample), window) in output.iter_mut().zip(&self.frame).zip(&self.window) {
+            *output = sample * window;
+        }
+    }
+    // Analyze a whole signal. Frames are centred on multiples of hop_size,
+    // with the signal padded with silence at both ends.
+    pub fn analyze(&mut self, signal: &[f32]) -> Vec<Vec<Complex>> {
+        let fft_size = self.fft_size();
+        let frame_count = signal.len() / self.hop_size + 1;
+        let mut input = vec![0.0; fft_size];
+        (0..frame_count)
+            .map(|t| {
+                let start = (t * self.hop_size) as isize - (fft_size / 2) as isize;
+                for (i, sample) in input.iter_mut().enumerate() {
+                    let index = start + i as isize;
+                    *sample = if index >= 0 && (index as usize) < signal.len() {
+                        signal[index as usize]
+                    } else {
+                        0.0
+                    };
+                }
+                let mut spectrum = vec![Complex::default(); self.bins()];
+                self.analyze_frame(&input, &mut spectrum);
+                spectrum
+            })
+            .collect()
+    }
+    // Resynthesize frames from analyze, or modified versions of them, into a
+    // signal of the given length.
+    pub fn synthesize(&mut self, frames: &[Vec<Complex>], length: usize) -> Vec<f32> {
+        let fft_size = self.fft_size();
+        let mut output = vec![0.0; length];
+        let mut window_sum = vec![0.0; length];
+        let mut frame = vec![0.0; fft_size];
+        for (t, spectrum) in frames.iter().enumerate() {
+            self.synthesize_frame(spectrum, &mut frame);
+            let start = (t * self.hop_size) as isize - (fft_size / 2) as isize;
+            for (i, (sample, window)) in frame.iter().zip(&self.window).enumerate() {
+                let index = start + i as isize;
+                if index >= 0 && (index as usize) < length {
+                    output[index as usize] += sample;
+                    window_sum[index as usize] += window * window;
+                }
+            }
+        }
+        for (sample, sum) in output.iter_mut().zip(window_sum) {
+            if sum > 1e-6 {
+                *sample /= sum;
+            }
+        }
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A signal with no symmetry, so every bin is exercised.
+    fn test_signal(size: usize) -> Vec<f32> {
+        (0..size)
+            .map(|n| ((n * 7919 + 13) % 101) as f32 / 50.0 - 1.0)
+            .collect()
+    }
+
+    fn naive_dft(input: &[f32]) -> Vec<Complex> {
+        let size = input.len();
+        (0..=size / 2)
+            .map(|k| {
+                let mut sum = (0.0f64, 0.0f64);
+                for (n, sample) in input.iter().enumerate() {
+                    let angle = -std::f64::consts::TAU * (k * n) as f64 / size as f64;
+                    sum.0 += *sample as f64 * angle.cos();
+                    sum.1 += *sample as f64 * angle.sin();
+                }
+                Complex::new(sum.0 as f32, sum.1 as f32)
+            })
+            .collect()
+    }
+
+    fn assert_close(actual: Complex, expected: Complex, tolerance: f32) {
+        assert!(
+            (actual - expected).norm() <= tolerance,
+            "expected {:?}, got {:?}",
+            expected,
+            actual
+        );
+    }
+
+    #[test]
+    fn real_fft_matches_dft() {
+        for size in [2, 4, 64].iter() {
+            let input = test_signal(*size);
+            let mut fft = RealFft::new(*size);
+            let mut output = vec![Complex::default(); fft.bins()];
+            fft.forward(&input, &mut output);
+            for (actual, expected) in output.iter().zip(naive_dft(&input)) {
+                assert_close(*actual, expected, 1e-4);
+            }
+        }
+    }
+
+    #[test]
+    fn forward_then_inverse() {
+        let input = test_signal(256);
+        let mut fft = RealFft::new(256);
+        let mut spectrum = vec![Complex::default(); fft.bins()];
+        let mut output = vec![0.0; 256];
+        fft.forward(&input, &mut spectrum);
+        fft.inverse(&spectrum, &mut output);
+        for (a, b) in input.iter().zip(output.iter()) {
+            assert!((a - b).abs() < 1e-5);
+        }
+
+        let mut data: Vec<Complex> = input
+            .chunks(2)
+            .map(|pair| Complex::new(pair[0], pair[1]))
+            .collect();
+        let original = data.clone();
+        let fft = Fft::new(128);
+        fft.forward(&mut data);
+        fft.inverse(&mut data);
+        for (a, b) in data.iter().zip(original.iter()) {
+            assert_close(*a, *b, 1e-5);
+        }
+    }
+
+    #[test]
+    fn sine_bin() {
+        let size = 512;
+        let bin = 10;
+        let input: Vec<f32> = (0..size)
+            .map(|n| (TWO_PI * (bin * n) as f32 / size as f32).sin())
+            .collect();
+        let mut fft = RealFft::new(size);
+        let mut spectrum = vec![Complex::default(); fft.bins()];
+        fft.forward(&input, &mut spectrum);
+        // A unit sine puts size / 2 into its bin, as -i for the sine phase.
+        assert_close(spectrum[bin], Complex::new(0.0, -(size as f32) / 2.0), 1e-2);
+        for (k, value) in spectrum.iter().enumerate() {
+            if k != bin {
+                assert!(value.norm() < 1e-2, "bin {} is {:?}", k, value);
+            }
+        }
+    }
+
+    #[test]
+    fn stft_reconstruction() {
+        let input = test_signal(4000);
+        let mut stft = Stft::new(512, 128, Window::Hann);
+        let frames = stft.analyze(&input);
+        let output = stft.synthesize(&frames, input.len());
+        for (i, (a, b)) in input.iter().zip(output.iter()).enumerate() {
+            assert!((a - b).abs() < 1e-5, "sample {}: {} != {}", i, a, b);
+        }
+    }
+}
@@ -0,0 +1,562 @@
+use std::error::Error;
+use std::f32::consts::E;
+use std::fmt;
+
+use crate::envelope;
+use crate::midi;
+use crate::osc;
+use crate::processor::Processor;
+use crate::synth::Message;
+use crate::tuning;
+use crate::voice::{AllocationKind, StealPolicy, VoiceAllocator};
+use crate::wavetable;
+
+// Frequency and phase modulation synthesis. An Operator is a sine oscillator
+// with its own envelope, and an Algorithm describes which operators modulate
+// which. Modulation is applied to the phase of each operator's OscReader, so
+// an operator's output level doubles as its modulation index, in cycles
+// (1.0 == 2 * PI radians).
+
+const TABLE_SIZE: usize = 1024;
+const MAX_VOICES: usize = 16;
+pub const NUM_OPERATORS: usize = 4;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum FreqMode {
+    // Multiple of the note frequency
+    Ratio(f32),
+    // Fixed frequency in Hz, regardless of the note
+    Fixed(f32),
+}
+
+#[derive(Clone)]
+pub struct OperatorParams {
+    pub freq_mode: FreqMode,
+    // Detune in Hz, added after the ratio is applied
+    pub detune: f32,
+    pub level: f32,
+    // Amount of the operator's own output fed back into its phase
+    pub feedback: f32,
+    pub attack: f32,
+    pub release: f32,
+}
+
+impl Default for OperatorParams {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl OperatorParams {
+    pub fn new() -> OperatorParams {
+        OperatorParams {
+            freq_mode: FreqMode::Ratio(1.0),
+            detune: 0.0,
+            level: 1.0,
+            feedback: 0.0,
+            attack: 0.01,
+            release: 0.5,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Operator {
+    osc: osc::OscReader,
+    env: envelope::EnvReader,
+    // The last two outputs, averaged for feedback to keep it stable
+    history: [f32; 2],
+}
+
+impl Default for Operator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Operator {
+    pub fn new() -> Operator {
+        Operator {
+            osc: osc::OscReader::new(),
+            env: envelope::EnvReader::new(),
+            history: [0.0; 2],
+        }
+    }
+    pub fn is_active(&self) -> bool {
+        self.env.is_active
+    }
+    pub fn start(&mut self) {
+        self.env.start();
+    }
+    pub fn stop(&mut self) {
+        self.env.stop();
+    }
+    pub fn reset(&mut self) {
+        self.env.reset();
+        self.osc.phase = 0.0;
+        self.history = [0.0; 2];
+    }
+    // Advance by one sample, with the phase offset by modulation (in cycles),
+    // and return the operator's output.
+    pub fn tick(
+        &mut self,
+        note_freq: f32,
+        modulation: f32,
+        params: &OperatorParams,
+        sine_table: &[f32],
+        env_table: &[f32],
+        sr: u32,
+    ) -> f32 {
+        if !self.env.is_active {
+            return 0.0;
+        }
+        let freq = match params.freq_mode {
+            FreqMode::Ratio(ratio) => note_freq * ratio,
+            FreqMode::Fixed(freq) => freq,
+        } + params.detune;
+        self.osc.increment(freq, sr);
+        self.env.increment(params.attack, params.release, sr);
+
+        // Offset the public phase for the read only, so modulation doesn't
+        // accumulate into the oscillator's frequency.
+        let feedback = params.feedback * (self.history[0] + self.history[1]) * 0.5;
+        let phase = self.osc.phase;
+        self.osc.phase = wrap_phase(phase + modulation + feedback);
+        let sample = self.osc.read(sine_table, osc::linear_interpolate);
+        self.osc.phase = phase;
+
+        let level = self.env.read(env_table, envelope::linear_interpolate) * params.level;
+        let output = sample * level;
+        self.history[1] = self.history[0];
+        self.history[0] = output;
+        output
+    }
+}
+
+// Describes how operators are connected. modulators[i] lists the operators
+// that modulate operator i, and carriers lists the operators that are mixed
+// to the output. Operators may only be modulated by operators with a higher
+// index, except through their own feedback, so the graph is evaluated from
+// the highest index down.
+// Wrap a phase into 0.0 to 1.0. rem_euclid returns 1.0 for tiny negative
+// values, which would read past the end of the table.
+fn wrap_phase(phase: f32) -> f32 {
+    let wrapped = phase - phase.floor();
+    if wrapped >= 1.0 {
+        0.0
+    } else {
+        wrapped
+    }
+}
+
+#[derive(Clone)]
+pub struct Algorithm {
+    modulators: Vec<Vec<usize>>,
+    carriers: Vec<usize>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum AlgorithmError {
+    // An operator is modulated by one with the same or a lower index, or by
+    // one that doesn't exist.
+    InvalidModulator { target: usize, source: usize },
+    // A carrier index is past the last operator.
+    InvalidCarrier(usize),
+    // The algorithm has a different number of operators than the synth.
+    OperatorCount { expected: usize, found: usize },
+}
+
+impl fmt::Display for AlgorithmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AlgorithmError::InvalidModulator { target, source } => write!(
+                f,
+                "operator {} can't be modulated by operator {}",
+                target, source
+            ),
+            AlgorithmError::InvalidCarrier(carrier) => {
+                write!(f, "carrier {} is not an operator", carrier)
+            }
+            AlgorithmError::OperatorCount { expected, found } => write!(
+                f,
+                "algorithm has {} operators, but {} are needed",
+                found, expected
+            ),
+        }
+    }
+}
+
+impl Error for AlgorithmError {}
+
+impl Algorithm {
+    pub fn new(
+        modulators: Vec<Vec<usize>>,
+        carriers: Vec<usize>,
+    ) -> Result<Algorithm, AlgorithmError> {
+        for (target, sources) in modulators.iter().enumerate() {
+            for source in sources.iter() {
+                if *source <= target || *source >= modulators.len() {
+                    return Err(AlgorithmError::InvalidModulator {
+                        target,
+                        source: *source,
+                    });
+                }
+            }
+        }
+        if let Some(carrier) = carriers
+            .iter()
+            .find(|carrier| **carrier >= modulators.len())
+        {
+            return Err(AlgorithmError::InvalidCarrier(*carrier));
+        }
+        Ok(Algorithm {
+            modulators,
+            carriers,
+        })
+    }
+    // The eight classic four-operator algorithms, numbered from 1. Operator 1
+    // is index 0. Numbers outside 1-8 use algorithm 1.
+    //
+    // 1: 4 -> 3 -> 2 -> 1
+    // 2: (3 + 4) -> 2 -> 1
+    // 3: (4 + (3 -> 2)) -> 1
+    // 4: ((4 -> 3) + 2) -> 1
+    // 5: 4 -> 3, 2 -> 1, carriers 1 and 3
+    // 6: 4 -> each of 1, 2 and 3, all carriers
+    // 7: 4 -> 3, carriers 1, 2 and 3
+    // 8: all operators are carriers
+    pub fn preset(number: usize) -> Algorithm {
+        let (modulators, carriers) = match number {
+            2 => (vec![vec![1], vec![2, 3], vec![], vec![]], vec![0]),
+            3 => (vec![vec![1, 3], vec![2], vec![], vec![]], vec![0]),
+            4 => (vec![vec![1, 2], vec![], vec![3], vec![]], vec![0]),
+            5 => (vec![vec![1], vec![], vec![3], vec![]], vec![0, 2]),
+            6 => (vec![vec![3], vec![3], vec![3], vec![]], vec![0, 1, 2]),
+            7 => (vec![vec![], vec![], vec![3], vec![]], vec![0, 1, 2]),
+            8 => (vec![vec![], vec![], vec![], vec![]], vec![0, 1, 2, 3]),
+            _ => (vec![vec![1], vec![2], vec![3], vec![]], vec![0]),
+        };
+        // The presets are all valid.
+        Algorithm {
+            modulators,
+            carriers,
+        }
+    }
+    pub fn num_operators(&self) -> usize {
+        self.modulators.len()
+    }
+}
+
+// A single FM voice, with one operator per operator in the algorithm.
+#[derive(Clone)]
+pub struct FmVoice {
+    operators: Vec<Operator>,
+    outputs: Vec<f32>,
+}
+
+impl FmVoice {
+    pub fn new(num_operators: usize) -> FmVoice {
+        FmVoice {
+            operators: vec![Operator::new(); num_operators],
+            outputs: vec![0.0; num_operators],
+        }
+    }
+    // The voice is active while any carrier is still sounding.
+    pub fn is_active(&self, algorithm: &Algorithm) -> bool {
+        algorithm
+            .carriers
+            .iter()
+            .any(|carrier| self.operators[*carrier].is_active())
+    }
+    pub fn start(&mut self) {
+        for operator in self.operators.iter_mut() {
+            operator.start();
+        }
+    }
+    pub fn stop(&mut self) {
+        for operator in self.operators.iter_mut() {
+            operator.stop();
+        }
+    }
+    pub fn reset(&mut self) {
+        for operator in self.operators.iter_mut() {
+            operator.reset();
+        }
+    }
+    pub fn tick(
+        &mut self,
+        note_freq: f32,
+        algorithm: &Algorithm,
+        params: &[OperatorParams],
+        sine_table: &[f32],
+        env_table: &[f32],
+        sample_rate: u32,
+    ) -> f32 {
+        for i in (0..self.operators.len()).rev() {
+            let modulation: f32 = algorithm.modulators[i]
+                .iter()
+                .map(|source| self.outputs[*source])
+                .sum();
+            self.outputs[i] = self.operators[i].tick(
+                note_freq,
+                modulation,
+                &params[i],
+                sine_table,
+                env_table,
+                sample_rate,
+            );
+        }
+        let mix: f32 = algorithm
+            .carriers
+            .iter()
+            .map(|carrier| self.outputs[*carrier])
+            .sum();
+        mix / algorithm.carriers.len().max(1) as f32
+    }
+}
+
+// A polyphonic four-operator FM synth, controlled with the same messages as
+// synth::BasicSynth. Messages for modules the FM synth doesn't have, such as
+// the filter and delay, are ignored.
+pub struct FmSynth {
+    algorithm: Algorithm,
+    env_table: Vec<f32>,
+    midi_table: Vec<f32>,
+    params: Vec<OperatorParams>,
+    // Used when processing through the Processor trait
+    sample_rate: u32,
+    sine_table: Vec<f32>,
+    sustain_pedal: bool,
+    voice_allocator: VoiceAllocator,
+    voice_freq: Vec<f32>,
+    voice_sustained: Vec<bool>,
+    voice_velocity: Vec<f32>,
+    voices: Vec<FmVoice>,
+    volume: f32,
+}
+
+impl Default for FmSynth {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FmSynth {
+    pub fn new() -> FmSynth {
+        FmSynth {
+            algorithm: Algorithm::preset(1),
+            env_table: wavetable::make_exp_envelope(TABLE_SIZE, E),
+            midi_table: midi::make_midi_freq_table(),
+            params: vec![OperatorParams::new(); NUM_OPERATORS],
+            sample_rate: 44100,
+            sine_table: wavetable::make_sine_table(TABLE_SIZE),
+            sustain_pedal: false,
+            voice_allocator: VoiceAllocator::new(MAX_VOICES, StealPolicy::Oldest),
+            voice_freq: vec![0.0; MAX_VOICES],
+            voice_sustained: vec![false; MAX_VOICES],
+            voice_velocity: vec![0.0; MAX_VOICES],
+            voices: vec![FmVoice::new(NUM_OPERATORS); MAX_VOICES],
+            volume: 0.5,
+        }
+    }
+    // Algorithms must have NUM_OPERATORS operators.
+    pub fn set_algorithm(&mut self, algorithm: Algorithm) -> Result<(), AlgorithmError> {
+        if algorithm.num_operators() != NUM_OPERATORS {
+            return Err(AlgorithmError::OperatorCount {
+                expected: NUM_OPERATORS,
+                found: algorithm.num_operators(),
+            });
+        }
+        self.algorithm = algorithm;
+        Ok(())
+    }
+    // Indices past the last operator are ignored.
+    pub fn set_operator(&mut self, index: usize, params: OperatorParams) {
+        if let Some(operator) = self.params.get_mut(index) {
+            *operator = params;
+        }
+    }
+    // Replace the note frequency table. See BasicSynth::set_tuning_table.
+    pub fn set_tuning_table(&mut self, table: Vec<f32>) -> Result<(), tuning::TuningError> {
+        self.midi_table = tuning::check_table(table)?;
+        Ok(())
+    }
+    pub fn apply_mts(&mut self, message: &[u8]) -> bool {
+        tuning::apply_mts(&mut self.midi_table, message)
+    }
+    pub fn send(&mut self, message: Message) {
+        match message {
+            // MIDI notes only go up to 127, so others are ignored.
+            Message::NoteOn(note, _) | Message::NoteOff(note, _) if note > 127 => {}
+            Message::NoteOn(note, 0) | Message::NoteOff(note, _) => {
+                if let Some(i) = self.voice_allocator.note_off(note) {
+                    if self.sustain_pedal {
+                        self.voice_sustained[i] = true;
+                    } else {
+                        self.voices[i].stop();
+                    }
+                }
+            }
+            Message::NoteOn(note, velocity) => {
+                // Notes left unmapped by the tuning aren't played.
+                if self.midi_table[note as usize] <= 0.0 {
+                    return;
+                }
+                // Stolen voices restart their envelopes from the current
+                // level, so there is no click from the amplitude jumping.
+                let allocation = self.voice_allocator.note_on(note, &[]);
+                let i = allocation.voice;
+                if allocation.kind == AllocationKind::Free {
+                    self.voices[i].reset();
+                }
+                self.voice_freq[i] = self.midi_table[note as usize];
+                self.voice_velocity[i] = velocity as f32 / 127.0;
+                self.voice_sustained[i] = false;
+                self.voices[i].start();
+            }
+            Message::SetSustainPedal(is_down) => {
+                self.sustain_pedal = is_down;
+                if !is_down {
+                    for i in 0..MAX_VOICES {
+                        if self.voice_sustained[i] {
+                            self.voice_sustained[i] = false;
+                            self.voices[i].stop();
+                        }
+                    }
+                }
+            }
+            Message::SetVolume(value) => {
+                self.volume = f32::powf(value, 2.0);
+            }
+            Message::SetPolyphony(value) => {
+                self.voice_allocator.set_polyphony(value);
+            }
+            Message::SetStealPolicy(policy) => {
+                self.voice_allocator.set_policy(policy);
+            }
+            _ => {}
+        }
+    }
+    pub fn tick(&mut self, sample_rate: u32) -> f32 {
+        let mut output = 0.0;
+        for i in 0..MAX_VOICES {
+            if !self.voice_allocator.is_active(i) {
+                continue;
+            }
+            output += self.voices[i].tick(
+                self.voice_freq[i],
+                &self.algorithm,
+                &self.params,
+                &self.sine_table,
+                &self.env_table,
+                sample_rate,
+            ) * self.voice_velocity[i];
+            if !self.voices[i].is_active(&self.algorithm) {
+                self.voice_allocator.voice_finished(i);
+            }
+        }
+        output * self.volume
+    }
+}
+
+// The synth is a signal generator, so the input to process is ignored.
+impl Processor for FmSynth {
+    fn prepare(&mut self, sample_rate: u32, _max_block: usize) {
+        self.sample_rate = sample_rate;
+    }
+    fn reset(&mut self) {
+        for i in 0..MAX_VOICES {
+            self.voices[i].reset();
+            self.voice_allocator.voice_finished(i);
+            self.voice_sustained[i] = false;
+        }
+        self.sustain_pedal = false;
+    }
+    fn process(&mut self, _input: f32) -> f32 {
+        self.tick(self.sample_rate)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A tiny negative phase offset used to wrap to exactly 1.0, reading past
+    // the end of the sine table.
+    #[test]
+    fn negative_epsilon_modulation() {
+        let sine_table = wavetable::make_sine_table(TABLE_SIZE);
+        let env_table = wavetable::make_exp_envelope(TABLE_SIZE, E);
+        let params = OperatorParams {
+            freq_mode: FreqMode::Fixed(0.0),
+            ..OperatorParams::new()
+        };
+        let mut operator = Operator::new();
+        operator.start();
+        for _ in 0..4 {
+            let output = operator.tick(0.0, -1e-9, &params, &sine_table, &env_table, 44100);
+            assert!(output.is_finite());
+        }
+        assert_eq!(wrap_phase(-1e-9), 0.0);
+        assert_eq!(wrap_phase(1.25), 0.25);
+        assert_eq!(wrap_phase(-0.25), 0.75);
+    }
+
+    #[test]
+    fn algorithm_rejects_lower_modulators() {
+        let result = Algorithm::new(vec![vec![], vec![0]], vec![0]);
+        assert_eq!(
+            result.err(),
+            Some(AlgorithmError::InvalidModulator {
+                target: 1,
+                source: 0
+            })
+        );
+    }
+
+    #[test]
+    fn algorithm_rejects_missing_operators() {
+        let modulator = Algorithm::new(vec![vec![2], vec![]], vec![0]);
+        assert!(modulator.is_err());
+        let carrier = Algorithm::new(vec![vec![1], vec![]], vec![2]);
+        assert_eq!(carrier.err(), Some(AlgorithmError::InvalidCarrier(2)));
+    }
+
+    #[test]
+    fn set_algorithm_checks_operator_count() {
+        let mut synth = FmSynth::new();
+        let algorithm = Algorithm::new(vec![vec![1], vec![]], vec![0]).unwrap();
+        assert_eq!(
+            synth.set_algorithm(algorithm).err(),
+            Some(AlgorithmError::OperatorCount {
+                expected: NUM_OPERATORS,
+                found: 2
+            })
+        );
+        for number in 1..=8 {
+            assert!(synth.set_algorithm(Algorithm::preset(number)).is_ok());
+        }
+    }
+
+    #[test]
+    fn set_operator_ignores_out_of_range() {
+        let mut synth = FmSynth::new();
+        synth.set_operator(NUM_OPERATORS, OperatorParams::default());
+    }
+
+    #[test]
+    fn rejects_short_tuning_tables() {
+        let mut synth = FmSynth::new();
+        assert!(synth.set_tuning_table(vec![440.0; 129]).is_err());
+        let table = vec![440.0; tuning::TABLE_SIZE];
+        assert!(synth.set_tuning_table(table).is_ok());
+    }
+
+    #[test]
+    fn ignores_notes_above_127() {
+        let mut synth = FmSynth::new();
+        synth.send(Message::NoteOn(200, 100));
+        synth.send(Message::NoteOff(200, 0));
+        assert_eq!(synth.tick(44100), 0.0);
+    }
+}
@@ -0,0 +1,273 @@
+use std::f32::consts::PI;
+
+use crate::delay::CircularBuffer;
+
+// Fractional-delay interpolation policies for reading from a CircularBuffer.
+// Delay effects are generic over an Interpolator, so the trade-off between
+// cost, high-frequency loss and modulation artifacts can be chosen per
+// effect.
+//
+// Delays are in samples, where a delay of 1.0 reads the most recently written
+// sample. Policies that need neighbouring samples clamp them to the range the
+// buffer can provide.
+pub trait Interpolator {
+    // Policies with internal state (such as Allpass) expect to be read once
+    // per sample, in order.
+    fn read(&mut self, buf: &CircularBuffer, delay_samples: f32) -> f32;
+    // Clear any internal state.
+    fn reset(&mut self) {}
+}
+
+// Read the sample written length writes ago. CircularBuffer clamps lengths
+// that are too long, so only the lower bound needs handling here.
+fn tap(buf: &CircularBuffer, length: isize) -> f32 {
+    buf.read(length.max(1) as usize)
+}
+
+// Truncates the delay to a whole number of samples. Cheapest, but modulated
+// delays will have zipper noise.
+#[derive(Clone, Copy, Default)]
+pub struct NoInterpolation;
+
+impl Interpolator for NoInterpolation {
+    fn read(&mut self, buf: &CircularBuffer, delay_samples: f32) -> f32 {
+        tap(buf, delay_samples as isize)
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+pub struct Linear;
+
+impl Interpolator for Linear {
+    fn read(&mut self, buf: &CircularBuffer, delay_samples: f32) -> f32 {
+        let index = delay_samples.floor();
+        let fraction = delay_samples - index;
+        let index = index as isize;
+        tap(buf, index) * (1.0 - fraction) + tap(buf, index + 1) * fraction
+    }
+}
+
+// Four-point, third-order Hermite interpolation. Much less high-frequency
+// loss than Linear at a small extra cost.
+#[derive(Clone, Copy, Default)]
+pub struct Hermite;
+
+impl Interpolator for Hermite {
+    fn read(&mut self, buf: &CircularBuffer, delay_samples: f32) -> f32 {
+        let index = delay_samples.floor();
+        let x = delay_samples - index;
+        let index = index as isize;
+        let y0 = tap(buf, index - 1);
+        let y1 = tap(buf, index);
+        let y2 = tap(buf, index + 1);
+        let y3 = tap(buf, index + 2);
+
+        let c1 = 0.5 * (y2 - y0);
+        let c2 = y0 - 2.5 * y1 + 2.0 * y2 - 0.5 * y3;
+        let c3 = 0.5 * (y3 - y0) + 1.5 * (y1 - y2);
+        ((c3 * x + c2) * x + c1) * x + y1
+    }
+}
+
+// Lagrange interpolation of order N, using N + 1 samples around the delay.
+// Odd orders are centred on the interval containing the delay.
+#[derive(Clone, Copy)]
+pub struct Lagrange {
+    order: usize,
+}
+
+impl Lagrange {
+    pub fn new(order: usize) -> Lagrange {
+        Lagrange {
+            order: order.max(1),
+        }
+    }
+}
+
+impl Default for Lagrange {
+    fn default() -> Self {
+        Lagrange::new(3)
+    }
+}
+
+impl Interpolator for Lagrange {
+    fn read(&mut self, buf: &CircularBuffer, delay_samples: f32) -> f32 {
+        let first = delay_samples.floor() as isize - (self.order as isize - 1) / 2;
+        // Delay relative to the first tap
+        let d = delay_samples - first as f32;
+        let mut output = 0.0;
+        for k in 0..=self.order {
+            let mut coefficient = 1.0;
+            for j in 0..=self.order {
+                if j != k {
+                    coefficient *= (d - j as f32) / (k as f32 - j as f32);
+                }
+            }
+            output += coefficient * tap(buf, first + k as isize);
+        }
+        output
+    }
+}
+
+// First-order allpass (Thiran) interpolation. It has a flat magnitude
+// response, so there is no high-frequency loss, which suits fixed or slowly
+// modulated delays such as physical models. Fast modulation causes transients,
+// since the filter state belongs to the previous delay value.
+#[derive(Clone, Copy, Default)]
+pub struct Allpass {
+    previous_output: f32,
+}
+
+impl Interpolator for Allpass {
+    fn read(&mut self, buf: &CircularBuffer, delay_samples: f32) -> f32 {
+        // Keep the fractional part in the range 0.1 - 1.1, where the allpass
+        // coefficient stays well away from the unstable region. Delays below
+        // one sample can't be read, and would make the coefficient infinite.
+        let delay_samples = delay_samples.max(1.0);
+        let index = (delay_samples - 0.1).floor().max(1.0);
+        let fraction = delay_samples - index;
+        let eta = (1.0 - fraction) / (1.0 + fraction);
+        let index = index as isize;
+        let output = eta * tap(buf, index) + tap(buf, index + 1) - eta * self.previous_output;
+        self.previous_output = output;
+        output
+    }
+    fn reset(&mut self) {
+        self.previous_output = 0.0;
+    }
+}
+
+// Windowed-sinc interpolation, the highest quality and the most expensive.
+// The kernel is precomputed with a Blackman window, and read with linear
+// interpolation between kernel points.
+#[derive(Clone)]
+pub struct Sinc {
+    half_width: usize,
+    // Kernel points per sample
+    resolution: usize,
+    kernel: Vec<f32>,
+}
+
+impl Sinc {
+    // half_width is the number of samples used on each side of the delay.
+    pub fn new(half_width: usize) -> Sinc {
+        let half_width = half_width.max(1);
+        let resolution = 256;
+        let length = half_width * resolution;
+        let mut kernel = Vec::with_capacity(length + 2);
+        for i in 0..length + 2 {
+            let x = i as f32 / resolution as f32;
+            let sinc = if x == 0.0 {
+                1.0
+            } else {
+                (PI * x).sin() / (PI * x)
+            };
+            // Blackman window over -half_width..half_width, evaluated on the
+            // positive half.
+            let n = (x / half_width as f32).min(1.0);
+            let window = 0.42 + 0.5 * (PI * n).cos() + 0.08 * (2.0 * PI * n).cos();
+            kernel.push(sinc * window);
+        }
+        Sinc {
+            half_width,
+            resolution,
+            kernel,
+        }
+    }
+    fn kernel_at(&self, x: f32) -> f32 {
+        let position = x.abs() * self.resolution as f32;
+        let index = position as usize;
+        if index + 1 >= self.kernel.len() {
+            return 0.0;
+        }
+        let fraction = position - index as f32;
+        self.kernel[index] * (1.0 - fraction) + self.kernel[index + 1] * fraction
+    }
+}
+
+impl Default for Sinc {
+    fn default() -> Self {
+        Sinc::new(8)
+    }
+}
+
+impl Interpolator for Sinc {
+    fn read(&mut self, buf: &CircularBuffer, delay_samples: f32) -> f32 {
+        let index = delay_samples.floor();
+        let fraction = delay_samples - index;
+        let index = index as isize;
+        let half_width = self.half_width as isize;
+        let mut output = 0.0;
+        for k in (1 - half_width)..=half_width {
+            output += self.kernel_at(k as f32 - fraction) * tap(buf, index + k);
+        }
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A buffer holding 0.0, 1.0, 2.0, ..., so that a delay of d reads
+    // 64.0 - d.
+    fn ramp() -> CircularBuffer {
+        let mut buf = CircularBuffer::new(64);
+        for i in 0..64 {
+            buf.write(i as f32);
+        }
+        buf
+    }
+
+    fn policies() -> Vec<Box<dyn Interpolator>> {
+        vec![
+            Box::new(NoInterpolation),
+            Box::new(Linear),
+            Box::new(Hermite),
+            Box::new(Lagrange::new(3)),
+            Box::new(Lagrange::new(4)),
+            Box::new(Allpass::default()),
+            Box::new(Sinc::default()),
+        ]
+    }
+
+    #[test]
+    fn integer_delays_are_exact() {
+        let buf = ramp();
+        for mut policy in policies() {
+            for delay in 10..40 {
+                let expected = (64 - delay) as f32;
+                let output = policy.read(&buf, delay as f32);
+                assert!((output - expected).abs() < 1e-3, "delay {}", delay);
+            }
+        }
+    }
+
+    #[test]
+    fn polynomial_policies_follow_a_ramp() {
+        let buf = ramp();
+        let mut policies: Vec<Box<dyn Interpolator>> = vec![
+            Box::new(Linear),
+            Box::new(Hermite),
+            Box::new(Lagrange::new(3)),
+            Box::new(Lagrange::new(4)),
+        ];
+        for policy in policies.iter_mut() {
+            for step in 0..100 {
+                let delay = 10.0 + step as f32 * 0.27;
+                let output = policy.read(&buf, delay);
+                assert!((output - (64.0 - delay)).abs() < 1e-3, "delay {}", delay);
+            }
+        }
+    }
+
+    #[test]
+    fn short_delays_are_clamped() {
+        let buf = ramp();
+        for mut policy in policies() {
+            assert!(policy.read(&buf, 0.0).is_finite());
+            assert!(policy.read(&buf, -3.5).is_finite());
+        }
+        assert_eq!(Linear.read(&buf, 0.0), 63.0);
+    }
+}
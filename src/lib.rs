@@ -1,8 +1,24 @@
+pub mod biquad;
+pub mod blep;
+pub mod chorus;
 pub mod svf;
 pub mod constants;
+pub mod conversion;
+pub mod convolution;
 pub mod delay;
 pub mod envelope;
+pub mod fft;
+pub mod fm;
+pub mod interpolation;
 pub mod midi;
 pub mod osc;
+pub mod pitch_shift;
+pub mod processor;
+pub mod reverb;
+pub mod smf;
 pub mod synth;
+pub mod tuning;
+pub mod vocoder;
+pub mod voice;
+pub mod wav;
 pub mod wavetable;
@@ -1,10 +1,14 @@
+use crate::synth::{Message, OscType};
+
 // Create a table of MIDI note frequencies and store it in memory. Frequencies
 // are accessed from the table by index, which is the desired MIDI note. For
-// example, notes[64] = 329.62..., which is the frequency at C4.
+// example, notes[60] = 261.62..., which is the frequency at C4, and
+// notes[69] = 440.0, at A4. For other tunings, see tuning::Tuning.
 //
 // See: http://subsynth.sourceforge.net/midinote2freq.html
 
 // TODO: determine if it's necessary to wrap table creation in a function
+#[allow(clippy::excessive_precision)]
 pub fn make_midi_freq_table() -> Vec<f32> {
     let notes: Vec<f32> = vec![
         8.1757989156,
@@ -138,3 +142,504 @@ pub fn make_midi_freq_table() -> Vec<f32> {
     ];
     notes.clone()
 }
+
+// A MIDI 1.0 message, as produced by MidiParser. Channels are numbered from 0
+// to 15.
+#[derive(Clone, PartialEq, Debug)]
+pub enum MidiEvent {
+    NoteOff {
+        channel: u8,
+        note: u8,
+        velocity: u8,
+    },
+    // Always has a non-zero velocity. A NoteOn with zero velocity is parsed as
+    // a NoteOff.
+    NoteOn {
+        channel: u8,
+        note: u8,
+        velocity: u8,
+    },
+    PolyAftertouch {
+        channel: u8,
+        note: u8,
+        pressure: u8,
+    },
+    ControlChange {
+        channel: u8,
+        controller: u8,
+        value: u8,
+    },
+    ProgramChange {
+        channel: u8,
+        program: u8,
+    },
+    ChannelAftertouch {
+        channel: u8,
+        pressure: u8,
+    },
+    // From -8192 to 8191, where 0 is the center
+    PitchBend {
+        channel: u8,
+        value: i16,
+    },
+    // SysEx data, without the leading 0xF0 or the trailing 0xF7
+    SysEx(Vec<u8>),
+    Clock,
+    Start,
+    Continue,
+    Stop,
+    ActiveSensing,
+    Reset,
+}
+
+impl MidiEvent {
+    // Build an event from a channel message. Messages with one data byte
+    // ignore data2. Returns None if status is not a channel status byte.
+    pub fn from_channel_message(status: u8, data1: u8, data2: u8) -> Option<MidiEvent> {
+        let channel = status & 0x0f;
+        let event = match status & 0xf0 {
+            0x80 => MidiEvent::NoteOff {
+                channel,
+                note: data1,
+                velocity: data2,
+            },
+            0x90 if data2 == 0 => MidiEvent::NoteOff {
+                channel,
+                note: data1,
+                velocity: 0,
+            },
+            0x90 => MidiEvent::NoteOn {
+                channel,
+                note: data1,
+                velocity: data2,
+            },
+            0xa0 => MidiEvent::PolyAftertouch {
+                channel,
+                note: data1,
+                pressure: data2,
+            },
+            0xb0 => MidiEvent::ControlChange {
+                channel,
+                controller: data1,
+                value: data2,
+            },
+            0xc0 => MidiEvent::ProgramChange {
+                channel,
+                program: data1,
+            },
+            0xd0 => MidiEvent::ChannelAftertouch {
+                channel,
+                pressure: data1,
+            },
+            0xe0 => MidiEvent::PitchBend {
+                channel,
+                // Seven low bits, then seven high bits
+                value: ((data2 as i16) << 7 | data1 as i16) - 8192,
+            },
+            _ => return None,
+        };
+        Some(event)
+    }
+    pub fn channel(&self) -> Option<u8> {
+        match *self {
+            MidiEvent::NoteOff { channel, .. }
+            | MidiEvent::NoteOn { channel, .. }
+            | MidiEvent::PolyAftertouch { channel, .. }
+            | MidiEvent::ControlChange { channel, .. }
+            | MidiEvent::ProgramChange { channel, .. }
+            | MidiEvent::ChannelAftertouch { channel, .. }
+            | MidiEvent::PitchBend { channel, .. } => Some(channel),
+            _ => None,
+        }
+    }
+}
+
+// Number of data bytes following a channel or system common status byte
+fn data_length(status: u8) -> usize {
+    match status {
+        0x80..=0xbf | 0xe0..=0xef => 2,
+        0xc0..=0xdf => 1,
+        // MIDI time code quarter frame, song select
+        0xf1 | 0xf3 => 1,
+        // Song position pointer
+        0xf2 => 2,
+        _ => 0,
+    }
+}
+
+// Upper limit on buffered SysEx data, so a missing 0xF7 can't grow the buffer
+// without bound. Longer messages are dropped.
+const MAX_SYSEX_LENGTH: usize = 4096;
+
+// A byte-at-a-time parser for a MIDI 1.0 stream, such as bytes from a serial
+// port or a MIDI driver. Handles running status, realtime bytes interleaved
+// with other messages, and SysEx framing. System common messages are consumed
+// but not reported.
+pub struct MidiParser {
+    // Status of the message being received. Channel statuses stay set between
+    // messages, for running status.
+    status: Option<u8>,
+    data: [u8; 2],
+    data_count: usize,
+    // Some while a SysEx message is being received
+    sysex: Option<Vec<u8>>,
+    sysex_overflow: bool,
+}
+
+impl Default for MidiParser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MidiParser {
+    pub fn new() -> MidiParser {
+        MidiParser {
+            status: None,
+            data: [0; 2],
+            data_count: 0,
+            sysex: None,
+            sysex_overflow: false,
+        }
+    }
+    pub fn reset(&mut self) {
+        self.status = None;
+        self.data_count = 0;
+        self.sysex = None;
+    }
+    // Parse one byte, returning an event when it completes one.
+    pub fn parse(&mut self, byte: u8) -> Option<MidiEvent> {
+        match byte {
+            // Realtime bytes may appear anywhere, even inside other messages,
+            // and don't affect running status.
+            0xf8 => Some(MidiEvent::Clock),
+            0xfa => Some(MidiEvent::Start),
+            0xfb => Some(MidiEvent::Continue),
+            0xfc => Some(MidiEvent::Stop),
+            0xfe => Some(MidiEvent::ActiveSensing),
+            0xff => {
+                self.reset();
+                Some(MidiEvent::Reset)
+            }
+            0xf9 | 0xfd => None,
+            0xf0 => {
+                self.status = None;
+                self.sysex_overflow = false;
+                match self.sysex.as_mut() {
+                    Some(data) => data.clear(),
+                    None => self.sysex = Some(Vec::with_capacity(256)),
+                }
+                None
+            }
+            0xf7 => {
+                self.status = None;
+                let data = self.sysex.take()?;
+                if self.sysex_overflow {
+                    None
+                } else {
+                    Some(MidiEvent::SysEx(data))
+                }
+            }
+            0x80..=0xf6 => {
+                // Any other status byte ends an unterminated SysEx message,
+                // which is dropped.
+                self.sysex = None;
+                self.data_count = 0;
+                self.status = Some(byte);
+                if byte == 0xf6 {
+                    // Tune request has no data bytes.
+                    self.status = None;
+                }
+                None
+            }
+            _ => {
+                if let Some(data) = self.sysex.as_mut() {
+                    if data.len() < MAX_SYSEX_LENGTH {
+                        data.push(byte);
+                    } else {
+                        self.sysex_overflow = true;
+                    }
+                    return None;
+                }
+                // Data bytes without a status are ignored.
+                let status = self.status?;
+                self.data[self.data_count] = byte;
+                self.data_count += 1;
+                if self.data_count < data_length(status) {
+                    return None;
+                }
+                self.data_count = 0;
+                if status >= 0xf0 {
+                    // System common messages cancel running status.
+                    self.status = None;
+                    return None;
+                }
+                MidiEvent::from_channel_message(status, self.data[0], self.data[1])
+            }
+        }
+    }
+    // Parse a slice of bytes, passing each complete event to a callback.
+    pub fn parse_bytes<F: FnMut(MidiEvent)>(&mut self, bytes: &[u8], mut callback: F) {
+        for &byte in bytes.iter() {
+            if let Some(event) = self.parse(byte) {
+                callback(event);
+            }
+        }
+    }
+}
+
+// What a MIDI controller is mapped to.
+#[derive(Clone, Copy)]
+pub enum ControlTarget {
+    // A message taking a value from 0.0 to 1.0, such as Message::SetVolume
+    Continuous(fn(f32) -> Message),
+    // A message taking an on/off value, such as Message::SetSustainPedal.
+    // Values of 64 and above are on.
+    Switch(fn(bool) -> Message),
+}
+
+impl ControlTarget {
+    fn message(&self, value: u8) -> Message {
+        match self {
+            ControlTarget::Continuous(message) => message(value as f32 / 127.0),
+            ControlTarget::Switch(message) => message(value >= 64),
+        }
+    }
+}
+
+// Maps MIDI events to synth messages. Notes are passed through, controllers
+// are mapped by number, and events with no mapping are ignored.
+#[derive(Clone)]
+pub struct MidiMapping {
+    // Channel to respond to, or None to respond to all channels
+    pub channel: Option<u8>,
+    controllers: [Option<ControlTarget>; 128],
+    pub channel_aftertouch: Option<ControlTarget>,
+    // Receives pitch bend from -1.0 to 1.0
+    pub pitch_bend: Option<fn(f32) -> Message>,
+    pub program_change: Option<fn(u8) -> Option<Message>>,
+}
+
+impl Default for MidiMapping {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MidiMapping {
+    // The default mapping, on all channels: pitch bend, vibrato depth from
+    // the mod wheel (CC1), volume (CC7), sustain pedal
+    // (CC64), filter resonance (CC71), envelope release and attack (CC72 and
+    // CC73), filter cutoff (CC74), and reverb and delay sends (CC91 and
+    // CC94). Programs 0 to 3 select the oscillator.
+    pub fn new() -> MidiMapping {
+        let mut mapping = MidiMapping::empty();
+        mapping.pitch_bend = Some(Message::PitchBend);
+        mapping.set_controller(1, Some(ControlTarget::Continuous(Message::SetVibratoDepth)));
+        mapping.set_controller(7, Some(ControlTarget::Continuous(Message::SetVolume)));
+        mapping.set_controller(64, Some(ControlTarget::Switch(Message::SetSustainPedal)));
+        mapping.set_controller(71, Some(ControlTarget::Continuous(Message::SetFilterQ)));
+        mapping.set_controller(72, Some(ControlTarget::Continuous(Message::SetEnvRelease)));
+        mapping.set_controller(73, Some(ControlTarget::Continuous(Message::SetEnvAttack)));
+        mapping.set_controller(74, Some(ControlTarget::Continuous(Message::SetFilterFreq)));
+        mapping.set_controller(
+            91,
+            Some(ControlTarget::Continuous(Message::SetReverbWetdry)),
+        );
+        mapping.set_controller(94, Some(ControlTarget::Continuous(Message::SetDelayWetdry)));
+        mapping.program_change = Some(program_to_oscillator);
+        mapping
+    }
+    // A mapping that only passes notes through.
+    pub fn empty() -> MidiMapping {
+        MidiMapping {
+            channel: None,
+            controllers: [None; 128],
+            channel_aftertouch: None,
+            pitch_bend: None,
+            program_change: None,
+        }
+    }
+    pub fn set_controller(&mut self, controller: u8, target: Option<ControlTarget>) {
+        self.controllers[(controller & 0x7f) as usize] = target;
+    }
+    pub fn controller(&self, controller: u8) -> Option<ControlTarget> {
+        self.controllers[(controller & 0x7f) as usize]
+    }
+    pub fn map(&self, event: &MidiEvent) -> Option<Message> {
+        if let (Some(channel), Some(event_channel)) = (self.channel, event.channel()) {
+            if channel != event_channel {
+                return None;
+            }
+        }
+        match *event {
+            MidiEvent::NoteOn { note, velocity, .. } => Some(Message::NoteOn(note, velocity)),
+            MidiEvent::NoteOff { note, velocity, .. } => Some(Message::NoteOff(note, velocity)),
+            MidiEvent::ControlChange {
+                controller, value, ..
+            } => self
+                .controller(controller)
+                .map(|target| target.message(value)),
+            MidiEvent::ChannelAftertouch { pressure, .. } => self
+                .channel_aftertouch
+                .map(|target| target.message(pressure)),
+            MidiEvent::PitchBend { value, .. } => self
+                .pitch_bend
+                .map(|message| message((value as f32 / 8192.0).max(-1.0))),
+            MidiEvent::ProgramChange { program, .. } => {
+                self.program_change.and_then(|map| map(program))
+            }
+            _ => None,
+        }
+    }
+}
+
+fn program_to_oscillator(program: u8) -> Option<Message> {
+    let osc_type = match program {
+        0 => OscType::Sine,
+        1 => OscType::Triangle,
+        2 => OscType::Square,
+        3 => OscType::Sawtooth,
+        _ => return None,
+    };
+    Some(Message::SetOscillator(osc_type))
+}
+
+// A MidiParser and a MidiMapping together, turning raw MIDI bytes into synth
+// messages.
+pub struct MidiInput {
+    parser: MidiParser,
+    pub mapping: MidiMapping,
+}
+
+impl Default for MidiInput {
+    fn default() -> Self {
+        Self::new(MidiMapping::new())
+    }
+}
+
+impl MidiInput {
+    pub fn new(mapping: MidiMapping) -> MidiInput {
+        MidiInput {
+            parser: MidiParser::new(),
+            mapping,
+        }
+    }
+    pub fn reset(&mut self) {
+        self.parser.reset();
+    }
+    // Parse bytes and pass the resulting messages to send, which is usually
+    // a synth's send method:
+    //
+    //     input.process(bytes, |message| synth.send(message));
+    pub fn process<F: FnMut(Message)>(&mut self, bytes: &[u8], mut send: F) {
+        let mapping = &self.mapping;
+        self.parser.parse_bytes(bytes, |event| {
+            if let Some(message) = mapping.map(&event) {
+                send(message);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_all(bytes: &[u8]) -> Vec<MidiEvent> {
+        let mut parser = MidiParser::new();
+        let mut events = Vec::new();
+        parser.parse_bytes(bytes, |event| events.push(event));
+        events
+    }
+
+    fn note_on(note: u8, velocity: u8) -> MidiEvent {
+        MidiEvent::NoteOn {
+            channel: 0,
+            note,
+            velocity,
+        }
+    }
+
+    #[test]
+    fn running_status() {
+        let events = parse_all(&[0x90, 60, 100, 64, 90, 0xc1, 5, 6]);
+        assert_eq!(
+            events,
+            vec![
+                note_on(60, 100),
+                note_on(64, 90),
+                MidiEvent::ProgramChange {
+                    channel: 1,
+                    program: 5
+                },
+                MidiEvent::ProgramChange {
+                    channel: 1,
+                    program: 6
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn realtime_inside_messages() {
+        let events = parse_all(&[0x90, 0xf8, 60, 0xfe, 100, 0xfa, 64, 0xfc, 90]);
+        assert_eq!(
+            events,
+            vec![
+                MidiEvent::Clock,
+                MidiEvent::ActiveSensing,
+                note_on(60, 100),
+                MidiEvent::Start,
+                MidiEvent::Stop,
+                note_on(64, 90),
+            ]
+        );
+    }
+
+    #[test]
+    fn sysex_overflow_is_dropped() {
+        let mut bytes = vec![0xf0];
+        bytes.extend_from_slice(&[0x7e; MAX_SYSEX_LENGTH]);
+        bytes.push(0xf7);
+        assert_eq!(
+            parse_all(&bytes),
+            vec![MidiEvent::SysEx(vec![0x7e; MAX_SYSEX_LENGTH])]
+        );
+
+        let mut bytes = vec![0xf0];
+        bytes.extend_from_slice(&[0x7e; MAX_SYSEX_LENGTH + 1]);
+        bytes.extend_from_slice(&[0xf7, 0x90, 60, 100]);
+        assert_eq!(parse_all(&bytes), vec![note_on(60, 100)]);
+
+        // The overflow doesn't carry over to the next message.
+        bytes.extend_from_slice(&[0xf0, 1, 2, 0xf7]);
+        assert_eq!(
+            parse_all(&bytes),
+            vec![note_on(60, 100), MidiEvent::SysEx(vec![1, 2])]
+        );
+    }
+
+    #[test]
+    fn zero_velocity_note_on_is_note_off() {
+        let events = parse_all(&[0x92, 60, 100, 60, 0]);
+        assert_eq!(
+            events[1],
+            MidiEvent::NoteOff {
+                channel: 2,
+                note: 60,
+                velocity: 0
+            }
+        );
+    }
+
+    #[test]
+    fn zero_filter_q_controller_is_finite() {
+        let mut input = MidiInput::new(MidiMapping::new());
+        let mut synth = crate::synth::BasicSynth::new();
+        input.process(&[0xb0, 71, 0, 0x90, 60, 100], |message| synth.send(message));
+        for _ in 0..1000 {
+            assert!(synth.tick(44100).is_finite());
+        }
+    }
+}
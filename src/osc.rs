@@ -1,3 +1,7 @@
+use crate::conversion::{note_to_hz, NoteLength};
+use crate::processor::Param;
+use crate::wavetable::{MipmapTable, MIPMAP_BASE_FREQ};
+
 // Reader for an oscillator wavetable. This data type does not manage any vector
 // it reads from. It only tracks the table position to read from, and takes a
 // reference to a vector to read from as an argument to the read function.
@@ -16,6 +20,14 @@ struct Memo {
     frequency: f32,
     sample_rate: u32,
     phase_inc: f32,
+    // Octaves above MIPMAP_BASE_FREQ, used to pick mipmap levels
+    octave: f32,
+}
+
+impl Default for OscReader {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl OscReader {
@@ -26,14 +38,16 @@ impl OscReader {
                 frequency: 0.0,
                 sample_rate: 44100,
                 phase_inc: 0.0,
+                octave: 0.0,
             },
         }
     }
     // TODO: Just use this one
-    pub fn read_linear(reader: &OscReader, table: &Vec<f32>) -> f32 {
+    pub fn read_linear(reader: &OscReader, table: &[f32]) -> f32 {
         // Expanded phase, from normal value to table length
         let ex_phase = reader.phase * table.len() as f32;
-        let index = ex_phase as usize;
+        // Phases just below 1.0 can round up to the table length.
+        let index = (ex_phase as usize).min(table.len() - 1);
         let fraction = ex_phase - index as f32;
         let mut next_index = index + 1;
         if next_index >= table.len() {
@@ -41,18 +55,86 @@ impl OscReader {
         }
         table[index] * (1.0 - fraction) + table[next_index] * fraction
     }
-    pub fn read<F>(&self, table: &Vec<f32>, interpolate: F) -> f32
+    pub fn read<F>(&self, table: &[f32], interpolate: F) -> f32
     where
-        F: Fn(&OscReader, &Vec<f32>) -> f32,
+        F: Fn(&OscReader, &[f32]) -> f32,
     {
-        interpolate(&self, table)
+        interpolate(self, table)
+    }
+    // Read from the mipmap levels for the frequency of the last increment,
+    // crossfading between neighbouring levels so that level changes during
+    // pitch sweeps are inaudible.
+    pub fn read_mipmap(&self, table: &MipmapTable) -> f32 {
+        let levels = table.levels();
+        let top = levels.len() - 1;
+        let level = (self.memo.octave as usize).min(top);
+        if level == top {
+            return linear_interpolate(self, &levels[level]);
+        }
+        let fraction = self.memo.octave - level as f32;
+        linear_interpolate(self, &levels[level]) * (1.0 - fraction)
+            + linear_interpolate(self, &levels[level + 1]) * fraction
     }
     pub fn increment(&mut self, freq: f32, sr: u32) {
+        self.update_memo(freq, sr);
+        self.advance();
+    }
+    // Increment by several samples at once, for skipping ahead without
+    // reading.
+    pub fn increment_by(&mut self, freq: f32, samples: usize, sr: u32) {
+        self.update_memo(freq, sr);
+        self.phase = (self.phase + self.memo.phase_inc * samples as f32) % 1.0;
+    }
+    // Increment at one cycle per note length at the given tempo, for
+    // tempo-synced LFOs.
+    pub fn increment_synced(&mut self, bpm: f32, length: NoteLength, sr: u32) {
+        self.increment(note_to_hz(length, bpm), sr);
+    }
+    // Fill output with table reads, incrementing before each read. Block
+    // frequencies only check the memo once per block.
+    pub fn process_block(&mut self, output: &mut [f32], table: &[f32], freq: Param, sr: u32) {
+        self.render_block(output, freq, sr, |reader| linear_interpolate(reader, table));
+    }
+    pub fn process_block_mipmap(
+        &mut self,
+        output: &mut [f32],
+        table: &MipmapTable,
+        freq: Param,
+        sr: u32,
+    ) {
+        self.render_block(output, freq, sr, |reader| reader.read_mipmap(table));
+    }
+    fn render_block<F>(&mut self, output: &mut [f32], freq: Param, sr: u32, read: F)
+    where
+        F: Fn(&OscReader) -> f32,
+    {
+        match freq {
+            Param::Block(freq) => {
+                self.update_memo(freq, sr);
+                for sample in output.iter_mut() {
+                    self.advance();
+                    *sample = read(self);
+                }
+            }
+            Param::Samples(_) => {
+                for (i, sample) in output.iter_mut().enumerate() {
+                    self.increment(freq.at(i), sr);
+                    *sample = read(self);
+                }
+            }
+        }
+    }
+    fn update_memo(&mut self, freq: f32, sr: u32) {
         if freq != self.memo.frequency || sr != self.memo.sample_rate {
             self.memo.frequency = freq;
             self.memo.sample_rate = sr;
-            self.memo.phase_inc = 1.0 / self.memo.sample_rate as f32 * self.memo.frequency
+            self.memo.phase_inc = 1.0 / self.memo.sample_rate as f32 * self.memo.frequency;
+            self.memo.octave = (self.memo.frequency.abs() / MIPMAP_BASE_FREQ)
+                .log2()
+                .max(0.0);
         }
+    }
+    fn advance(&mut self) {
         // While we could store the wavetable index and update it directly
         // instead of using a normalized phase value, that would require us to
         // make assumptions about the size of the wavetable, which we don't
@@ -64,10 +146,11 @@ impl OscReader {
     }
 }
 
-pub fn linear_interpolate(reader: &OscReader, table: &Vec<f32>) -> f32 {
+pub fn linear_interpolate(reader: &OscReader, table: &[f32]) -> f32 {
     // Expanded phase, from normal value to table length
     let ex_phase = reader.phase * table.len() as f32;
-    let index = ex_phase as usize;
+    // Phases just below 1.0 can round up to the table length.
+    let index = (ex_phase as usize).min(table.len() - 1);
     let fraction = ex_phase - index as f32;
     let mut next_index = index + 1;
     if next_index >= table.len() {
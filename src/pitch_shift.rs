@@ -0,0 +1,217 @@
+use crate::constants::TWO_PI;
+use crate::conversion::semitones_to_ratio;
+use crate::delay::CircularBuffer;
+use crate::interpolation::{Interpolator, Linear};
+use crate::processor::Processor;
+
+// A time-domain pitch shifter for monophonic material, made of several read
+// heads sweeping across a CircularBuffer. Reading at a delay that shrinks or
+// grows at a constant rate plays the input back faster or slower. Each head
+// sweeps through a window of delays, jumps back when it reaches the end, and
+// is faded in and out with a raised-cosine window so the jumps are
+// inaudible. Heads are spread evenly across the window, so their fades sum to
+// a constant gain.
+//
+// The delay is always shorter than the window, so latency is low, but
+// complex material can sound rough where heads overlap. Shorter windows suit
+// higher-pitched material.
+pub struct PitchShifter<I: Interpolator + Clone = Linear> {
+    buffer: CircularBuffer,
+    interpolators: Vec<I>,
+    // Position of the first head in the window, from 0.0 to 1.0
+    phase: f32,
+    memo: Memo,
+    // Parameters for processing through the Processor trait
+    semitones: f32,
+    cents: f32,
+    sample_rate: u32,
+}
+
+struct Memo {
+    semitones: f32,
+    cents: f32,
+    window_seconds: f32,
+    sample_rate: u32,
+    window_samples: f32,
+    // Change in phase per sample
+    phase_inc: f32,
+}
+
+impl PitchShifter {
+    pub fn new(buffer_size: usize, heads: usize) -> PitchShifter {
+        PitchShifter::with_interpolator(buffer_size, heads, Linear)
+    }
+}
+
+impl<I: Interpolator + Clone> PitchShifter<I> {
+    pub fn with_interpolator(buffer_size: usize, heads: usize, interpolator: I) -> PitchShifter<I> {
+        let mut shifter = PitchShifter {
+            buffer: CircularBuffer::new(buffer_size),
+            interpolators: vec![interpolator; heads.max(2)],
+            phase: 0.0,
+            memo: Memo {
+                semitones: 0.0,
+                cents: 0.0,
+                window_seconds: 0.05,
+                sample_rate: 44100,
+                window_samples: 0.0,
+                phase_inc: 0.0,
+            },
+            semitones: 0.0,
+            cents: 0.0,
+            sample_rate: 44100,
+        };
+        shifter.calculate();
+        shifter
+    }
+    pub fn heads(&self) -> usize {
+        self.interpolators.len()
+    }
+    // Length of the window each head sweeps through. It is limited by the
+    // buffer size.
+    pub fn set_window(&mut self, window_seconds: f32) {
+        self.memo.window_seconds = window_seconds;
+        self.calculate();
+    }
+    // Store parameters for processing through the Processor trait.
+    pub fn set_params(&mut self, semitones: f32, cents: f32) {
+        self.semitones = semitones;
+        self.cents = cents;
+    }
+    pub fn tick(&mut self, input_sample: f32, semitones: f32, cents: f32, sample_rate: u32) -> f32 {
+        self.update_memo(semitones, cents, sample_rate);
+        let heads = self.interpolators.len();
+        let mut output = 0.0;
+        for (i, interpolator) in self.interpolators.iter_mut().enumerate() {
+            let mut phase = self.phase + i as f32 / heads as f32;
+            if phase >= 1.0 {
+                phase -= 1.0;
+            }
+            let gain = 0.5 - 0.5 * (TWO_PI * phase).cos();
+            output +=
+                interpolator.read(&self.buffer, 1.0 + phase * self.memo.window_samples) * gain;
+        }
+        self.buffer.write(input_sample);
+        self.phase += self.memo.phase_inc;
+        self.phase -= self.phase.floor();
+        // Raised-cosine fades spread evenly sum to heads / 2.
+        output * 2.0 / heads as f32
+    }
+    fn update_memo(&mut self, semitones: f32, cents: f32, sample_rate: u32) {
+        if semitones != self.memo.semitones
+            || cents != self.memo.cents
+            || sample_rate != self.memo.sample_rate
+        {
+            self.memo.semitones = semitones;
+            self.memo.cents = cents;
+            self.memo.sample_rate = sample_rate;
+            self.calculate();
+        }
+    }
+    fn calculate(&mut self) {
+        let max_window = self.buffer.len().saturating_sub(2) as f32;
+        self.memo.window_samples = (self.memo.window_seconds * self.memo.sample_rate as f32)
+            .clamp(1.0, max_window.max(1.0));
+        let ratio = semitones_to_ratio(self.memo.semitones + self.memo.cents / 100.0);
+        // The delay shrinks by ratio - 1 samples per sample to raise the
+        // pitch, and grows to lower it.
+        self.memo.phase_inc = (1.0 - ratio) / self.memo.window_samples;
+    }
+}
+
+impl<I: Interpolator + Clone> Processor for PitchShifter<I> {
+    fn prepare(&mut self, sample_rate: u32, _max_block: usize) {
+        self.sample_rate = sample_rate;
+    }
+    fn reset(&mut self) {
+        self.buffer.clear();
+        for interpolator in self.interpolators.iter_mut() {
+            interpolator.reset();
+        }
+        self.phase = 0.0;
+    }
+    fn process(&mut self, input: f32) -> f32 {
+        self.tick(input, self.semitones, self.cents, self.sample_rate)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: u32 = 48000;
+    // A 50ms window is 2400 samples at 48kHz.
+    const WINDOW_SAMPLES: usize = 2400;
+
+    fn shifter(heads: usize) -> PitchShifter {
+        let mut shifter = PitchShifter::new(4096, heads);
+        shifter.prepare(SAMPLE_RATE, 64);
+        shifter
+    }
+
+    #[test]
+    fn unshifted_input_is_delayed() {
+        // With two heads and no shift, one head sits at the middle of the
+        // window with full gain, and the other is silent.
+        let mut shifter = shifter(2);
+        let output: Vec<f32> = (0..WINDOW_SAMPLES * 2)
+            .map(|i| shifter.process(if i == 0 { 1.0 } else { 0.0 }))
+            .collect();
+        let delay = 1 + WINDOW_SAMPLES / 2;
+        for (i, sample) in output.iter().enumerate() {
+            let expected = if i == delay { 1.0 } else { 0.0 };
+            assert!((sample - expected).abs() < 1e-4, "sample {}: {}", i, sample);
+        }
+    }
+
+    #[test]
+    fn octave_up_doubles_the_frequency() {
+        let mut shifter = shifter(4);
+        shifter.set_params(12.0, 0.0);
+        // Heads are a quarter window, 600 samples, apart. Pick a frequency
+        // with a whole number of cycles in that, so the heads don't cancel.
+        let input_freq = 240.0;
+        let output: Vec<f32> = (0..SAMPLE_RATE as usize)
+            .map(|i| {
+                let input = (TWO_PI * input_freq * i as f32 / SAMPLE_RATE as f32).sin();
+                shifter.process(input)
+            })
+            .collect();
+        // Find the strongest frequency from 100Hz to 800Hz, in 10Hz steps,
+        // after the buffer has filled.
+        let analysis = &output[output.len() - 4800..];
+        let magnitude = |freq: f32| {
+            let (mut re, mut im) = (0.0, 0.0);
+            for (i, sample) in analysis.iter().enumerate() {
+                let angle = TWO_PI * freq * i as f32 / SAMPLE_RATE as f32;
+                re += sample * angle.cos();
+                im += sample * angle.sin();
+            }
+            re * re + im * im
+        };
+        let peak = (10..=80)
+            .map(|step| step as f32 * 10.0)
+            .max_by(|a, b| magnitude(*a).partial_cmp(&magnitude(*b)).unwrap())
+            .unwrap();
+        assert!((peak - input_freq * 2.0).abs() <= 10.0, "peak at {}", peak);
+    }
+
+    #[test]
+    fn head_gains_sum_to_one() {
+        for &heads in &[2, 4] {
+            for &semitones in &[-5.0, 0.0, 7.0] {
+                let mut shifter = shifter(heads);
+                assert_eq!(shifter.heads(), heads);
+                shifter.set_params(semitones, 0.0);
+                for i in 0..WINDOW_SAMPLES * 4 {
+                    let output = shifter.process(1.0);
+                    // Once the window has filled, every head reads the same
+                    // constant input.
+                    if i > WINDOW_SAMPLES + 2 {
+                        assert!((output - 1.0).abs() < 1e-4, "{} heads: {}", heads, output);
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,98 @@
+// A common interface for tick-based modules, so that host code can chain, swap
+// and test effects generically.
+//
+// Unlike the inherent tick functions on each module, which take their
+// parameters on every call, a Processor reads from parameters that were stored
+// ahead of time (see the set_params functions on each module), and from the
+// sample rate provided to prepare. Modules that generate signal rather than
+// process it ignore their input.
+pub trait Processor {
+    // Called before processing starts, and whenever the sample rate or the
+    // maximum block size changes. Allocation belongs here, not in the process
+    // functions.
+    fn prepare(&mut self, sample_rate: u32, max_block: usize);
+    // Clear all internal state (delay lines, filter registers, voices) without
+    // changing parameters.
+    fn reset(&mut self);
+    fn process(&mut self, input: f32) -> f32;
+    // Mono modules sum a stereo input to mono and return the same output on
+    // both channels. Stereo modules should override this.
+    fn process_stereo(&mut self, left: f32, right: f32) -> (f32, f32) {
+        let output = self.process((left + right) * 0.5);
+        (output, output)
+    }
+    // Process a block of samples in place.
+    fn process_buffer(&mut self, buffer: &mut [f32]) {
+        for sample in buffer.iter_mut() {
+            *sample = self.process(*sample);
+        }
+    }
+    fn process_buffer_stereo(&mut self, left: &mut [f32], right: &mut [f32]) {
+        for (l, r) in left.iter_mut().zip(right.iter_mut()) {
+            let (out_l, out_r) = self.process_stereo(*l, *r);
+            *l = out_l;
+            *r = out_r;
+        }
+    }
+}
+
+// A parameter to a block processing function: either a single value for the
+// whole block, or one value per sample. Modules can skip their per-sample
+// parameter checks entirely for block values.
+#[derive(Clone, Copy)]
+pub enum Param<'a> {
+    Block(f32),
+    Samples(&'a [f32]),
+}
+
+impl<'a> Param<'a> {
+    // Value of the parameter at the given index into the block. Per-sample
+    // slices that are shorter than the block hold their last value.
+    pub fn at(&self, index: usize) -> f32 {
+        match self {
+            Param::Block(value) => *value,
+            Param::Samples(values) => match values.get(index) {
+                Some(value) => *value,
+                None => values.last().copied().unwrap_or(0.0),
+            },
+        }
+    }
+}
+
+// Two processors in series. Chains can be nested to build longer chains.
+pub struct Chain<A: Processor, B: Processor> {
+    pub first: A,
+    pub second: B,
+}
+
+impl<A: Processor, B: Processor> Chain<A, B> {
+    pub fn new(first: A, second: B) -> Chain<A, B> {
+        Chain { first, second }
+    }
+}
+
+impl<A: Processor, B: Processor> Processor for Chain<A, B> {
+    fn prepare(&mut self, sample_rate: u32, max_block: usize) {
+        self.first.prepare(sample_rate, max_block);
+        self.second.prepare(sample_rate, max_block);
+    }
+    fn reset(&mut self) {
+        self.first.reset();
+        self.second.reset();
+    }
+    fn process(&mut self, input: f32) -> f32 {
+        self.second.process(self.first.process(input))
+    }
+    fn process_stereo(&mut self, left: f32, right: f32) -> (f32, f32) {
+        let (left, right) = self.first.process_stereo(left, right);
+        self.second.process_stereo(left, right)
+    }
+    fn process_buffer(&mut self, buffer: &mut [f32]) {
+        self.first.process_buffer(buffer);
+        self.second.process_buffer(buffer);
+    }
+    fn process_buffer_stereo(&mut self, left: &mut [f32], right: &mut [f32]) {
+        self.first.process_buffer_stereo(left, right);
+        self.second.process_buffer_stereo(left, right);
+    }
+}
@@ -0,0 +1,543 @@
+use crate::delay::CircularBuffer;
+use crate::processor::Processor;
+
+// Algorithmic reverbs built from CircularBuffer delay lines: a Freeverb-style
+// Schroeder reverb, and a feedback delay network (FDN).
+//
+// Delay line lengths depend on the sample rate, so changing it reallocates the
+// delay lines. Callers should use Processor::prepare to do this ahead of time.
+
+// Longest pre-delay the pre-delay buffer can hold
+pub const MAX_PRE_DELAY_SECONDS: f32 = 0.5;
+
+// Sample rate that the delay line lengths below are tuned for
+const TUNING_SAMPLE_RATE: f32 = 44100.0;
+
+#[derive(Clone, Copy)]
+pub struct ReverbParams {
+    // 0.0 to 1.0. Larger rooms have longer tails.
+    pub room_size: f32,
+    // 0.0 to 1.0. How quickly high frequencies decay relative to the rest of
+    // the tail.
+    pub damping: f32,
+    pub pre_delay_seconds: f32,
+    // 0.0 (mono) to 1.0 (full stereo width)
+    pub width: f32,
+    pub wetdry: f32,
+}
+
+impl Default for ReverbParams {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ReverbParams {
+    pub fn new() -> ReverbParams {
+        ReverbParams {
+            room_size: 0.5,
+            damping: 0.5,
+            pre_delay_seconds: 0.0,
+            width: 1.0,
+            wetdry: 0.3,
+        }
+    }
+}
+
+// Building blocks
+
+// A feedback comb filter with a one-pole lowpass in the feedback path, as
+// used in Freeverb. The delay is the full length of the buffer.
+pub struct Comb {
+    buffer: CircularBuffer,
+    filter_state: f32,
+}
+
+impl Comb {
+    pub fn new(delay_samples: usize) -> Comb {
+        Comb {
+            buffer: CircularBuffer::new(delay_samples.max(1)),
+            filter_state: 0.0,
+        }
+    }
+    // damping is the coefficient of the lowpass filter, from 0.0 (no
+    // filtering) to just below 1.0.
+    pub fn tick(&mut self, input: f32, feedback: f32, damping: f32) -> f32 {
+        let output = self.buffer.read(self.buffer.len());
+        self.filter_state = output * (1.0 - damping) + self.filter_state * damping;
+        self.buffer.write(input + self.filter_state * feedback);
+        output
+    }
+    pub fn reset(&mut self) {
+        self.buffer.clear();
+        self.filter_state = 0.0;
+    }
+}
+
+// A Schroeder allpass diffuser. The delay is the full length of the buffer.
+pub struct Allpass {
+    buffer: CircularBuffer,
+}
+
+impl Allpass {
+    pub fn new(delay_samples: usize) -> Allpass {
+        Allpass {
+            buffer: CircularBuffer::new(delay_samples.max(1)),
+        }
+    }
+    pub fn tick(&mut self, input: f32, gain: f32) -> f32 {
+        let delayed = self.buffer.read(self.buffer.len());
+        let stored = input + delayed * gain;
+        self.buffer.write(stored);
+        delayed - stored * gain
+    }
+    pub fn reset(&mut self) {
+        self.buffer.clear();
+    }
+}
+
+// Delays the mono reverb input by the pre-delay time.
+struct PreDelay {
+    buffer: CircularBuffer,
+}
+
+impl PreDelay {
+    fn new(sample_rate: u32) -> PreDelay {
+        let size = (MAX_PRE_DELAY_SECONDS * sample_rate as f32) as usize + 1;
+        PreDelay {
+            buffer: CircularBuffer::new(size),
+        }
+    }
+    fn tick(&mut self, input: f32, pre_delay_seconds: f32, sample_rate: u32) -> f32 {
+        self.buffer.write(input);
+        let delay_samples = (pre_delay_seconds.max(0.0) * sample_rate as f32) as usize;
+        self.buffer.read(delay_samples + 1)
+    }
+}
+
+fn scale_length(length: usize, sample_rate: u32) -> usize {
+    (length as f32 * sample_rate as f32 / TUNING_SAMPLE_RATE) as usize
+}
+
+// Mix the left and right reverb outputs for a stereo width.
+fn apply_width(left: f32, right: f32, width: f32) -> (f32, f32) {
+    let width = width.clamp(0.0, 1.0);
+    let direct = 0.5 + width * 0.5;
+    let cross = 0.5 - width * 0.5;
+    (left * direct + right * cross, right * direct + left * cross)
+}
+
+// Freeverb
+
+// Delay lengths from Jezar's Freeverb, in samples at 44.1kHz. The right
+// channel's lines are longer by STEREO_SPREAD to decorrelate the channels.
+const COMB_LENGTHS: [usize; 8] = [1116, 1188, 1277, 1356, 1422, 1491, 1557, 1617];
+const ALLPASS_LENGTHS: [usize; 4] = [556, 441, 341, 225];
+const STEREO_SPREAD: usize = 23;
+const ALLPASS_GAIN: f32 = 0.5;
+// Attenuates the input to the parallel combs, which sum to a large gain.
+const FREEVERB_INPUT_GAIN: f32 = 0.015;
+const FREEVERB_WET_GAIN: f32 = 3.0;
+
+// A Schroeder reverb in the Freeverb topology: eight parallel lowpass-feedback
+// combs into four series allpasses, per channel.
+pub struct Freeverb {
+    combs: [Vec<Comb>; 2],
+    allpasses: [Vec<Allpass>; 2],
+    pre_delay: PreDelay,
+    // Parameters for processing through the Processor trait
+    params: ReverbParams,
+    sample_rate: u32,
+}
+
+impl Default for Freeverb {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Freeverb {
+    pub fn new() -> Freeverb {
+        let mut reverb = Freeverb {
+            combs: [Vec::new(), Vec::new()],
+            allpasses: [Vec::new(), Vec::new()],
+            pre_delay: PreDelay::new(44100),
+            params: ReverbParams::new(),
+            sample_rate: 44100,
+        };
+        reverb.build(44100);
+        reverb
+    }
+    // Store parameters for processing through the Processor trait.
+    pub fn set_params(&mut self, params: ReverbParams) {
+        self.params = params;
+    }
+    // The input is summed to mono before the reverb. The delay lines are
+    // sized in prepare, so sample_rate should match the prepared rate.
+    pub fn tick(
+        &mut self,
+        left: f32,
+        right: f32,
+        params: &ReverbParams,
+        sample_rate: u32,
+    ) -> (f32, f32) {
+        let input =
+            self.pre_delay
+                .tick((left + right) * 0.5, params.pre_delay_seconds, sample_rate)
+                * FREEVERB_INPUT_GAIN;
+        let feedback = 0.7 + params.room_size.clamp(0.0, 1.0) * 0.28;
+        let damping = params.damping.clamp(0.0, 1.0) * 0.4;
+
+        let mut wet = [0.0; 2];
+        for (channel, output) in wet.iter_mut().enumerate() {
+            for comb in self.combs[channel].iter_mut() {
+                *output += comb.tick(input, feedback, damping);
+            }
+            for allpass in self.allpasses[channel].iter_mut() {
+                *output = allpass.tick(*output, ALLPASS_GAIN);
+            }
+        }
+        let (wet_l, wet_r) = apply_width(
+            wet[0] * FREEVERB_WET_GAIN,
+            wet[1] * FREEVERB_WET_GAIN,
+            params.width,
+        );
+        (
+            left * (1.0 - params.wetdry) + wet_l * params.wetdry,
+            right * (1.0 - params.wetdry) + wet_r * params.wetdry,
+        )
+    }
+    fn build(&mut self, sample_rate: u32) {
+        self.sample_rate = sample_rate;
+        for (channel, spread) in [0, STEREO_SPREAD].iter().enumerate() {
+            self.combs[channel] = COMB_LENGTHS
+                .iter()
+                .map(|length| Comb::new(scale_length(length + spread, sample_rate)))
+                .collect();
+            self.allpasses[channel] = ALLPASS_LENGTHS
+                .iter()
+                .map(|length| Allpass::new(scale_length(length + spread, sample_rate)))
+                .collect();
+        }
+        self.pre_delay = PreDelay::new(sample_rate);
+    }
+}
+
+impl Processor for Freeverb {
+    fn prepare(&mut self, sample_rate: u32, _max_block: usize) {
+        if sample_rate != self.sample_rate {
+            self.build(sample_rate);
+        }
+    }
+    fn reset(&mut self) {
+        for channel in 0..2 {
+            for comb in self.combs[channel].iter_mut() {
+                comb.reset();
+            }
+            for allpass in self.allpasses[channel].iter_mut() {
+                allpass.reset();
+            }
+        }
+        self.pre_delay.buffer.clear();
+    }
+    fn process(&mut self, input: f32) -> f32 {
+        let (left, right) = self.process_stereo(input, input);
+        (left + right) * 0.5
+    }
+    fn process_stereo(&mut self, left: f32, right: f32) -> (f32, f32) {
+        let params = self.params;
+        self.tick(left, right, &params, self.sample_rate)
+    }
+}
+
+// Feedback delay network
+
+const FDN_SIZE: usize = 8;
+// Mutually prime delay lengths in samples at 44.1kHz, so that echoes from
+// different lines rarely coincide.
+const FDN_LENGTHS: [usize; FDN_SIZE] = [1433, 1601, 1867, 2053, 2251, 2399, 2617, 2797];
+// Range of decay times (RT60) that room_size maps onto
+const FDN_MIN_DECAY_SECONDS: f32 = 0.3;
+const FDN_MAX_DECAY_SECONDS: f32 = 10.0;
+
+// The orthogonal matrix that mixes the delay line outputs before they are fed
+// back. Both are lossless, so the decay time is set by the line gains alone.
+#[derive(Clone, Copy, PartialEq)]
+pub enum MixingMatrix {
+    // Every output feeds every input with equal magnitude, for the fastest
+    // build-up of echo density.
+    Hadamard,
+    // A reflection that feeds each line mostly back into itself, for a
+    // slower, smoother build-up.
+    Householder,
+}
+
+// An eight-line feedback delay network. Each line has a one-pole lowpass for
+// damping and a gain set for the decay time, and the line outputs are mixed
+// by a lossless matrix before being fed back.
+pub struct FdnReverb {
+    lines: Vec<CircularBuffer>,
+    filter_state: [f32; FDN_SIZE],
+    matrix: MixingMatrix,
+    memo: FdnMemo,
+    pre_delay: PreDelay,
+    // Parameters for processing through the Processor trait
+    params: ReverbParams,
+    sample_rate: u32,
+}
+
+struct FdnMemo {
+    room_size: f32,
+    sample_rate: u32,
+    // Per-line gains for the decay time
+    gains: [f32; FDN_SIZE],
+}
+
+impl Default for FdnReverb {
+    fn default() -> Self {
+        Self::new(MixingMatrix::Hadamard)
+    }
+}
+
+impl FdnReverb {
+    pub fn new(matrix: MixingMatrix) -> FdnReverb {
+        let mut reverb = FdnReverb {
+            lines: Vec::new(),
+            filter_state: [0.0; FDN_SIZE],
+            matrix,
+            memo: FdnMemo {
+                // Not a valid room size, so the first tick sets the gains.
+                room_size: -1.0,
+                sample_rate: 44100,
+                gains: [0.0; FDN_SIZE],
+            },
+            pre_delay: PreDelay::new(44100),
+            params: ReverbParams::new(),
+            sample_rate: 44100,
+        };
+        reverb.build(44100);
+        reverb
+    }
+    pub fn set_matrix(&mut self, matrix: MixingMatrix) {
+        self.matrix = matrix;
+    }
+    // Store parameters for processing through the Processor trait.
+    pub fn set_params(&mut self, params: ReverbParams) {
+        self.params = params;
+    }
+    // The input is summed to mono before the reverb. The delay lines are
+    // sized in prepare, so sample_rate should match the prepared rate.
+    pub fn tick(
+        &mut self,
+        left: f32,
+        right: f32,
+        params: &ReverbParams,
+        sample_rate: u32,
+    ) -> (f32, f32) {
+        let input =
+            self.pre_delay
+                .tick((left + right) * 0.5, params.pre_delay_seconds, sample_rate);
+        self.update_memo(params.room_size, sample_rate);
+        let damping = params.damping.clamp(0.0, 1.0) * 0.7;
+
+        let mut outputs = [0.0; FDN_SIZE];
+        for (i, output) in outputs.iter_mut().enumerate() {
+            let line = &self.lines[i];
+            let delayed = line.read(line.len());
+            self.filter_state[i] = delayed * (1.0 - damping) + self.filter_state[i] * damping;
+            *output = self.filter_state[i] * self.memo.gains[i];
+        }
+
+        // Alternate the channel taps, with sign flips to decorrelate the
+        // channels.
+        let mut wet_l = 0.0;
+        let mut wet_r = 0.0;
+        for (i, output) in outputs.iter().enumerate() {
+            let sign = if i % 4 < 2 { 1.0 } else { -1.0 };
+            if i % 2 == 0 {
+                wet_l += output * sign;
+            } else {
+                wet_r += output * sign;
+            }
+        }
+
+        mix(&mut outputs, self.matrix);
+        for (i, line) in self.lines.iter_mut().enumerate() {
+            let sign = if i % 2 == 0 { 1.0 } else { -1.0 };
+            line.write(outputs[i] + input * sign);
+        }
+
+        let norm = 1.0 / FDN_SIZE as f32;
+        let (wet_l, wet_r) = apply_width(wet_l * norm, wet_r * norm, params.width);
+        (
+            left * (1.0 - params.wetdry) + wet_l * params.wetdry,
+            right * (1.0 - params.wetdry) + wet_r * params.wetdry,
+        )
+    }
+    fn build(&mut self, sample_rate: u32) {
+        self.sample_rate = sample_rate;
+        self.lines = FDN_LENGTHS
+            .iter()
+            .map(|length| CircularBuffer::new(scale_length(*length, sample_rate).max(1)))
+            .collect();
+        self.filter_state = [0.0; FDN_SIZE];
+        self.pre_delay = PreDelay::new(sample_rate);
+    }
+    fn update_memo(&mut self, room_size: f32, sample_rate: u32) {
+        if room_size != self.memo.room_size || sample_rate != self.memo.sample_rate {
+            self.memo.room_size = room_size;
+            self.memo.sample_rate = sample_rate;
+            let decay_seconds = FDN_MIN_DECAY_SECONDS
+                * (FDN_MAX_DECAY_SECONDS / FDN_MIN_DECAY_SECONDS).powf(room_size.clamp(0.0, 1.0));
+            for (gain, line) in self.memo.gains.iter_mut().zip(self.lines.iter()) {
+                // Gain for a 60dB decay over decay_seconds
+                *gain =
+                    10.0_f32.powf(-3.0 * line.len() as f32 / (decay_seconds * sample_rate as f32));
+            }
+        }
+    }
+}
+
+// Multiply in place by the normalized mixing matrix.
+fn mix(values: &mut [f32; FDN_SIZE], matrix: MixingMatrix) {
+    match matrix {
+        MixingMatrix::Hadamard => {
+            // Fast Walsh-Hadamard transform
+            let mut step = 1;
+            while step < FDN_SIZE {
+                for start in (0..FDN_SIZE).step_by(step * 2) {
+                    for i in start..start + step {
+                        let a = values[i];
+                        let b = values[i + step];
+                        values[i] = a + b;
+                        values[i + step] = a - b;
+                    }
+                }
+                step *= 2;
+            }
+            let norm = 1.0 / (FDN_SIZE as f32).sqrt();
+            for value in values.iter_mut() {
+                *value *= norm;
+            }
+        }
+        MixingMatrix::Householder => {
+            let sum: f32 = values.iter().sum();
+            let reflection = sum * 2.0 / FDN_SIZE as f32;
+            for value in values.iter_mut() {
+                *value -= reflection;
+            }
+        }
+    }
+}
+
+impl Processor for FdnReverb {
+    fn prepare(&mut self, sample_rate: u32, _max_block: usize) {
+        if sample_rate != self.sample_rate {
+            self.build(sample_rate);
+        }
+    }
+    fn reset(&mut self) {
+        for line in self.lines.iter_mut() {
+            line.clear();
+        }
+        self.filter_state = [0.0; FDN_SIZE];
+        self.pre_delay.buffer.clear();
+    }
+    fn process(&mut self, input: f32) -> f32 {
+        let (left, right) = self.process_stereo(input, input);
+        (left + right) * 0.5
+    }
+    fn process_stereo(&mut self, left: f32, right: f32) -> (f32, f32) {
+        let params = self.params;
+        self.tick(left, right, &params, self.sample_rate)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: u32 = 44100;
+
+    fn wet_params() -> ReverbParams {
+        ReverbParams {
+            room_size: 0.5,
+            wetdry: 1.0,
+            ..ReverbParams::new()
+        }
+    }
+
+    fn reverbs() -> Vec<Box<dyn Processor>> {
+        let mut freeverb = Freeverb::new();
+        freeverb.set_params(wet_params());
+        let mut hadamard = FdnReverb::new(MixingMatrix::Hadamard);
+        hadamard.set_params(wet_params());
+        let mut householder = FdnReverb::new(MixingMatrix::Householder);
+        householder.set_params(wet_params());
+        let mut reverbs: Vec<Box<dyn Processor>> = vec![
+            Box::new(freeverb),
+            Box::new(hadamard),
+            Box::new(householder),
+        ];
+        for reverb in reverbs.iter_mut() {
+            reverb.prepare(SAMPLE_RATE, 64);
+        }
+        reverbs
+    }
+
+    fn impulse_response(reverb: &mut dyn Processor, length: usize) -> Vec<f32> {
+        (0..length)
+            .map(|i| reverb.process(if i == 0 { 1.0 } else { 0.0 }))
+            .collect()
+    }
+
+    fn energy(samples: &[f32]) -> f32 {
+        samples.iter().map(|sample| sample * sample).sum()
+    }
+
+    #[test]
+    fn silence_in_silence_out() {
+        for reverb in reverbs().iter_mut() {
+            for _ in 0..SAMPLE_RATE {
+                assert_eq!(reverb.process(0.0), 0.0);
+            }
+        }
+    }
+
+    #[test]
+    fn impulse_response_decays() {
+        let window = SAMPLE_RATE as usize / 2;
+        for reverb in reverbs().iter_mut() {
+            let response = impulse_response(reverb.as_mut(), window * 8);
+            assert!(response.iter().all(|sample| sample.is_finite()));
+            let energies: Vec<f32> = response.chunks(window).map(energy).collect();
+            assert!(energies[0] > 0.0);
+            for pair in energies[1..].windows(2) {
+                assert!(pair[1] < pair[0], "{:?}", energies);
+            }
+            assert!(energies[7] < energies[0] * 1e-3, "{:?}", energies);
+        }
+    }
+
+    #[test]
+    fn reset_clears_the_tail() {
+        for reverb in reverbs().iter_mut() {
+            impulse_response(reverb.as_mut(), 1000);
+            reverb.reset();
+            for _ in 0..SAMPLE_RATE {
+                assert_eq!(reverb.process(0.0), 0.0);
+            }
+        }
+    }
+
+    #[test]
+    fn mixing_matrices_preserve_energy() {
+        let input = [0.3, -1.2, 0.7, 0.05, -0.4, 2.0, -0.9, 0.25];
+        for &matrix in &[MixingMatrix::Hadamard, MixingMatrix::Householder] {
+            let mut values = input;
+            mix(&mut values, matrix);
+            assert_ne!(values, input);
+            assert!((energy(&values) - energy(&input)).abs() < 1e-5);
+        }
+    }
+}
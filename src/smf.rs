@@ -0,0 +1,418 @@
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+// Standard MIDI File parsing, for formats 0 and 1. Events from all tracks can
+// be merged into one list timed in seconds, following the tempo map.
+//
+// See: The Complete MIDI 1.0 Detailed Specification, "Standard MIDI Files".
+
+#[derive(Debug)]
+pub enum SmfError {
+    Io(io::Error),
+    // The data is not a well-formed MIDI file.
+    Invalid(&'static str),
+    // The file is valid, but uses a feature this module doesn't handle.
+    Unsupported(String),
+}
+
+impl fmt::Display for SmfError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SmfError::Io(error) => write!(f, "MIDI file I/O error: {}", error),
+            SmfError::Invalid(reason) => write!(f, "invalid MIDI file: {}", reason),
+            SmfError::Unsupported(reason) => write!(f, "unsupported MIDI file: {}", reason),
+        }
+    }
+}
+
+impl Error for SmfError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            SmfError::Io(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for SmfError {
+    fn from(error: io::Error) -> Self {
+        SmfError::Io(error)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Division {
+    TicksPerQuarter(u16),
+    // Frames per second (24, 25, 29 for 29.97 drop-frame, or 30), and ticks
+    // per frame
+    Smpte(u8, u8),
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum Event {
+    // A channel message: status byte, then one or two data bytes. Messages
+    // with one data byte have data2 set to 0.
+    Midi { status: u8, data1: u8, data2: u8 },
+    // Microseconds per quarter note
+    Tempo(u32),
+    // SysEx data, without the leading 0xF0 or 0xF7
+    SysEx(Vec<u8>),
+    // Any other meta event
+    Meta { kind: u8, data: Vec<u8> },
+    EndOfTrack,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct TrackEvent {
+    // Ticks since the previous event in the track
+    pub delta: u32,
+    pub event: Event,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct TimedEvent {
+    pub seconds: f64,
+    pub track: usize,
+    pub event: Event,
+}
+
+pub struct Smf {
+    pub format: u16,
+    pub division: Division,
+    pub tracks: Vec<Vec<TrackEvent>>,
+}
+
+// Tempo until the first tempo event: 120 bpm
+const DEFAULT_TEMPO: u32 = 500_000;
+
+impl Smf {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Smf, SmfError> {
+        Smf::from_bytes(&fs::read(path)?)
+    }
+    pub fn from_bytes(bytes: &[u8]) -> Result<Smf, SmfError> {
+        let mut reader = Reader { bytes, position: 0 };
+        if reader.take(4)? != b"MThd" {
+            return Err(SmfError::Invalid("missing MThd header"));
+        }
+        let header_length = reader.read_u32()? as usize;
+        if header_length < 6 {
+            return Err(SmfError::Invalid("header is too short"));
+        }
+        let format = reader.read_u16()?;
+        let track_count = reader.read_u16()?;
+        let division = reader.read_u16()?;
+        reader.take(header_length - 6)?;
+        if format > 1 {
+            return Err(SmfError::Unsupported(format!("format {}", format)));
+        }
+        let division = if division & 0x8000 == 0 {
+            Division::TicksPerQuarter(division.max(1))
+        } else {
+            // The high byte is the negative frame rate, as two's complement.
+            let fps = ((division >> 8) as u8 as i8).wrapping_neg() as u8;
+            Division::Smpte(fps.max(1), (division & 0xff).max(1) as u8)
+        };
+
+        let mut tracks = Vec::with_capacity(track_count as usize);
+        while tracks.len() < track_count as usize && reader.remaining() >= 8 {
+            let id = reader.take(4)?;
+            let length = reader.read_u32()? as usize;
+            let chunk = reader.take(length.min(reader.remaining()))?;
+            // Skip unknown chunks, as the specification requires.
+            if id == b"MTrk" {
+                tracks.push(parse_track(chunk)?);
+            }
+        }
+        Ok(Smf {
+            format,
+            division,
+            tracks,
+        })
+    }
+    // Merge all tracks into one list of events, timed in seconds. Tempo
+    // events in any track apply to all tracks. Events at the same time keep
+    // their track order.
+    pub fn timed_events(&self) -> Vec<TimedEvent> {
+        let mut events: Vec<(u64, usize, &Event)> = Vec::new();
+        for (track, track_events) in self.tracks.iter().enumerate() {
+            let mut ticks = 0u64;
+            for event in track_events.iter() {
+                ticks += event.delta as u64;
+                events.push((ticks, track, &event.event));
+            }
+        }
+        // Stable, so events at the same tick stay in file order.
+        events.sort_by_key(|(ticks, track, _)| (*ticks, *track));
+
+        let mut timed = Vec::with_capacity(events.len());
+        let mut tempo = DEFAULT_TEMPO;
+        let mut last_ticks = 0;
+        let mut seconds = 0.0;
+        for (ticks, track, event) in events {
+            seconds += self.ticks_to_seconds(ticks - last_ticks, tempo);
+            last_ticks = ticks;
+            if let Event::Tempo(value) = event {
+                tempo = *value;
+            }
+            timed.push(TimedEvent {
+                seconds,
+                track,
+                event: event.clone(),
+            });
+        }
+        timed
+    }
+    fn ticks_to_seconds(&self, ticks: u64, tempo: u32) -> f64 {
+        match self.division {
+            Division::TicksPerQuarter(division) => {
+                ticks as f64 * tempo as f64 / 1_000_000.0 / division as f64
+            }
+            Division::Smpte(fps, ticks_per_frame) => {
+                // 29 means 29.97 frames per second.
+                let fps = if fps == 29 { 29.97 } else { fps as f64 };
+                ticks as f64 / (fps * ticks_per_frame as f64)
+            }
+        }
+    }
+}
+
+fn parse_track(chunk: &[u8]) -> Result<Vec<TrackEvent>, SmfError> {
+    let mut reader = Reader {
+        bytes: chunk,
+        position: 0,
+    };
+    let mut events = Vec::new();
+    let mut running_status: Option<u8> = None;
+    while reader.remaining() > 0 {
+        let delta = reader.read_vlq()?;
+        let mut status = reader.read_u8()?;
+        let event = match status {
+            0xff => {
+                let kind = reader.read_u8()?;
+                let length = reader.read_vlq()? as usize;
+                let data = reader.take(length)?;
+                match kind {
+                    0x2f => Event::EndOfTrack,
+                    0x51 if data.len() == 3 => Event::Tempo(
+                        (data[0] as u32) << 16 | (data[1] as u32) << 8 | data[2] as u32,
+                    ),
+                    _ => Event::Meta {
+                        kind,
+                        data: data.to_vec(),
+                    },
+                }
+            }
+            0xf0 | 0xf7 => {
+                // SysEx cancels running status.
+                running_status = None;
+                let length = reader.read_vlq()? as usize;
+                let mut data = reader.take(length)?;
+                if data.last() == Some(&0xf7) {
+                    data = &data[..data.len() - 1];
+                }
+                Event::SysEx(data.to_vec())
+            }
+            0xf1..=0xfe => return Err(SmfError::Invalid("system message in track")),
+            _ => {
+                let data1 = if status < 0x80 {
+                    // Running status: this byte is the first data byte.
+                    let data1 = status;
+                    status = running_status.ok_or(SmfError::Invalid("data byte without status"))?;
+                    data1
+                } else {
+                    running_status = Some(status);
+                    reader.read_data_byte()?
+                };
+                let data2 = match status & 0xf0 {
+                    // Program change and channel pressure have one data byte.
+                    0xc0 | 0xd0 => 0,
+                    _ => reader.read_data_byte()?,
+                };
+                Event::Midi {
+                    status,
+                    data1,
+                    data2,
+                }
+            }
+        };
+        let is_end = event == Event::EndOfTrack;
+        events.push(TrackEvent { delta, event });
+        if is_end {
+            break;
+        }
+    }
+    Ok(events)
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn remaining(&self) -> usize {
+        self.bytes.len() - self.position
+    }
+    fn take(&mut self, count: usize) -> Result<&'a [u8], SmfError> {
+        if count > self.remaining() {
+            return Err(SmfError::Invalid("unexpected end of data"));
+        }
+        let slice = &self.bytes[self.position..self.position + count];
+        self.position += count;
+        Ok(slice)
+    }
+    fn read_u8(&mut self) -> Result<u8, SmfError> {
+        Ok(self.take(1)?[0])
+    }
+    // A MIDI data byte, which must have the high bit clear.
+    fn read_data_byte(&mut self) -> Result<u8, SmfError> {
+        let byte = self.read_u8()?;
+        if byte >= 0x80 {
+            return Err(SmfError::Invalid("unexpected status byte in event data"));
+        }
+        Ok(byte)
+    }
+    fn read_u16(&mut self) -> Result<u16, SmfError> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+    fn read_u32(&mut self) -> Result<u32, SmfError> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+    // Variable-length quantity: seven bits per byte, most significant first,
+    // with the high bit set on all but the last byte. At most four bytes.
+    fn read_vlq(&mut self) -> Result<u32, SmfError> {
+        let mut value = 0u32;
+        for _ in 0..4 {
+            let byte = self.read_u8()?;
+            value = (value << 7) | (byte & 0x7f) as u32;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(SmfError::Invalid("variable-length quantity is too long"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A format 0 file with one track, at 96 ticks per quarter note.
+    fn single_track(track: &[u8]) -> Vec<u8> {
+        let mut bytes = b"MThd".to_vec();
+        bytes.extend_from_slice(&6u32.to_be_bytes());
+        bytes.extend_from_slice(&[0, 0, 0, 1, 0, 96]);
+        bytes.extend_from_slice(b"MTrk");
+        bytes.extend_from_slice(&(track.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(track);
+        bytes
+    }
+
+    fn parse(track: &[u8]) -> Result<Vec<TrackEvent>, SmfError> {
+        Smf::from_bytes(&single_track(track)).map(|mut smf| smf.tracks.remove(0))
+    }
+
+    fn midi(delta: u32, status: u8, data1: u8, data2: u8) -> TrackEvent {
+        TrackEvent {
+            delta,
+            event: Event::Midi {
+                status,
+                data1,
+                data2,
+            },
+        }
+    }
+
+    #[test]
+    fn rejects_status_bytes_as_data() {
+        // Note 200, velocity 100
+        assert!(matches!(
+            parse(&[0x00, 0x90, 0xc8, 0x64]),
+            Err(SmfError::Invalid(_))
+        ));
+        // Note 60, velocity 200
+        assert!(matches!(
+            parse(&[0x00, 0x90, 0x3c, 0xc8]),
+            Err(SmfError::Invalid(_))
+        ));
+        // A data byte with no status to run on
+        assert!(matches!(
+            parse(&[0x00, 0x3c, 0x64]),
+            Err(SmfError::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn running_status() {
+        let events = parse(&[
+            0x00, 0x90, 0x3c, 0x64, // Note on, C4
+            0x10, 0x40, 0x64, // Running status, E4
+            0x10, 0xc0, 0x05, // Program change
+            0x00, 0x07, // Running status, one data byte
+            0x00, 0xff, 0x2f, 0x00,
+        ])
+        .unwrap();
+        assert_eq!(
+            events,
+            vec![
+                midi(0, 0x90, 0x3c, 0x64),
+                midi(0x10, 0x90, 0x40, 0x64),
+                midi(0x10, 0xc0, 0x05, 0),
+                midi(0, 0xc0, 0x07, 0),
+                TrackEvent {
+                    delta: 0,
+                    event: Event::EndOfTrack
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn meta_and_sysex_lengths() {
+        // Tempo, 600000 microseconds per quarter note
+        let mut track = vec![0x00, 0xff, 0x51, 0x03, 0x09, 0x27, 0xc0];
+        // Track name, with a two-byte length of 130
+        track.extend_from_slice(&[0x00, 0xff, 0x03, 0x81, 0x02]);
+        track.extend_from_slice(&[b'a'; 130]);
+        // SysEx, with the terminating 0xF7 dropped
+        track.extend_from_slice(&[0x00, 0xf0, 0x03, 0x7e, 0x01, 0xf7]);
+        track.extend_from_slice(&[0x60, 0x90, 0x3c, 0x64, 0x00, 0xff, 0x2f, 0x00]);
+
+        let smf = Smf::from_bytes(&single_track(&track)).unwrap();
+        let events = &smf.tracks[0];
+        assert_eq!(events[0].event, Event::Tempo(600_000));
+        assert_eq!(
+            events[1].event,
+            Event::Meta {
+                kind: 0x03,
+                data: vec![b'a'; 130]
+            }
+        );
+        assert_eq!(events[2].event, Event::SysEx(vec![0x7e, 0x01]));
+        assert_eq!(events[3], midi(0x60, 0x90, 0x3c, 0x64));
+        assert_eq!(events[4].event, Event::EndOfTrack);
+        // 96 ticks is one quarter note at the new tempo.
+        let timed = smf.timed_events();
+        assert!((timed[3].seconds - 0.6).abs() < 1e-9);
+    }
+
+    #[test]
+    fn rejects_truncated_meta_and_sysex() {
+        // A track name that claims 130 bytes but has 2
+        assert!(parse(&[0x00, 0xff, 0x03, 0x81, 0x02, b'a', b'b']).is_err());
+        assert!(parse(&[0x00, 0xf0, 0x05, 0x7e, 0xf7]).is_err());
+    }
+
+    #[test]
+    fn sysex_cancels_running_status() {
+        let track = [
+            0x00, 0x90, 0x3c, 0x64, 0x00, 0xf0, 0x01, 0xf7, 0x00, 0x40, 0x64,
+        ];
+        assert!(matches!(parse(&track), Err(SmfError::Invalid(_))));
+    }
+}
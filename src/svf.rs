@@ -1,10 +1,64 @@
 use crate::constants::TWO_PI;
+use crate::processor::{Param, Processor};
+
+// Lower Q values are raised to this, since a Q of 0 divides by zero.
+const MIN_Q: f32 = 0.01;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum FilterMode {
+    Lowpass,
+    Bandpass,
+    Highpass,
+    Notch,
+    Peak,
+    Allpass,
+}
+
+// All of the responses the SVF produces for a single input sample. The
+// bandpass output is normalized to unity gain at the cutoff frequency, and the
+// notch, peak and allpass outputs are mixed from the three core outputs.
+#[derive(Clone, Copy)]
+pub struct SVFOutput {
+    pub lowpass: f32,
+    pub bandpass: f32,
+    pub highpass: f32,
+    pub notch: f32,
+    pub peak: f32,
+    pub allpass: f32,
+}
+
+impl SVFOutput {
+    pub fn select(&self, mode: FilterMode) -> f32 {
+        match mode {
+            FilterMode::Lowpass => self.lowpass,
+            FilterMode::Bandpass => self.bandpass,
+            FilterMode::Highpass => self.highpass,
+            FilterMode::Notch => self.notch,
+            FilterMode::Peak => self.peak,
+            FilterMode::Allpass => self.allpass,
+        }
+    }
+    // Crossfade continuously from lowpass (0.0) through bandpass (0.5) to
+    // highpass (1.0).
+    pub fn morph(&self, position: f32) -> f32 {
+        let position = position.clamp(0.0, 1.0) * 2.0;
+        if position < 1.0 {
+            self.lowpass * (1.0 - position) + self.bandpass * position
+        } else {
+            let position = position - 1.0;
+            self.bandpass * (1.0 - position) + self.highpass * position
+        }
+    }
+}
 
 pub struct SVF {
     integrator_z: Vec<f32>,
     alpha0: f32,
     alpha: f32,
     rho: f32,
+    r: f32,
+    // Output used when processing through the Processor trait
+    mode: FilterMode,
     memo: Memo,
 }
 
@@ -21,6 +75,8 @@ impl SVF {
             alpha0: 0.0,
             alpha: 0.0,
             rho: 0.0,
+            r: 0.0,
+            mode: FilterMode::Lowpass,
             memo: Memo {
                 fc: 500.0,
                 q: 20.0,
@@ -30,34 +86,110 @@ impl SVF {
         svf.calculate_coeffs();
         svf
     }
-    pub fn process_sample(&mut self, input: f32, fc: f32, q: f32, sample_rate: u32) -> f32 {
+    // Store parameters for processing through the Processor trait.
+    pub fn set_params(&mut self, fc: f32, q: f32, mode: FilterMode) {
+        self.mode = mode;
+        if fc != self.memo.fc || q != self.memo.q {
+            self.memo.fc = fc;
+            self.memo.q = q;
+            self.calculate_coeffs();
+        }
+    }
+    pub fn process_sample(
+        &mut self,
+        input: f32,
+        fc: f32,
+        q: f32,
+        mode: FilterMode,
+        sample_rate: u32,
+    ) -> f32 {
+        self.process(input, fc, q, sample_rate).select(mode)
+    }
+    // Filter a block in place. Block parameters only check the memo once per
+    // block.
+    pub fn process_block(
+        &mut self,
+        buffer: &mut [f32],
+        fc: Param,
+        q: Param,
+        mode: FilterMode,
+        sample_rate: u32,
+    ) {
+        if let (Param::Block(fc), Param::Block(q)) = (fc, q) {
+            self.update_memo(fc, q, sample_rate);
+            for sample in buffer.iter_mut() {
+                *sample = self.filter(*sample).select(mode);
+            }
+        } else {
+            for (i, sample) in buffer.iter_mut().enumerate() {
+                *sample = self.process_sample(*sample, fc.at(i), q.at(i), mode, sample_rate);
+            }
+        }
+    }
+    pub fn process(&mut self, input: f32, fc: f32, q: f32, sample_rate: u32) -> SVFOutput {
+        self.update_memo(fc, q, sample_rate);
+        self.filter(input)
+    }
+    fn update_memo(&mut self, fc: f32, q: f32, sample_rate: u32) {
         if fc != self.memo.fc || q != self.memo.q || sample_rate != self.memo.sample_rate {
             self.memo.fc = fc;
             self.memo.q = q;
             self.memo.sample_rate = sample_rate;
             self.calculate_coeffs();
         }
+    }
+    fn filter(&mut self, input: f32) -> SVFOutput {
         // Calculate filter outputs
         let hpf = self.alpha0 * (input - self.rho * self.integrator_z[0] - self.integrator_z[1]);
         let bpf = self.alpha * hpf + self.integrator_z[0];
         let lpf = self.alpha * bpf + self.integrator_z[1];
-        //let bsf = hpf + lpf;
 
         // Update state registers
         self.integrator_z[0] = self.alpha * hpf + bpf;
         self.integrator_z[1] = self.alpha * bpf + lpf;
 
-        lpf
+        // The input is decomposed as hpf + 2r * bpf + lpf, so the remaining
+        // responses can be mixed from the core outputs.
+        let bpf_norm = 2.0 * self.r * bpf;
+        SVFOutput {
+            lowpass: lpf,
+            bandpass: bpf_norm,
+            highpass: hpf,
+            notch: input - bpf_norm,
+            peak: lpf - hpf,
+            allpass: input - 2.0 * bpf_norm,
+        }
     }
     fn calculate_coeffs(&mut self) {
         let wd = TWO_PI * self.memo.fc;
         let ts = 1.0 / self.memo.sample_rate as f32;
         let angle = (2.0 / ts) * (wd * ts / 2.0).tan();
         let g = angle * ts / 2.0;
-        let r = 1.0 / (2.0 * self.memo.q);
+        let r = 1.0 / (2.0 * self.memo.q.max(MIN_Q));
 
         self.alpha0 = 1.0 / (1.0 + 2.0 * r * g + g * g);
         self.alpha = g;
         self.rho = 2.0 * r + g;
+        self.r = r;
+    }
+}
+
+impl Processor for SVF {
+    fn prepare(&mut self, sample_rate: u32, _max_block: usize) {
+        self.memo.sample_rate = sample_rate;
+        self.calculate_coeffs();
+    }
+    fn reset(&mut self) {
+        for z in self.integrator_z.iter_mut() {
+            *z = 0.0;
+        }
+    }
+    fn process(&mut self, input: f32) -> f32 {
+        self.filter(input).select(self.mode)
+    }
+    fn process_buffer(&mut self, buffer: &mut [f32]) {
+        for sample in buffer.iter_mut() {
+            *sample = self.filter(*sample).select(self.mode);
+        }
     }
 }
@@ -1,13 +1,30 @@
-use std::f32::consts::E;
-
 //use crate::biquad;
 use crate::svf;
 use crate::delay;
+use crate::conversion::semitones_to_ratio;
 use crate::envelope;
 use crate::midi;
 use crate::osc;
+use crate::processor::{Param, Processor};
+use crate::reverb;
+use crate::tuning;
+use crate::voice::{AllocationKind, StealPolicy, VoiceAllocator};
 use crate::wavetable;
 
+// Size of the scratch buffers used by process_block until prepare is called.
+// Longer blocks are processed in chunks of this size.
+const DEFAULT_BLOCK_SIZE: usize = 512;
+const WAVETABLE_SIZE: usize = 2048;
+// Number of voice slots, which is the upper limit for polyphony.
+const MAX_VOICES: usize = 32;
+const DEFAULT_POLYPHONY: usize = 16;
+// Stolen voices fade out over this time before the new note starts, to avoid
+// clicks.
+const STEAL_FADE_SECONDS: f32 = 0.005;
+const VIBRATO_TABLE_SIZE: usize = 1024;
+// Longest delay time, which sets the size of the delay buffer
+const MAX_DELAY_SECONDS: f32 = 2.0;
+
 pub enum OscType {
     Sine,
     Triangle,
@@ -34,23 +51,60 @@ pub enum Message {
     SetVolume(f32),
     SetOscillator(OscType),
     NoteOn(u8, u8),
+    NoteOff(u8, u8),
+    // Sustain pedal (MIDI CC64) down or up
+    SetSustainPedal(bool),
     SetEnvAttack(f32),
+    SetEnvDecay(f32),
+    SetEnvSustain(f32),
     SetEnvRelease(f32),
     SetDelayWetdry(f32),
     SetDelayFeedback(f32),
     SetDelaySeconds(f32),
     SetFilterFreq(f32),
     SetFilterQ(f32),
+    SetFilterMorph(f32),
+    SetPolyphony(usize),
+    SetStealPolicy(StealPolicy),
+    SetReverbWetdry(f32),
+    SetReverbRoomSize(f32),
+    SetReverbDamping(f32),
+    SetReverbPreDelay(f32),
+    // Pitch bend, from -1.0 to 1.0, scaled by the pitch bend range
+    PitchBend(f32),
+    // Pitch bend range in semitones, in each direction
+    SetPitchBendRange(f32),
+    // Pitch offset for all voices in semitones, for modulation from outside
+    // the synth. Fractions of a semitone are cents.
+    SetPitchModulation(f32),
+    // Spread of the fixed detune given to each voice, in cents
+    SetDetune(f32),
+    SetVibratoRate(f32),
+    // Vibrato depth in semitones
+    SetVibratoDepth(f32),
+    // Time from the start of a note until vibrato begins, in seconds
+    SetVibratoDelay(f32),
+    // Time for vibrato to fade in once it begins, in seconds
+    SetVibratoFade(f32),
 }
 
 struct UserControl {
     delay_feedback_amount: f32,
     delay_seconds: f32,
     delay_wetdry: f32,
-    envelope_attack: f32,
-    envelope_release: f32,
+    envelope: envelope::AdsrParams,
     filter_freq: f32,
+    filter_morph: f32,
     filter_q: f32,
+    detune_cents: f32,
+    pitch_bend: f32,
+    pitch_bend_range: f32,
+    pitch_modulation: f32,
+    reverb: reverb::ReverbParams,
+    vibrato_delay: f32,
+    vibrato_depth: f32,
+    vibrato_fade: f32,
+    vibrato_rate: f32,
     volume: f32,
     wavetable_index: usize,
 }
@@ -61,10 +115,24 @@ impl UserControl {
             delay_feedback_amount: 0.7,
             delay_seconds: 0.25,
             delay_wetdry: 0.5,
-            envelope_attack: 0.01,
-            envelope_release: 0.5,
+            envelope: envelope::AdsrParams::new(),
             filter_freq: 1000.0,
+            filter_morph: 0.0,
             filter_q: 1.0,
+            detune_cents: 0.0,
+            pitch_bend: 0.0,
+            pitch_bend_range: 2.0,
+            pitch_modulation: 0.0,
+            // The reverb is off until its wet/dry is raised.
+            reverb: reverb::ReverbParams {
+                wetdry: 0.0,
+                ..reverb::ReverbParams::new()
+            },
+            // Vibrato is off until its depth is raised.
+            vibrato_delay: 0.0,
+            vibrato_depth: 0.0,
+            vibrato_fade: 0.0,
+            vibrato_rate: 5.0,
             volume: 0.5,
             wavetable_index: OscType::Sine as usize,
         }
@@ -74,40 +142,125 @@ impl UserControl {
 pub struct BasicSynth {
     control: UserControl,
     delay: delay::SimpleDelay,
-    envelope_reader: Vec<envelope::EnvReader>,
-    envelope_table: Vec<f32>,
+    envelope_buffer: Vec<f32>,
+    envelope: Vec<envelope::Adsr>,
     filter: svf::SVF,
     midi_table: Vec<f32>,
+    osc_buffer: Vec<f32>,
+    // Per-sample voice frequencies, used by process_block while vibrato is on
+    pitch_buffer: Vec<f32>,
+    reverb: reverb::Freeverb,
+    // Used when processing through the Processor trait
+    sample_rate: u32,
+    sustain_pedal: bool,
     table_reader: Vec<osc::OscReader>,
+    vibrato: Vec<osc::OscReader>,
+    vibrato_table: Vec<f32>,
+    voice_allocator: VoiceAllocator,
+    // Samples since each voice's note started, for the vibrato delay and fade
+    voice_age: Vec<u32>,
+    // Fixed detune of each voice, from -1.0 to 1.0, scaled by the detune
+    // spread
+    voice_detune: Vec<f32>,
+    // Gain applied to voices that are fading out after being stolen
+    voice_fade: Vec<f32>,
     voice_info: Vec<NoteInfo>,
+    voice_levels: Vec<f32>,
     voice_output: f32,
-    wavetable: Vec<Vec<f32>>,
+    // Notes waiting for a stolen voice to finish fading out
+    voice_pending: Vec<Option<NoteInfo>>,
+    // Voices whose key has been released while the sustain pedal is down
+    voice_sustained: Vec<bool>,
+    wavetable: Vec<wavetable::MipmapTable>,
+}
+
+impl Default for BasicSynth {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl BasicSynth {
     pub fn new() -> BasicSynth {
-        BasicSynth {
+        let mut synth = BasicSynth {
             control: UserControl::new(),
-            delay: delay::SimpleDelay::new((44100 * 2) as usize),
-            envelope_reader: vec![envelope::EnvReader::new(); 128],
-            envelope_table: wavetable::make_exp_envelope(1024, E),
+            delay: delay::SimpleDelay::new((44100.0 * MAX_DELAY_SECONDS) as usize),
+            envelope_buffer: vec![0.0; DEFAULT_BLOCK_SIZE],
+            envelope: vec![envelope::Adsr::new(); MAX_VOICES],
             filter: svf::SVF::new(44100),
             midi_table: midi::make_midi_freq_table(),
-            table_reader: vec![osc::OscReader::new(); 128],
-            voice_info: vec![NoteInfo::new(0.0, 0.0); 128],
+            osc_buffer: vec![0.0; DEFAULT_BLOCK_SIZE],
+            pitch_buffer: vec![0.0; DEFAULT_BLOCK_SIZE],
+            reverb: reverb::Freeverb::new(),
+            sample_rate: 44100,
+            sustain_pedal: false,
+            table_reader: vec![osc::OscReader::new(); MAX_VOICES],
+            vibrato: vec![osc::OscReader::new(); MAX_VOICES],
+            vibrato_table: wavetable::make_sine_table(VIBRATO_TABLE_SIZE),
+            voice_allocator: VoiceAllocator::new(MAX_VOICES, StealPolicy::Oldest),
+            voice_age: vec![0; MAX_VOICES],
+            voice_detune: (0..MAX_VOICES).map(detune_offset).collect(),
+            voice_fade: vec![1.0; MAX_VOICES],
+            voice_info: vec![NoteInfo::new(0.0, 0.0); MAX_VOICES],
+            voice_levels: vec![0.0; MAX_VOICES],
             voice_output: 0.0,
-            wavetable: wavetable::make_sin_saw_table(1024, 24),
-        }
+            voice_pending: vec![None; MAX_VOICES],
+            voice_sustained: vec![false; MAX_VOICES],
+            wavetable: wavetable::make_sin_saw_mipmaps(WAVETABLE_SIZE, 44100),
+        };
+        synth.voice_allocator.set_polyphony(DEFAULT_POLYPHONY);
+        synth.delay.set_max_seconds(MAX_DELAY_SECONDS);
+        synth
+    }
+    // Replace the note frequency table, such as one made by
+    // tuning::Tuning::make_table. The table must have an entry for each of
+    // the 128 MIDI notes, or it is rejected. Notes already playing keep their
+    // frequency.
+    pub fn set_tuning_table(&mut self, table: Vec<f32>) -> Result<(), tuning::TuningError> {
+        self.midi_table = tuning::check_table(table)?;
+        Ok(())
+    }
+    pub fn tuning_table(&self) -> &[f32] {
+        &self.midi_table
+    }
+    // Retune from a MIDI Tuning Standard SysEx message, returning true if it
+    // was a tuning message. See tuning::apply_mts.
+    pub fn apply_mts(&mut self, message: &[u8]) -> bool {
+        tuning::apply_mts(&mut self.midi_table, message)
     }
     pub fn send(&mut self, message: Message) {
         match message {
             // NOTE: Incoming slider range values will always be in range 0.0
             // 1.0.
+            // MIDI notes only go up to 127, so others are ignored.
+            Message::NoteOn(note, _) | Message::NoteOff(note, _) if note > 127 => {}
+            // A NoteOn with zero velocity is a NoteOff, as in MIDI.
+            Message::NoteOn(note, 0) => {
+                self.note_off(note);
+            }
             Message::NoteOn(note, velocity) => {
+                let frequency = self.midi_table[note as usize];
+                // Notes left unmapped by the tuning aren't played.
+                if frequency <= 0.0 {
+                    return;
+                }
                 let norm_velocity: f32 = velocity as f32 / 127.0;
-                let n = NoteInfo::new(self.midi_table[note as usize], norm_velocity);
-                self.voice_info[note as usize] = n;
-                self.envelope_reader[note as usize].start();
+                let n = NoteInfo::new(frequency, norm_velocity);
+                self.note_on(note, n);
+            }
+            Message::NoteOff(note, _velocity) => {
+                self.note_off(note);
+            }
+            Message::SetSustainPedal(is_down) => {
+                self.sustain_pedal = is_down;
+                if !is_down {
+                    for i in 0..MAX_VOICES {
+                        if self.voice_sustained[i] {
+                            self.voice_sustained[i] = false;
+                            self.envelope[i].stop();
+                        }
+                    }
+                }
             }
             Message::SetOscillator(osctype) => {
                 self.control.wavetable_index = osctype as usize;
@@ -116,10 +269,16 @@ impl BasicSynth {
                 self.control.volume = f32::powf(value, 2.0);
             }
             Message::SetEnvAttack(value) => {
-                self.control.envelope_attack = value;
+                self.control.envelope.attack = value;
             }
             Message::SetEnvRelease(value) => {
-                self.control.envelope_release = value;
+                self.control.envelope.release = value;
+            }
+            Message::SetEnvDecay(value) => {
+                self.control.envelope.decay = value;
+            }
+            Message::SetEnvSustain(value) => {
+                self.control.envelope.sustain = value;
             }
             Message::SetDelayWetdry(value) => {
                 self.control.delay_wetdry = value;
@@ -134,44 +293,216 @@ impl BasicSynth {
                 self.control.filter_freq = f32::powf(value, 2.0) * 22050.0;
             }
             Message::SetFilterQ(value) => {
-                self.control.filter_q = value * 20.0;
+                // Scale to a Q of 0.5 to 20.
+                self.control.filter_q = 0.5 + value.clamp(0.0, 1.0) * 19.5;
+            }
+            Message::SetFilterMorph(value) => {
+                self.control.filter_morph = value;
+            }
+            Message::SetPolyphony(value) => {
+                self.voice_allocator.set_polyphony(value);
+            }
+            Message::SetStealPolicy(policy) => {
+                self.voice_allocator.set_policy(policy);
+            }
+            Message::SetReverbWetdry(value) => {
+                // The reverb isn't run while it's off, so clear any old tail
+                // before turning it back on.
+                if self.control.reverb.wetdry == 0.0 && value != 0.0 {
+                    self.reverb.reset();
+                }
+                self.control.reverb.wetdry = value;
+            }
+            Message::SetReverbRoomSize(value) => {
+                self.control.reverb.room_size = value;
+            }
+            Message::SetReverbDamping(value) => {
+                self.control.reverb.damping = value;
+            }
+            Message::SetReverbPreDelay(value) => {
+                self.control.reverb.pre_delay_seconds = value;
+            }
+            Message::PitchBend(value) => {
+                self.control.pitch_bend = value.clamp(-1.0, 1.0);
+            }
+            Message::SetPitchBendRange(value) => {
+                self.control.pitch_bend_range = value;
+            }
+            Message::SetPitchModulation(value) => {
+                self.control.pitch_modulation = value;
+            }
+            Message::SetDetune(value) => {
+                self.control.detune_cents = value;
+            }
+            Message::SetVibratoRate(value) => {
+                self.control.vibrato_rate = value;
+            }
+            Message::SetVibratoDepth(value) => {
+                self.control.vibrato_depth = value;
+            }
+            Message::SetVibratoDelay(value) => {
+                self.control.vibrato_delay = value;
+            }
+            Message::SetVibratoFade(value) => {
+                self.control.vibrato_fade = value;
             }
         }
     }
-    pub fn tick(&mut self, sample_rate: u32) -> f32 {
-        self.voice_output = 0.0;
-        // Increment readers if they have an active envelope. There is still
-        // time to tweak the phase after this for FM or sync effects.
-        for i in 0..self.table_reader.len() {
-            if self.envelope_reader[i].is_active {
-                let freq = self.voice_info[i].frequency;
-                self.table_reader[i].increment(freq, sample_rate);
-                self.envelope_reader[i].increment(
-                    self.control.envelope_attack,
-                    self.control.envelope_release,
-                    sample_rate,
-                );
+    fn note_on(&mut self, note: u8, info: NoteInfo) {
+        for i in 0..MAX_VOICES {
+            self.voice_levels[i] = if self.voice_allocator.is_active(i) {
+                self.envelope[i].level() * self.voice_info[i].velocity * self.voice_fade[i]
+            } else {
+                0.0
+            };
+        }
+        let allocation = self.voice_allocator.note_on(note, &self.voice_levels);
+        let i = allocation.voice;
+        self.voice_sustained[i] = false;
+        match allocation.kind {
+            AllocationKind::Free => {
+                self.voice_info[i] = info;
+                self.start_vibrato(i);
+                self.envelope[i].start(&self.control.envelope);
+            }
+            AllocationKind::Retrigger => {
+                if self.voice_pending[i].is_some() {
+                    self.voice_pending[i] = Some(info);
+                } else {
+                    self.voice_info[i] = info;
+                    self.start_vibrato(i);
+                    self.envelope[i].start(&self.control.envelope);
+                }
+            }
+            AllocationKind::Stolen => {
+                // The allocator only steals a voice with a note already
+                // pending when every voice has one, and then the newest note
+                // wins.
+                self.voice_pending[i] = Some(info);
+                self.voice_allocator.set_pending(i, true);
+            }
+        }
+    }
+    fn note_off(&mut self, note: u8) {
+        if let Some(i) = self.voice_allocator.note_off(note) {
+            if self.sustain_pedal {
+                self.voice_sustained[i] = true;
+            } else if self.voice_pending[i].is_none() {
+                // Pending notes check whether they are still held once the
+                // stolen voice has faded out.
+                self.envelope[i].stop();
+            }
+        }
+    }
+    fn start_pending_note(&mut self, i: usize) {
+        if let Some(info) = self.voice_pending[i].take() {
+            self.voice_allocator.set_pending(i, false);
+            self.voice_info[i] = info;
+            self.start_vibrato(i);
+            self.voice_fade[i] = 1.0;
+            self.envelope[i].reset();
+            self.envelope[i].start(&self.control.envelope);
+            if !self.voice_allocator.is_held(i) && !self.voice_sustained[i] {
+                self.envelope[i].stop();
             }
         }
-        // Read from signal generators and add to the voice output.
-        for i in 0..self.table_reader.len() {
-            if self.envelope_reader[i].is_active {
-                self.voice_output += self.table_reader[i].read(
-                    &self.wavetable[self.control.wavetable_index],
-                    osc::linear_interpolate,
-                ) * self.envelope_reader[i]
-                    .read(&self.envelope_table, envelope::linear_interpolate)
-                    * self.voice_info[i].velocity;
+    }
+    fn start_vibrato(&mut self, i: usize) {
+        self.voice_age[i] = 0;
+        self.vibrato[i].phase = 0.0;
+    }
+    // Pitch offset of a voice in semitones from everything but vibrato:
+    // pitch bend, pitch modulation and detune.
+    fn static_pitch(&self, i: usize) -> f32 {
+        self.control.pitch_bend * self.control.pitch_bend_range
+            + self.control.pitch_modulation
+            + self.voice_detune[i] * self.control.detune_cents / 100.0
+    }
+    // Vibrato offset of a voice in semitones, advancing its LFO by one
+    // sample. The LFO runs even at zero depth, so tick and process_block stay
+    // in step.
+    fn tick_vibrato(&mut self, i: usize, sample_rate: u32) -> f32 {
+        self.vibrato[i].increment(self.control.vibrato_rate, sample_rate);
+        let lfo = osc::OscReader::read_linear(&self.vibrato[i], &self.vibrato_table);
+        let seconds = self.voice_age[i] as f32 / sample_rate as f32 - self.control.vibrato_delay;
+        self.voice_age[i] = self.voice_age[i].saturating_add(1);
+        let fade = if seconds < 0.0 {
+            0.0
+        } else if seconds < self.control.vibrato_fade {
+            seconds / self.control.vibrato_fade
+        } else {
+            1.0
+        };
+        lfo * fade * self.control.vibrato_depth
+    }
+    // Advance a voice's vibrato LFO and age by several samples at once, for
+    // blocks without vibrato.
+    fn skip_vibrato(&mut self, i: usize, samples: usize, sample_rate: u32) {
+        self.vibrato[i].increment_by(self.control.vibrato_rate, samples, sample_rate);
+        self.voice_age[i] = self.voice_age[i].saturating_add(samples as u32);
+    }
+    // Frequency of a voice for the next sample.
+    fn voice_frequency(&mut self, i: usize, sample_rate: u32) -> f32 {
+        let vibrato = self.tick_vibrato(i, sample_rate);
+        self.voice_info[i].frequency * semitones_to_ratio(self.static_pitch(i) + vibrato)
+    }
+    // Release voices whose envelope has finished, or start the pending note
+    // on a stolen voice.
+    fn update_voice_state(&mut self, i: usize) {
+        if !self.envelope[i].is_active {
+            if self.voice_pending[i].is_some() {
+                self.start_pending_note(i);
+            } else {
+                self.voice_allocator.voice_finished(i);
+            }
+        }
+    }
+    fn tick_voice(&mut self, i: usize, sample_rate: u32) -> f32 {
+        // There is still time to tweak the phase after incrementing for FM or
+        // sync effects.
+        let freq = self.voice_frequency(i, sample_rate);
+        self.table_reader[i].increment(freq, sample_rate);
+        let level = self.envelope[i].tick(&self.control.envelope, sample_rate);
+        let mut output = self.table_reader[i]
+            .read_mipmap(&self.wavetable[self.control.wavetable_index])
+            * level
+            * self.voice_info[i].velocity;
+        if self.voice_pending[i].is_some() {
+            output *= self.voice_fade[i];
+            self.voice_fade[i] -= 1.0 / (STEAL_FADE_SECONDS * sample_rate as f32);
+            if self.voice_fade[i] <= 0.0 {
+                self.start_pending_note(i);
+            }
+        }
+        output
+    }
+    // The band-limited wavetables depend on the sample rate. Rebuilding them
+    // allocates, so it is only done in Processor::prepare. Until prepare is
+    // called, the tables are built for 44.1kHz.
+    fn update_wavetables(&mut self, sample_rate: u32) {
+        if self.wavetable[0].sample_rate() != sample_rate {
+            self.wavetable = wavetable::make_sin_saw_mipmaps(WAVETABLE_SIZE, sample_rate);
+        }
+    }
+    pub fn tick(&mut self, sample_rate: u32) -> f32 {
+        self.voice_output = 0.0;
+        for i in 0..MAX_VOICES {
+            if self.voice_allocator.is_active(i) {
+                self.voice_output += self.tick_voice(i, sample_rate);
+                self.update_voice_state(i);
             }
         }
 
         // Pass output through the filter
-        let filter_output = self.filter.process_sample(
-            self.voice_output,
-            self.control.filter_freq,
-            self.control.filter_q,
-            sample_rate,
-        );
+        let filter_output = self
+            .filter
+            .process(
+                self.voice_output,
+                self.control.filter_freq,
+                self.control.filter_q,
+                sample_rate,
+            )
+            .morph(self.control.filter_morph);
 
         // TODO: Stop storing voice_output, just build it up in the tick fn
         self.voice_output = filter_output;
@@ -184,8 +515,301 @@ impl BasicSynth {
         );
 
         // TODO: Create a mixer module to handle wet/dry
-        (self.voice_output * (1.0 - self.control.delay_wetdry)
-            + (delay_output * self.control.delay_wetdry))
-            * self.control.volume
+        let delay_mix = self.voice_output * (1.0 - self.control.delay_wetdry)
+            + (delay_output * self.control.delay_wetdry);
+
+        self.reverb_tick(delay_mix, sample_rate) * self.control.volume
+    }
+    // The synth is mono, so the stereo reverb output is summed. The reverb is
+    // skipped while it's off.
+    fn reverb_tick(&mut self, input: f32, sample_rate: u32) -> f32 {
+        if self.control.reverb.wetdry == 0.0 {
+            return input;
+        }
+        let (left, right) = self
+            .reverb
+            .tick(input, input, &self.control.reverb, sample_rate);
+        (left + right) * 0.5
+    }
+    // Render a block of output. Each voice renders a whole block at a time,
+    // so control values are only checked once per block.
+    pub fn process_block(&mut self, output: &mut [f32], sample_rate: u32) {
+        let chunk_size = self.osc_buffer.len();
+        for chunk in output.chunks_mut(chunk_size) {
+            self.render_chunk(chunk, sample_rate);
+        }
+    }
+    fn render_chunk(&mut self, output: &mut [f32], sample_rate: u32) {
+        let len = output.len();
+        for sample in output.iter_mut() {
+            *sample = 0.0;
+        }
+        for i in 0..MAX_VOICES {
+            if !self.voice_allocator.is_active(i) {
+                continue;
+            }
+            // Voices fading out after being stolen switch notes partway
+            // through the block, so they are rendered one sample at a time.
+            if self.voice_pending[i].is_some() {
+                for sample in output.iter_mut() {
+                    *sample += self.tick_voice(i, sample_rate);
+                }
+                self.update_voice_state(i);
+                continue;
+            }
+            // Without vibrato, the frequency is the same for the whole block.
+            let frequency = if self.control.vibrato_depth == 0.0 {
+                self.skip_vibrato(i, len, sample_rate);
+                let ratio = semitones_to_ratio(self.static_pitch(i));
+                Param::Block(self.voice_info[i].frequency * ratio)
+            } else {
+                for j in 0..len {
+                    self.pitch_buffer[j] = self.voice_frequency(i, sample_rate);
+                }
+                Param::Samples(&self.pitch_buffer[..len])
+            };
+            self.table_reader[i].process_block_mipmap(
+                &mut self.osc_buffer[..len],
+                &self.wavetable[self.control.wavetable_index],
+                frequency,
+                sample_rate,
+            );
+            self.envelope[i].process_block(
+                &mut self.envelope_buffer[..len],
+                &self.control.envelope,
+                sample_rate,
+            );
+            let velocity = self.voice_info[i].velocity;
+            for (j, sample) in output.iter_mut().enumerate() {
+                *sample += self.osc_buffer[j] * self.envelope_buffer[j] * velocity;
+            }
+            self.update_voice_state(i);
+        }
+
+        for sample in output.iter_mut() {
+            *sample = self
+                .filter
+                .process(
+                    *sample,
+                    self.control.filter_freq,
+                    self.control.filter_q,
+                    sample_rate,
+                )
+                .morph(self.control.filter_morph);
+        }
+
+        // Keep the dry signal in the oscillator scratch buffer, which is free
+        // once the voices are rendered.
+        let delay_output = &mut self.osc_buffer[..len];
+        delay_output.copy_from_slice(output);
+        self.delay.process_block(
+            delay_output,
+            Param::Block(self.control.delay_seconds),
+            Param::Block(self.control.delay_feedback_amount),
+            sample_rate,
+        );
+
+        let wetdry = self.control.delay_wetdry;
+        for (sample, delayed) in output.iter_mut().zip(delay_output.iter()) {
+            *sample = *sample * (1.0 - wetdry) + delayed * wetdry;
+        }
+
+        for sample in output.iter_mut() {
+            *sample = self.reverb_tick(*sample, sample_rate) * self.control.volume;
+        }
+    }
+}
+
+// The synth is a signal generator, so the input to process is ignored.
+impl Processor for BasicSynth {
+    fn prepare(&mut self, sample_rate: u32, max_block: usize) {
+        self.sample_rate = sample_rate;
+        self.update_wavetables(sample_rate);
+        self.osc_buffer.resize(max_block.max(1), 0.0);
+        self.envelope_buffer.resize(max_block.max(1), 0.0);
+        self.pitch_buffer.resize(max_block.max(1), 0.0);
+        self.filter.prepare(sample_rate, max_block);
+        self.delay.prepare(sample_rate, max_block);
+        self.reverb.prepare(sample_rate, max_block);
+    }
+    fn reset(&mut self) {
+        for i in 0..MAX_VOICES {
+            self.envelope[i].reset();
+            self.voice_allocator.voice_finished(i);
+            self.voice_fade[i] = 1.0;
+            self.voice_pending[i] = None;
+            self.voice_sustained[i] = false;
+        }
+        self.sustain_pedal = false;
+        for reader in self.table_reader.iter_mut() {
+            reader.phase = 0.0;
+        }
+        for i in 0..MAX_VOICES {
+            self.start_vibrato(i);
+        }
+        self.filter.reset();
+        self.delay.reset();
+        self.reverb.reset();
+    }
+    fn process(&mut self, _input: f32) -> f32 {
+        self.tick(self.sample_rate)
+    }
+    fn process_buffer(&mut self, buffer: &mut [f32]) {
+        self.process_block(buffer, self.sample_rate);
+    }
+}
+
+// Detune of each voice slot, from -1.0 to 1.0. Successive slots are spread
+// out by the golden ratio, so any group of voices covers the range evenly.
+fn detune_offset(voice: usize) -> f32 {
+    let x = voice as f32 * 0.618_034;
+    (x - x.floor()) * 2.0 - 1.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: u32 = 48000;
+
+    // A synth with a sine oscillator and no delay or reverb, holding A3.
+    fn play_a3(messages: Vec<Message>) -> BasicSynth {
+        let mut synth = BasicSynth::new();
+        synth.prepare(SAMPLE_RATE, DEFAULT_BLOCK_SIZE);
+        synth.send(Message::SetDelayWetdry(0.0));
+        for message in messages {
+            synth.send(message);
+        }
+        synth.send(Message::NoteOn(57, 100));
+        synth
+    }
+
+    // Frequency from the rising zero crossings in the second half of a
+    // second of output, after the attack.
+    fn measure_frequency(output: &[f32]) -> f64 {
+        let mut crossings = Vec::new();
+        for i in output.len() / 2..output.len() - 1 {
+            let (a, b) = (output[i] as f64, output[i + 1] as f64);
+            if a < 0.0 && b >= 0.0 {
+                crossings.push(i as f64 + a / (a - b));
+            }
+        }
+        let cycles = (crossings.len() - 1) as f64;
+        cycles * SAMPLE_RATE as f64 / (crossings[crossings.len() - 1] - crossings[0])
+    }
+
+    fn render_block(synth: &mut BasicSynth) -> Vec<f32> {
+        let mut output = vec![0.0; SAMPLE_RATE as usize];
+        synth.process_block(&mut output, SAMPLE_RATE);
+        output
+    }
+
+    fn assert_frequency(output: &[f32], expected: f64) {
+        let frequency = measure_frequency(output);
+        assert!(
+            (frequency / expected - 1.0).abs() < 1e-4,
+            "expected {} Hz, measured {} Hz",
+            expected,
+            frequency
+        );
+    }
+
+    #[test]
+    fn ignores_notes_above_127() {
+        let mut synth = BasicSynth::new();
+        synth.send(Message::NoteOn(200, 100));
+        synth.send(Message::NoteOff(200, 0));
+        assert!(synth.tick(44100).abs() < 1e-9);
+    }
+
+    #[test]
+    fn rejects_short_tuning_tables() {
+        let mut synth = BasicSynth::new();
+        assert!(synth.set_tuning_table(vec![440.0; 12]).is_err());
+        assert_eq!(synth.tuning_table().len(), tuning::TABLE_SIZE);
+        let table = tuning::Tuning::equal_temperament(432.0)
+            .make_table()
+            .unwrap();
+        assert!(synth.set_tuning_table(table).is_ok());
+        assert_eq!(synth.tuning_table()[69], 432.0);
+    }
+
+    #[test]
+    fn pitch_bend_range() {
+        let mut synth = play_a3(vec![Message::PitchBend(-1.0)]);
+        assert_frequency(&render_block(&mut synth), 220.0 * 2f64.powf(-2.0 / 12.0));
+
+        let mut synth = play_a3(vec![
+            Message::SetPitchBendRange(12.0),
+            Message::PitchBend(1.0),
+        ]);
+        assert_frequency(&render_block(&mut synth), 440.0);
+        let output: Vec<f32> = (0..SAMPLE_RATE).map(|_| synth.tick(SAMPLE_RATE)).collect();
+        assert_frequency(&output, 440.0);
+    }
+
+    #[test]
+    fn detune() {
+        // The first voice has the lowest detune, the full amount flat.
+        let mut synth = play_a3(vec![Message::SetDetune(50.0)]);
+        assert_frequency(&render_block(&mut synth), 220.0 * 2f64.powf(-50.0 / 1200.0));
+    }
+
+    #[test]
+    fn vibrato_delay_and_fade() {
+        let mut synth = play_a3(vec![
+            Message::SetVibratoDepth(1.0),
+            Message::SetVibratoDelay(0.1),
+            Message::SetVibratoFade(0.2),
+        ]);
+        let sample_rate = SAMPLE_RATE as f32;
+        let mut fade_in = 0.0f32;
+        let mut full = 0.0f32;
+        for n in 0..SAMPLE_RATE {
+            let seconds = n as f32 / sample_rate;
+            let semitones = (synth.voice_frequency(0, SAMPLE_RATE) / 220.0).log2() * 12.0;
+            if seconds < 0.1 {
+                assert!(semitones.abs() < 1e-5, "vibrato before the delay");
+            } else if seconds < 0.3 {
+                let fade = (seconds - 0.1) / 0.2;
+                assert!(semitones.abs() <= fade + 1e-3, "vibrato ahead of the fade");
+                fade_in = fade_in.max(semitones.abs());
+            } else {
+                full = full.max(semitones.abs());
+            }
+        }
+        assert!(fade_in > 0.5);
+        assert!((full - 1.0).abs() < 1e-3);
+    }
+
+    // Blocks without vibrato skip the LFO ahead, so it stays in step with
+    // tick for when the vibrato starts.
+    #[test]
+    fn process_block_matches_tick() {
+        let messages = || {
+            vec![
+                Message::PitchBend(0.3),
+                Message::SetDetune(20.0),
+                Message::SetVibratoDelay(0.5),
+            ]
+        };
+        let mut block_synth = play_a3(messages());
+        let mut tick_synth = play_a3(messages());
+        let block = render_block(&mut block_synth);
+        for (i, a) in block.iter().enumerate() {
+            let b = tick_synth.tick(SAMPLE_RATE);
+            assert!((a - b).abs() < 1e-5, "sample {}: {} != {}", i, a, b);
+        }
+        assert_eq!(block_synth.voice_age[0], tick_synth.voice_age[0]);
+        let phase_error = block_synth.vibrato[0].phase - tick_synth.vibrato[0].phase;
+        assert!(phase_error.abs() < 1e-3);
+    }
+
+    #[test]
+    fn zero_sample_rate_does_not_panic() {
+        let mut synth = BasicSynth::new();
+        synth.prepare(0, DEFAULT_BLOCK_SIZE);
+        synth.send(Message::NoteOn(57, 100));
+        synth.tick(0);
     }
 }
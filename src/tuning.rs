@@ -0,0 +1,649 @@
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+// Microtuning: Scala scale (.scl) and keyboard mapping (.kbm) files, and MIDI
+// Tuning Standard (MTS) SysEx messages. A Tuning combines a scale and a
+// mapping into the 128-note frequency table that the synths read note
+// frequencies from.
+//
+// See: http://www.huygens-fokker.org/scala/scl_format.html
+// See: http://www.huygens-fokker.org/scala/help.htm#mappings
+// See: The MIDI Tuning Standard, in The Complete MIDI 1.0 Detailed
+// Specification.
+
+#[derive(Debug)]
+pub enum TuningError {
+    Io(io::Error),
+    // A line of a Scala file couldn't be parsed. Lines are numbered from 1.
+    Parse { line: usize, reason: &'static str },
+    // The files parsed, but don't describe a usable tuning.
+    Invalid(&'static str),
+}
+
+impl fmt::Display for TuningError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TuningError::Io(error) => write!(f, "tuning file I/O error: {}", error),
+            TuningError::Parse { line, reason } => {
+                write!(f, "invalid tuning file, line {}: {}", line, reason)
+            }
+            TuningError::Invalid(reason) => write!(f, "invalid tuning: {}", reason),
+        }
+    }
+}
+
+impl Error for TuningError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            TuningError::Io(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for TuningError {
+    fn from(error: io::Error) -> Self {
+        TuningError::Io(error)
+    }
+}
+
+// Number of notes in a frequency table, one per MIDI note
+pub const TABLE_SIZE: usize = 128;
+pub const A4_NOTE: u8 = 69;
+pub const A4_FREQUENCY: f64 = 440.0;
+// Largest keyboard mapping accepted, and the most space reserved for a
+// scale's degrees before they are read, so a bad count in a file can't
+// allocate without bound
+const MAX_FILE_COUNT: usize = 1024;
+
+// A scale, as in a Scala .scl file: the pitches of each degree above the
+// root, in cents. The root (1/1) is implied, and the last degree is the
+// period the scale repeats at, usually an octave of 1200 cents.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Scale {
+    pub description: String,
+    pub degrees: Vec<f64>,
+}
+
+impl Scale {
+    pub fn equal_temperament(notes: usize, period_cents: f64) -> Scale {
+        let notes = notes.max(1);
+        Scale {
+            description: format!("{} equal divisions of {} cents", notes, period_cents),
+            degrees: (1..=notes)
+                .map(|i| period_cents * i as f64 / notes as f64)
+                .collect(),
+        }
+    }
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Scale, TuningError> {
+        Scale::parse(&fs::read_to_string(path)?)
+    }
+    pub fn parse(text: &str) -> Result<Scale, TuningError> {
+        let mut lines = scala_lines(text);
+        // The description may be empty, so it is the only line read whole.
+        let description = match lines.next() {
+            Some((_, line)) => line.trim().to_string(),
+            None => return Err(TuningError::Invalid("missing description")),
+        };
+        let (line, count) = lines
+            .next()
+            .ok_or(TuningError::Invalid("missing note count"))?;
+        let count: usize = first_field(count)
+            .parse()
+            .map_err(|_| parse_error(line, "note count is not a number"))?;
+        let mut degrees = Vec::with_capacity(count.min(MAX_FILE_COUNT));
+        for _ in 0..count {
+            let (line, text) = lines
+                .next()
+                .ok_or(TuningError::Invalid("fewer pitches than the note count"))?;
+            degrees
+                .push(parse_pitch(first_field(text)).map_err(|reason| parse_error(line, reason))?);
+        }
+        Ok(Scale {
+            description,
+            degrees,
+        })
+    }
+    pub fn len(&self) -> usize {
+        self.degrees.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.degrees.is_empty()
+    }
+    // The interval the scale repeats at, in cents.
+    pub fn period(&self) -> f64 {
+        self.degrees.last().copied().unwrap_or(1200.0)
+    }
+    // Pitch of a degree above the root, in cents. Degrees past the end of the
+    // scale, and negative degrees, continue into the next and previous
+    // periods.
+    pub fn cents(&self, degree: i32) -> f64 {
+        if self.degrees.is_empty() {
+            return 0.0;
+        }
+        let len = self.degrees.len() as i32;
+        let period = degree.div_euclid(len);
+        let index = degree.rem_euclid(len);
+        let cents = if index == 0 {
+            0.0
+        } else {
+            self.degrees[index as usize - 1]
+        };
+        period as f64 * self.period() + cents
+    }
+}
+
+// A keyboard mapping, as in a Scala .kbm file, which places a scale on the
+// MIDI keyboard and sets the reference pitch.
+#[derive(Clone, PartialEq, Debug)]
+pub struct KeyboardMapping {
+    // Lowest and highest notes to retune. Notes outside the range are
+    // unmapped.
+    pub first_note: u8,
+    pub last_note: u8,
+    // Note the first entry of the mapping, and the root of the scale, is
+    // placed on
+    pub middle_note: u8,
+    // Note with a fixed frequency, such as A4 at 440 Hz
+    pub reference_note: u8,
+    pub reference_frequency: f64,
+    // Scale degree that the mapping pattern repeats at. If 0, the pattern
+    // repeats at the scale's period.
+    pub octave_degree: usize,
+    // Scale degree of each key in the repeating pattern, or None for keys
+    // that aren't mapped. If empty, consecutive keys play consecutive
+    // degrees.
+    pub keys: Vec<Option<i32>>,
+}
+
+impl Default for KeyboardMapping {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl KeyboardMapping {
+    // The standard mapping: the scale's root on C4 (60), with A4 (69) at
+    // 440 Hz.
+    pub fn new() -> KeyboardMapping {
+        KeyboardMapping::linear(60, A4_NOTE, A4_FREQUENCY)
+    }
+    // Consecutive keys play consecutive degrees, with the root on
+    // middle_note.
+    pub fn linear(
+        middle_note: u8,
+        reference_note: u8,
+        reference_frequency: f64,
+    ) -> KeyboardMapping {
+        KeyboardMapping {
+            first_note: 0,
+            last_note: 127,
+            middle_note,
+            reference_note,
+            reference_frequency,
+            octave_degree: 0,
+            keys: Vec::new(),
+        }
+    }
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<KeyboardMapping, TuningError> {
+        KeyboardMapping::parse(&fs::read_to_string(path)?)
+    }
+    pub fn parse(text: &str) -> Result<KeyboardMapping, TuningError> {
+        let mut lines = scala_lines(text).filter(|(_, line)| !line.trim().is_empty());
+        let mut header = [0.0f64; 7];
+        for value in header.iter_mut() {
+            let (line, text) = lines
+                .next()
+                .ok_or(TuningError::Invalid("incomplete keyboard mapping header"))?;
+            *value = first_field(text)
+                .parse()
+                .map_err(|_| parse_error(line, "expected a number"))?;
+        }
+        let [size, first_note, last_note, middle_note, reference_note, frequency, octave_degree] =
+            header;
+        let notes = [first_note, last_note, middle_note, reference_note];
+        if notes.iter().any(|note| !is_whole(*note, 127.0)) {
+            return Err(TuningError::Invalid("note number out of range"));
+        }
+        if !frequency.is_finite() || frequency <= 0.0 {
+            return Err(TuningError::Invalid("reference frequency must be positive"));
+        }
+        if !is_whole(size, MAX_FILE_COUNT as f64) {
+            return Err(TuningError::Invalid("mapping size out of range"));
+        }
+        if !is_whole(octave_degree, MAX_FILE_COUNT as f64) {
+            return Err(TuningError::Invalid("octave degree out of range"));
+        }
+        // Keys missing from the end of the file are unmapped.
+        let mut keys = vec![None; size as usize];
+        for key in keys.iter_mut() {
+            let (line, text) = match lines.next() {
+                Some(line) => line,
+                None => break,
+            };
+            *key = match first_field(text) {
+                "x" | "X" => None,
+                degree => Some(
+                    degree
+                        .parse()
+                        .map_err(|_| parse_error(line, "expected a scale degree or x"))?,
+                ),
+            };
+        }
+        Ok(KeyboardMapping {
+            first_note: first_note as u8,
+            last_note: last_note as u8,
+            middle_note: middle_note as u8,
+            reference_note: reference_note as u8,
+            reference_frequency: frequency,
+            octave_degree: octave_degree as usize,
+            keys,
+        })
+    }
+    // Scale degree a note plays, or None if it isn't mapped.
+    pub fn degree(&self, note: u8, scale: &Scale) -> Option<i32> {
+        let step = note as i32 - self.middle_note as i32;
+        if self.keys.is_empty() {
+            return Some(step);
+        }
+        let size = self.keys.len() as i32;
+        let key = self.keys[step.rem_euclid(size) as usize]?;
+        let octave_degree = if self.octave_degree == 0 {
+            scale.len()
+        } else {
+            self.octave_degree
+        };
+        Some((step.div_euclid(size) * octave_degree as i32).saturating_add(key))
+    }
+}
+
+// A scale placed on the keyboard by a mapping.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Tuning {
+    pub scale: Scale,
+    pub mapping: KeyboardMapping,
+}
+
+impl Default for Tuning {
+    fn default() -> Self {
+        Self::new(Scale::equal_temperament(12, 1200.0), KeyboardMapping::new())
+    }
+}
+
+impl Tuning {
+    pub fn new(scale: Scale, mapping: KeyboardMapping) -> Tuning {
+        Tuning { scale, mapping }
+    }
+    // Twelve-tone equal temperament with A4 at the given frequency, such as
+    // 432 or 442 Hz.
+    pub fn equal_temperament(a4_frequency: f64) -> Tuning {
+        Tuning::new(
+            Scale::equal_temperament(12, 1200.0),
+            KeyboardMapping::linear(60, A4_NOTE, a4_frequency),
+        )
+    }
+    pub fn open<P: AsRef<Path>, Q: AsRef<Path>>(
+        scl_path: P,
+        kbm_path: Q,
+    ) -> Result<Tuning, TuningError> {
+        Ok(Tuning::new(
+            Scale::open(scl_path)?,
+            KeyboardMapping::open(kbm_path)?,
+        ))
+    }
+    // Frequency of a note in Hz, or None if the note isn't mapped.
+    pub fn frequency(&self, note: u8) -> Option<f64> {
+        let mapping = &self.mapping;
+        if note < mapping.first_note || note > mapping.last_note {
+            return None;
+        }
+        let cents = self.scale.cents(mapping.degree(note, &self.scale)?);
+        Some(mapping.reference_frequency * 2f64.powf((cents - self.reference_cents()?) / 1200.0))
+    }
+    // Pitch of the reference note relative to the scale's root. The
+    // reference note may be outside the retuned range, but must be mapped.
+    fn reference_cents(&self) -> Option<f64> {
+        let degree = self
+            .mapping
+            .degree(self.mapping.reference_note, &self.scale)?;
+        Some(self.scale.cents(degree))
+    }
+    // The frequency of each MIDI note. Unmapped notes are 0.0 Hz, and are
+    // not played by the synths.
+    pub fn make_table(&self) -> Result<Vec<f32>, TuningError> {
+        if self.scale.is_empty() {
+            return Err(TuningError::Invalid("scale has no notes"));
+        }
+        if self.reference_cents().is_none() {
+            return Err(TuningError::Invalid("reference note is not mapped"));
+        }
+        Ok((0..TABLE_SIZE)
+            .map(|note| self.frequency(note as u8).unwrap_or(0.0) as f32)
+            .collect())
+    }
+}
+
+// Check that a frequency table has an entry for each MIDI note, for the
+// synths' set_tuning_table.
+pub fn check_table(table: Vec<f32>) -> Result<Vec<f32>, TuningError> {
+    if table.len() != TABLE_SIZE {
+        return Err(TuningError::Invalid("tuning table must have 128 notes"));
+    }
+    Ok(table)
+}
+
+// Frequency of a note in twelve-tone equal temperament, with A4 at
+// A4_FREQUENCY. The note may be fractional.
+fn equal_frequency(note: f64) -> f64 {
+    A4_FREQUENCY * 2f64.powf((note - A4_NOTE as f64) / 12.0)
+}
+
+// Apply a MIDI Tuning Standard SysEx message to a frequency table, returning
+// true if it was a tuning message. The message may include or omit the
+// leading 0xF0 and the trailing 0xF7. Device IDs, tuning programs and banks
+// are ignored: every message retunes the given table.
+//
+// Handles bulk tuning dumps, single note tuning changes (with and without a
+// bank), and scale/octave tuning in the one- and two-byte forms. Scale/octave
+// tuning retunes every note relative to equal temperament.
+pub fn apply_mts(table: &mut [f32], message: &[u8]) -> bool {
+    let mut data = message;
+    if data.first() == Some(&0xf0) {
+        data = &data[1..];
+    }
+    if data.last() == Some(&0xf7) {
+        data = &data[..data.len() - 1];
+    }
+    // Universal non-realtime (0x7E) or realtime (0x7F), a device ID, then
+    // sub-ID 0x08 for MIDI Tuning Standard
+    if data.len() < 4 || (data[0] != 0x7e && data[0] != 0x7f) || data[2] != 0x08 {
+        return false;
+    }
+    let body = &data[4..];
+    match data[3] {
+        // Bulk dump: program, 16-byte name, then three bytes per note
+        0x01 => {
+            let notes = match body.get(17..17 + TABLE_SIZE * 3) {
+                Some(notes) => notes,
+                None => return false,
+            };
+            for (note, frequency) in notes.chunks(3).enumerate() {
+                set_mts_frequency(table, note, frequency);
+            }
+            true
+        }
+        // Single note tuning change: program, then a count of changes
+        0x02 => apply_note_changes(table, body.get(1..)),
+        // Single note tuning change with bank: bank, program, then a count
+        0x07 => apply_note_changes(table, body.get(2..)),
+        // Scale/octave tuning: three channel mask bytes, then an offset for
+        // each of the twelve pitch classes, from C
+        0x08 | 0x09 => {
+            let two_byte = data[3] == 0x09;
+            let offsets = match body.get(3..3 + if two_byte { 24 } else { 12 }) {
+                Some(offsets) => offsets,
+                None => return false,
+            };
+            let cents: Vec<f64> = if two_byte {
+                // 14 bits, from -100 to +100 cents, with 0x2000 as 0
+                offsets
+                    .chunks(2)
+                    .map(|pair| {
+                        let value = (pair[0] as i32) << 7 | pair[1] as i32;
+                        (value - 0x2000) as f64 * 100.0 / 8192.0
+                    })
+                    .collect()
+            } else {
+                // From -64 to +63 cents, with 0x40 as 0
+                offsets.iter().map(|&value| value as f64 - 64.0).collect()
+            };
+            for (note, frequency) in table.iter_mut().enumerate() {
+                let offset = cents[note % 12];
+                *frequency = equal_frequency(note as f64 + offset / 100.0) as f32;
+            }
+            true
+        }
+        _ => false,
+    }
+}
+
+fn apply_note_changes(table: &mut [f32], body: Option<&[u8]>) -> bool {
+    let body = match body {
+        Some(body) if !body.is_empty() => body,
+        _ => return false,
+    };
+    let count = body[0] as usize;
+    for change in body[1..].chunks_exact(4).take(count) {
+        set_mts_frequency(table, change[0] as usize, &change[1..]);
+    }
+    true
+}
+
+// MTS frequency data: the equal-tempered note at or below the frequency,
+// then a 14-bit fraction of a semitone. 0x7F 0x7F 0x7F means no change.
+fn set_mts_frequency(table: &mut [f32], note: usize, data: &[u8]) {
+    if note >= table.len() || data == [0x7f, 0x7f, 0x7f] {
+        return;
+    }
+    let fraction = ((data[1] as u32) << 7 | data[2] as u32) as f64 / 16384.0;
+    table[note] = equal_frequency(data[0] as f64 + fraction) as f32;
+}
+
+// Non-comment lines of a Scala file, with line numbers. Comment lines start
+// with '!'.
+fn scala_lines(text: &str) -> impl Iterator<Item = (usize, &str)> {
+    text.lines()
+        .enumerate()
+        .map(|(i, line)| (i + 1, line))
+        .filter(|(_, line)| !line.starts_with('!'))
+}
+
+// Values may be followed by any text, which is ignored.
+fn first_field(line: &str) -> &str {
+    line.split_whitespace().next().unwrap_or("")
+}
+
+fn parse_error(line: usize, reason: &'static str) -> TuningError {
+    TuningError::Parse { line, reason }
+}
+
+// Whether a header value is a whole number from 0 to max.
+fn is_whole(value: f64, max: f64) -> bool {
+    value.is_finite() && value.fract() == 0.0 && value >= 0.0 && value <= max
+}
+
+// Values with a period are in cents. Others are ratios, such as 3/2, or whole
+// numbers, such as 2.
+fn parse_pitch(text: &str) -> Result<f64, &'static str> {
+    if text.contains('.') {
+        let cents: f64 = text.parse().map_err(|_| "invalid cents value")?;
+        if !cents.is_finite() {
+            return Err("invalid cents value");
+        }
+        return Ok(cents);
+    }
+    let (numerator, denominator) = match text.find('/') {
+        Some(i) => (&text[..i], &text[i + 1..]),
+        None => (text, "1"),
+    };
+    let numerator: f64 = numerator.parse::<u64>().map_err(|_| "invalid ratio")? as f64;
+    let denominator: f64 = denominator.parse::<u64>().map_err(|_| "invalid ratio")? as f64;
+    if numerator == 0.0 || denominator == 0.0 {
+        return Err("ratio must be positive");
+    }
+    Ok(1200.0 * (numerator / denominator).log2())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::midi::make_midi_freq_table;
+
+    fn assert_close(actual: f32, expected: f64) {
+        let error = (actual as f64 / expected - 1.0).abs();
+        assert!(error < 1e-6, "expected {}, got {}", expected, actual);
+    }
+
+    // A mapping with the given keys, the root on C4 and A4 at 440 Hz
+    fn kbm(keys: &str) -> String {
+        let size = keys.split_whitespace().count();
+        format!(
+            "! test.kbm\n{}\n0\n127\n60\n69\n440.0\n12\n{}\n",
+            size,
+            keys.split_whitespace().collect::<Vec<_>>().join("\n")
+        )
+    }
+
+    #[test]
+    fn equal_temperament_matches_midi_table() {
+        let table = Tuning::default().make_table().unwrap();
+        for (actual, expected) in table.iter().zip(make_midi_freq_table().iter()) {
+            assert_close(*actual, *expected as f64);
+        }
+    }
+
+    #[test]
+    fn reference_pitch() {
+        let table = Tuning::equal_temperament(432.0).make_table().unwrap();
+        assert_close(table[69], 432.0);
+        assert_close(table[81], 864.0);
+        assert_close(table[60], 432.0 * 2f64.powf(-9.0 / 12.0));
+    }
+
+    #[test]
+    fn unmapped_keys_are_zero() {
+        let mapping = KeyboardMapping::parse(&kbm("0 x 2 x 4 5 x 7 x 9 x 11")).unwrap();
+        let tuning = Tuning::new(Scale::equal_temperament(12, 1200.0), mapping);
+        let table = tuning.make_table().unwrap();
+        let midi_table = make_midi_freq_table();
+        for note in 0..TABLE_SIZE {
+            if [1, 3, 6, 8, 10].contains(&(note % 12)) {
+                assert_eq!(table[note], 0.0, "note {}", note);
+            } else {
+                assert_close(table[note], midi_table[note] as f64);
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_bad_mapping_headers() {
+        let header = |values: [&str; 7]| values.join("\n");
+        let valid = ["0", "0", "127", "60", "69", "440.0", "0"];
+        assert!(KeyboardMapping::parse(&header(valid)).is_ok());
+        let bad = [
+            (5, "NaN"),
+            (5, "inf"),
+            (5, "0"),
+            (3, "60.5"),
+            (4, "128"),
+            (1, "-1"),
+            (0, "1e18"),
+            (0, "12.5"),
+            (6, "inf"),
+        ];
+        for (index, value) in bad.iter() {
+            let mut values = valid;
+            values[*index] = value;
+            assert!(
+                KeyboardMapping::parse(&header(values)).is_err(),
+                "{} accepted at line {}",
+                value,
+                index
+            );
+        }
+    }
+
+    #[test]
+    fn parse_scale() {
+        let text = "! example.scl\n!\n\n 6\n!\n 9/8\n 5/4 major third\n -5.0\n 701.955\n 3/2\n 2\n";
+        let scale = Scale::parse(text).unwrap();
+        assert_eq!(scale.description, "");
+        assert_eq!(scale.len(), 6);
+        let expected = [203.910, 386.314, -5.0, 701.955, 701.955, 1200.0];
+        for (actual, expected) in scale.degrees.iter().zip(expected.iter()) {
+            assert!(
+                (actual - expected).abs() < 1e-3,
+                "{} != {}",
+                actual,
+                expected
+            );
+        }
+        assert_eq!(scale.period(), 1200.0);
+        assert!((scale.cents(7) - 1403.910).abs() < 1e-3);
+        assert!((scale.cents(-1) + 498.045).abs() < 1e-3);
+        assert!(Scale::parse("short\n3\n100.0\n").is_err());
+        assert!(Scale::parse("bad\n1\n3/0\n").is_err());
+    }
+
+    #[test]
+    fn octave_degree_differs_from_scale_length() {
+        // Three keys per repeat, climbing five semitones each time
+        let text = "! fourths.kbm\n3\n0\n127\n60\n60\n261.6255653006\n5\n0\n2\n4\n";
+        let mapping = KeyboardMapping::parse(text).unwrap();
+        assert_eq!(mapping.octave_degree, 5);
+        let tuning = Tuning::new(Scale::equal_temperament(12, 1200.0), mapping);
+        let table = tuning.make_table().unwrap();
+        let midi_table = make_midi_freq_table();
+        assert_close(table[60], midi_table[60] as f64);
+        assert_close(table[61], midi_table[62] as f64);
+        assert_close(table[62], midi_table[64] as f64);
+        assert_close(table[63], midi_table[65] as f64);
+        assert_close(table[66], midi_table[70] as f64);
+        assert_close(table[59], midi_table[59] as f64);
+        assert_close(table[57], midi_table[55] as f64);
+    }
+
+    #[test]
+    fn huge_scale_count_is_an_error() {
+        let result = Scale::parse("huge\n18446744073709551615\n100.0\n");
+        assert!(matches!(result, Err(TuningError::Invalid(_))));
+    }
+
+    #[test]
+    fn mts_single_note() {
+        let mut table = make_midi_freq_table();
+        // Note 60 to half a semitone above A4, and note 61 unchanged
+        let message = [
+            0xf0, 0x7f, 0x00, 0x08, 0x02, 0x00, 0x02, 60, 69, 0x40, 0x00, 61, 0x7f, 0x7f, 0x7f,
+            0xf7,
+        ];
+        assert!(apply_mts(&mut table, &message));
+        assert_close(table[60], 440.0 * 2f64.powf(0.5 / 12.0));
+        assert_close(table[61], 277.182_630_976_9);
+        assert_close(table[69], 440.0);
+    }
+
+    #[test]
+    fn mts_scale_octave() {
+        let mut table = make_midi_freq_table();
+        // C +10 cents, A -20 cents
+        let mut message = vec![0xf0, 0x7e, 0x7f, 0x08, 0x08, 0x03, 0x7f, 0x7f];
+        message.extend_from_slice(&[0x4a, 64, 64, 64, 64, 64, 64, 64, 64, 0x2c, 64, 64]);
+        message.push(0xf7);
+        assert!(apply_mts(&mut table, &message));
+        for octave in 0..10 {
+            let c = octave * 12;
+            assert_close(table[c], equal_frequency(c as f64 + 0.1));
+            assert_close(table[c + 9], equal_frequency(c as f64 + 9.0 - 0.2));
+            assert_close(table[c + 4], equal_frequency(c as f64 + 4.0));
+        }
+        assert_close(table[69], 440.0 * 2f64.powf(-20.0 / 1200.0));
+
+        // The two-byte form, with A at +50 cents
+        let mut message = vec![0xf0, 0x7e, 0x7f, 0x08, 0x09, 0x03, 0x7f, 0x7f];
+        for pitch_class in 0..12 {
+            if pitch_class == 9 {
+                message.extend_from_slice(&[0x60, 0x00]);
+            } else {
+                message.extend_from_slice(&[0x40, 0x00]);
+            }
+        }
+        message.push(0xf7);
+        assert!(apply_mts(&mut table, &message));
+        assert_close(table[69], 440.0 * 2f64.powf(50.0 / 1200.0));
+        assert_close(table[60], 261.625_565_300_6);
+    }
+}
@@ -0,0 +1,460 @@
+use crate::constants::TWO_PI;
+use crate::fft::{Complex, Stft, Window};
+use crate::processor::Processor;
+
+// Phase vocoder time-stretching and pitch-shifting, for monophonic and
+// polyphonic material.
+//
+// Both APIs use identity phase locking (Laroche & Dolson, "Improved Phase
+// Vocoder Time-Scale Modification of Audio", 1999). Only spectral peaks get
+// their phase from the usual phase vocoder frequency estimate. The bins around
+// each peak keep their phase relative to it, which preserves the shape of
+// each partial and greatly reduces the "phasiness" of the plain phase
+// vocoder.
+
+// Wrap a phase to the range -PI to PI.
+fn wrap_phase(phase: f32) -> f32 {
+    phase - TWO_PI * (phase / TWO_PI).round()
+}
+
+// Find the local maxima of a magnitude spectrum, and the peak that owns each
+// bin. Each bin belongs to the nearest peak, so regions split halfway
+// between peaks.
+fn find_peaks(magnitudes: &[f32], peaks: &mut Vec<usize>, owners: &mut [usize]) {
+    peaks.clear();
+    let bins = magnitudes.len();
+    for k in 1..bins.saturating_sub(1) {
+        if magnitudes[k] > magnitudes[k - 1] && magnitudes[k] >= magnitudes[k + 1] {
+            peaks.push(k);
+        }
+    }
+    if peaks.is_empty() {
+        // No peaks (e.g. silence), so every bin is its own peak.
+        peaks.extend(0..bins);
+    }
+    let mut peak = 0;
+    for (k, owner) in owners.iter_mut().enumerate() {
+        let distance = |p: usize| (p as isize - k as isize).abs();
+        while peak + 1 < peaks.len() && distance(peaks[peak + 1]) < distance(peaks[peak]) {
+            peak += 1;
+        }
+        *owner = peaks[peak];
+    }
+}
+
+// Estimate the frequency of a bin in radians per sample, from its phase
+// advance over hop samples.
+fn bin_frequency(bin: usize, phase: f32, previous_phase: f32, fft_size: usize, hop: f32) -> f32 {
+    let bin_freq = TWO_PI * bin as f32 / fft_size as f32;
+    if hop <= 0.0 {
+        return bin_freq;
+    }
+    bin_freq + wrap_phase(phase - previous_phase - bin_freq * hop) / hop
+}
+
+// Cubic Hermite interpolation into a signal at a fractional position.
+fn read_cubic(signal: &[f32], position: f32) -> f32 {
+    let index = position.floor();
+    let x = position - index;
+    let index = index as isize;
+    let at = |i: isize| -> f32 {
+        if i < 0 || i as usize >= signal.len() {
+            0.0
+        } else {
+            signal[i as usize]
+        }
+    };
+    let y0 = at(index - 1);
+    let y1 = at(index);
+    let y2 = at(index + 1);
+    let y3 = at(index + 2);
+    let c1 = 0.5 * (y2 - y0);
+    let c2 = y0 - 2.5 * y1 + 2.0 * y2 - 0.5 * y3;
+    let c3 = 0.5 * (y3 - y0) + 1.5 * (y1 - y2);
+    ((c3 * x + c2) * x + c1) * x + y1
+}
+
+// Offline time-stretching and pitch-shifting of whole buffers.
+//
+// The input is time-stretched by time_ratio * pitch_ratio with a fixed
+// synthesis hop and a variable analysis hop, then resampled by pitch_ratio,
+// so that the length changes by time_ratio and the pitch by pitch_ratio.
+pub struct PhaseVocoder {
+    stft: Stft,
+}
+
+impl Default for PhaseVocoder {
+    fn default() -> Self {
+        PhaseVocoder::new(2048)
+    }
+}
+
+impl PhaseVocoder {
+    // fft_size must be a power of two. Larger sizes give better frequency
+    // resolution, for low or polyphonic material, and smear transients more.
+    pub fn new(fft_size: usize) -> PhaseVocoder {
+        PhaseVocoder {
+            stft: Stft::new(fft_size, fft_size / 4, Window::Hann),
+        }
+    }
+    // time_ratio is the output length relative to the input, and pitch_ratio
+    // the frequency ratio (see conversion::semitones_to_ratio).
+    pub fn process(&mut self, input: &[f32], time_ratio: f32, pitch_ratio: f32) -> Vec<f32> {
+        let stretch = (time_ratio * pitch_ratio).max(1e-3);
+        let stretched = self.stretch(input, stretch);
+        let length = (input.len() as f32 * time_ratio).round() as usize;
+        (0..length)
+            .map(|n| read_cubic(&stretched, n as f32 * pitch_ratio))
+            .collect()
+    }
+    pub fn time_stretch(&mut self, input: &[f32], time_ratio: f32) -> Vec<f32> {
+        self.process(input, time_ratio, 1.0)
+    }
+    pub fn pitch_shift(&mut self, input: &[f32], pitch_ratio: f32) -> Vec<f32> {
+        self.process(input, 1.0, pitch_ratio)
+    }
+    fn stretch(&mut self, input: &[f32], stretch: f32) -> Vec<f32> {
+        let fft_size = self.stft.fft_size();
+        let hop = self.stft.hop_size();
+        let bins = self.stft.bins();
+        let length = (input.len() as f32 * stretch).ceil() as usize;
+        let frame_count = length / hop + 1;
+
+        let mut output = vec![0.0; length];
+        let mut window_sum = vec![0.0; length];
+        let mut frame = vec![0.0; fft_size];
+        let mut spectrum = vec![Complex::default(); bins];
+        let mut magnitudes = vec![0.0; bins];
+        let mut phases = vec![0.0; bins];
+        let mut previous_phases = vec![0.0; bins];
+        let mut synth_phases = vec![0.0; bins];
+        let mut peaks = Vec::with_capacity(bins);
+        let mut owners = vec![0; bins];
+        let mut previous_position = 0;
+
+        for t in 0..frame_count {
+            // Analysis frames are centred on their positions, like Stft.
+            let position = ((t * hop) as f32 / stretch).round() as isize;
+            let start = position - (fft_size / 2) as isize;
+            for (i, sample) in frame.iter_mut().enumerate() {
+                let index = start + i as isize;
+                *sample = if index >= 0 && (index as usize) < input.len() {
+                    input[index as usize]
+                } else {
+                    0.0
+                };
+            }
+            self.stft.analyze_frame(&frame, &mut spectrum);
+            for (k, bin) in spectrum.iter().enumerate() {
+                magnitudes[k] = bin.norm();
+                phases[k] = bin.arg();
+            }
+
+            if t == 0 {
+                synth_phases.copy_from_slice(&phases);
+            } else {
+                let analysis_hop = (position - previous_position) as f32;
+                find_peaks(&magnitudes, &mut peaks, &mut owners);
+                for &p in peaks.iter() {
+                    let freq =
+                        bin_frequency(p, phases[p], previous_phases[p], fft_size, analysis_hop);
+                    synth_phases[p] += freq * hop as f32;
+                }
+                // Lock the other bins to their peaks.
+                for k in 0..bins {
+                    let p = owners[k];
+                    if p != k {
+                        synth_phases[k] = synth_phases[p] + phases[k] - phases[p];
+                    }
+                }
+            }
+            for k in 0..bins {
+                synth_phases[k] = wrap_phase(synth_phases[k]);
+                spectrum[k] = Complex::from_polar(magnitudes[k], synth_phases[k]);
+            }
+            previous_phases.copy_from_slice(&phases);
+            previous_position = position;
+
+            self.stft.synthesize_frame(&spectrum, &mut frame);
+            let start = (t * hop) as isize - (fft_size / 2) as isize;
+            for (i, (sample, window)) in frame.iter().zip(self.stft.window()).enumerate() {
+                let index = start + i as isize;
+                if index >= 0 && (index as usize) < length {
+                    output[index as usize] += sample;
+                    window_sum[index as usize] += window * window;
+                }
+            }
+        }
+        for (sample, sum) in output.iter_mut().zip(window_sum) {
+            if sum > 1e-6 {
+                *sample /= sum;
+            }
+        }
+        output
+    }
+}
+
+// Real-time pitch-shifting at a constant tempo. Each frame's spectral peaks
+// are moved to their shifted frequencies, carrying the bins around them
+// along, so phase locking also applies here (Laroche & Dolson, "New Phase
+// Vocoder Techniques for Pitch-Shifting, Harmonizing and Other Exotic
+// Effects", 1999).
+//
+// The output is delayed by latency() samples. Processing happens once per
+// hop, and never allocates.
+pub struct StreamingPhaseVocoder {
+    stft: Stft,
+    // The most recent fft_size input samples, oldest first
+    input: Vec<f32>,
+    // Overlap-add accumulator, with the next output sample first
+    accumulator: Vec<f32>,
+    output: Vec<f32>,
+    // Position in the current hop
+    position: usize,
+    // Scale for the summed squared windows
+    norm: f32,
+    pitch_ratio: f32,
+    frame: Vec<f32>,
+    spectrum: Vec<Complex>,
+    shifted: Vec<Complex>,
+    magnitudes: Vec<f32>,
+    phases: Vec<f32>,
+    previous_phases: Vec<f32>,
+    synth_phases: Vec<f32>,
+    peaks: Vec<usize>,
+    owners: Vec<usize>,
+}
+
+impl StreamingPhaseVocoder {
+    // fft_size must be a power of two. The hop is a quarter of it.
+    pub fn new(fft_size: usize) -> StreamingPhaseVocoder {
+        let stft = Stft::new(fft_size, fft_size / 4, Window::Hann);
+        let hop = stft.hop_size();
+        let bins = stft.bins();
+        let norm = hop as f32 / stft.window().iter().map(|w| w * w).sum::<f32>();
+        StreamingPhaseVocoder {
+            stft,
+            input: vec![0.0; fft_size],
+            accumulator: vec![0.0; fft_size],
+            output: vec![0.0; hop],
+            position: 0,
+            norm,
+            pitch_ratio: 1.0,
+            frame: vec![0.0; fft_size],
+            spectrum: vec![Complex::default(); bins],
+            shifted: vec![Complex::default(); bins],
+            magnitudes: vec![0.0; bins],
+            phases: vec![0.0; bins],
+            previous_phases: vec![0.0; bins],
+            synth_phases: vec![0.0; bins],
+            peaks: Vec::with_capacity(bins),
+            owners: vec![0; bins],
+        }
+    }
+    // Delay between input and output, in samples
+    pub fn latency(&self) -> usize {
+        self.stft.fft_size()
+    }
+    // Store parameters for processing through the Processor trait.
+    pub fn set_params(&mut self, pitch_ratio: f32) {
+        self.pitch_ratio = pitch_ratio;
+    }
+    // pitch_ratio is read once per hop.
+    pub fn tick(&mut self, input_sample: f32, pitch_ratio: f32) -> f32 {
+        self.pitch_ratio = pitch_ratio;
+        let hop = self.output.len();
+        let output = self.output[self.position];
+        let fft_size = self.input.len();
+        self.input[fft_size - hop + self.position] = input_sample;
+        self.position += 1;
+        if self.position == hop {
+            self.position = 0;
+            self.process_frame();
+            self.input.copy_within(hop.., 0);
+        }
+        output
+    }
+    pub fn process_block(&mut self, buffer: &mut [f32], pitch_ratio: f32) {
+        for sample in buffer.iter_mut() {
+            *sample = self.tick(*sample, pitch_ratio);
+        }
+    }
+    fn process_frame(&mut self) {
+        let fft_size = self.input.len();
+        let hop = self.output.len() as f32;
+        let bins = self.spectrum.len();
+        self.stft.analyze_frame(&self.input, &mut self.spectrum);
+        for (k, bin) in self.spectrum.iter().enumerate() {
+            self.magnitudes[k] = bin.norm();
+            self.phases[k] = bin.arg();
+        }
+        find_peaks(&self.magnitudes, &mut self.peaks, &mut self.owners);
+
+        for bin in self.shifted.iter_mut() {
+            *bin = Complex::default();
+        }
+        for &p in self.peaks.iter() {
+            let freq = bin_frequency(p, self.phases[p], self.previous_phases[p], fft_size, hop)
+                * self.pitch_ratio;
+            let target = (p as f32 * self.pitch_ratio).round() as isize;
+            if target < 0 || target as usize >= bins {
+                continue;
+            }
+            let target = target as usize;
+            self.synth_phases[target] = wrap_phase(self.synth_phases[target] + freq * hop);
+            let rotation = Complex::from_polar(1.0, self.synth_phases[target] - self.phases[p]);
+            let shift = target as isize - p as isize;
+            // Move the peak's region, keeping its bins' relative phases.
+            for k in 0..bins {
+                if self.owners[k] != p {
+                    continue;
+                }
+                let destination = k as isize + shift;
+                if destination >= 0 && (destination as usize) < bins {
+                    self.shifted[destination as usize] += self.spectrum[k] * rotation;
+                }
+            }
+        }
+        for (phase, bin) in self.synth_phases.iter_mut().zip(self.shifted.iter()) {
+            if bin.re != 0.0 || bin.im != 0.0 {
+                *phase = bin.arg();
+            }
+        }
+        self.previous_phases.copy_from_slice(&self.phases);
+
+        self.stft.synthesize_frame(&self.shifted, &mut self.frame);
+        for (sum, sample) in self.accumulator.iter_mut().zip(self.frame.iter()) {
+            *sum += sample * self.norm;
+        }
+        let hop = self.output.len();
+        self.output.copy_from_slice(&self.accumulator[..hop]);
+        self.accumulator.copy_within(hop.., 0);
+        for sum in self.accumulator[fft_size - hop..].iter_mut() {
+            *sum = 0.0;
+        }
+    }
+}
+
+impl Processor for StreamingPhaseVocoder {
+    fn prepare(&mut self, _sample_rate: u32, _max_block: usize) {}
+    fn reset(&mut self) {
+        for buffer in [
+            &mut self.input,
+            &mut self.accumulator,
+            &mut self.output,
+            &mut self.previous_phases,
+            &mut self.synth_phases,
+        ] {
+            for sample in buffer.iter_mut() {
+                *sample = 0.0;
+            }
+        }
+        self.position = 0;
+    }
+    fn process(&mut self, input: f32) -> f32 {
+        self.tick(input, self.pitch_ratio)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fft::RealFft;
+
+    const SAMPLE_RATE: f32 = 44100.0;
+
+    fn sine(freq: f32, length: usize) -> Vec<f32> {
+        (0..length)
+            .map(|n| (TWO_PI * freq * n as f32 / SAMPLE_RATE).sin() * 0.5)
+            .collect()
+    }
+
+    // Bin with the most energy in a Hann-windowed frame
+    fn peak_bin(signal: &[f32], fft_size: usize) -> usize {
+        let window = crate::fft::make_window(Window::Hann, fft_size);
+        let frame: Vec<f32> = signal[..fft_size]
+            .iter()
+            .zip(window.iter())
+            .map(|(sample, window)| sample * window)
+            .collect();
+        let mut fft = RealFft::new(fft_size);
+        let mut spectrum = vec![Complex::default(); fft.bins()];
+        fft.forward(&frame, &mut spectrum);
+        (0..spectrum.len())
+            .max_by(|a, b| {
+                spectrum[*a]
+                    .norm()
+                    .partial_cmp(&spectrum[*b].norm())
+                    .unwrap()
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn unity_ratios_reconstruct() {
+        let input = sine(441.0, 8192);
+        let output = PhaseVocoder::new(1024).process(&input, 1.0, 1.0);
+        assert_eq!(output.len(), input.len());
+        let error = input
+            .iter()
+            .zip(output.iter())
+            .map(|(a, b)| (a - b).abs())
+            .fold(0.0, f32::max);
+        assert!(error < 1e-3, "error {}", error);
+    }
+
+    #[test]
+    fn time_stretch_length() {
+        let input = sine(441.0, 5000);
+        let output = PhaseVocoder::new(1024).time_stretch(&input, 2.0);
+        assert_eq!(output.len(), 10000);
+        // The pitch is unchanged.
+        assert_eq!(
+            peak_bin(&output[4000..], 4096),
+            peak_bin(&input[500..], 4096)
+        );
+    }
+
+    #[test]
+    fn pitch_shift_moves_the_peak() {
+        // 40 bins of a 4096-point FFT
+        let freq = 40.0 * SAMPLE_RATE / 4096.0;
+        let input = sine(freq, 16384);
+        let output = PhaseVocoder::new(2048).pitch_shift(&input, 1.5);
+        assert_eq!(output.len(), input.len());
+        assert_eq!(peak_bin(&input[6000..], 4096), 40);
+        assert_eq!(peak_bin(&output[6000..], 4096), 60);
+
+        let mut vocoder = StreamingPhaseVocoder::new(2048);
+        let mut streamed = input.clone();
+        vocoder.process_block(&mut streamed, 1.5);
+        assert_eq!(peak_bin(&streamed[8000..], 4096), 60);
+    }
+
+    #[test]
+    fn streaming_latency() {
+        let fft_size = 512;
+        // Two partials, so no delay but the right one lines them both up
+        let input: Vec<f32> = sine(441.0, 8192)
+            .iter()
+            .zip(sine(1013.0, 8192).iter())
+            .map(|(a, b)| a + b)
+            .collect();
+        let mut vocoder = StreamingPhaseVocoder::new(fft_size);
+        let output: Vec<f32> = input
+            .iter()
+            .map(|sample| vocoder.tick(*sample, 1.0))
+            .collect();
+        // The delay that best lines the output up with the input
+        let error = |delay: usize| -> f32 {
+            (4096..8192)
+                .map(|n| (output[n] - input[n - delay]).powi(2))
+                .sum()
+        };
+        let delay = (0..fft_size * 2)
+            .min_by(|a, b| error(*a).partial_cmp(&error(*b)).unwrap())
+            .unwrap();
+        assert_eq!(delay, vocoder.latency());
+        assert_eq!(vocoder.latency(), fft_size);
+    }
+}
@@ -0,0 +1,319 @@
+// Voice allocation for polyphonic instruments. The allocator only does the
+// bookkeeping of which note is playing in which voice slot. The instrument
+// owns the actual signal generators, and tells the allocator when a voice has
+// finished sounding.
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum StealPolicy {
+    Oldest,
+    Quietest,
+    Lowest,
+    Highest,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum AllocationKind {
+    // The voice was idle.
+    Free,
+    // The note was already sounding in this voice, and should be retriggered.
+    Retrigger,
+    // The voice was playing another note, which should be faded out quickly
+    // before the new note starts.
+    Stolen,
+}
+
+#[derive(Clone, Copy)]
+pub struct Allocation {
+    pub voice: usize,
+    pub kind: AllocationKind,
+}
+
+#[derive(Clone)]
+struct Slot {
+    note: u8,
+    is_active: bool,
+    // False once the note has been released, while the voice may still be
+    // sounding. Released voices are stolen before held ones.
+    is_held: bool,
+    // True while a stolen voice is fading out before its new note starts.
+    // Pending voices are only stolen again if every candidate is pending.
+    is_pending: bool,
+    age: u64,
+}
+
+pub struct VoiceAllocator {
+    slots: Vec<Slot>,
+    polyphony: usize,
+    policy: StealPolicy,
+    // Incremented on every allocation, used to find the oldest voice.
+    counter: u64,
+}
+
+impl VoiceAllocator {
+    // max_voices is the number of voice slots, which is also the upper limit
+    // for the polyphony setting. There is always at least one slot.
+    pub fn new(max_voices: usize, policy: StealPolicy) -> VoiceAllocator {
+        let max_voices = max_voices.max(1);
+        VoiceAllocator {
+            slots: vec![
+                Slot {
+                    note: 0,
+                    is_active: false,
+                    is_held: false,
+                    is_pending: false,
+                    age: 0,
+                };
+                max_voices
+            ],
+            polyphony: max_voices,
+            policy,
+            counter: 0,
+        }
+    }
+    pub fn max_voices(&self) -> usize {
+        self.slots.len()
+    }
+    pub fn polyphony(&self) -> usize {
+        self.polyphony
+    }
+    // Voices at or above the new limit are left to finish sounding, but are
+    // never allocated again.
+    pub fn set_polyphony(&mut self, polyphony: usize) {
+        self.polyphony = polyphony.clamp(1, self.slots.len());
+    }
+    pub fn set_policy(&mut self, policy: StealPolicy) {
+        self.policy = policy;
+    }
+    pub fn is_active(&self, voice: usize) -> bool {
+        self.slots[voice].is_active
+    }
+    // True until the note playing in this voice is released.
+    pub fn is_held(&self, voice: usize) -> bool {
+        self.slots[voice].is_active && self.slots[voice].is_held
+    }
+    pub fn note(&self, voice: usize) -> Option<u8> {
+        if self.slots[voice].is_active {
+            Some(self.slots[voice].note)
+        } else {
+            None
+        }
+    }
+    // Find a voice for a new note. levels holds the current output level of
+    // each voice, and is only used by the Quietest policy.
+    pub fn note_on(&mut self, note: u8, levels: &[f32]) -> Allocation {
+        self.counter += 1;
+        let (voice, kind) = match self.find_voice(note, levels) {
+            Some(allocation) => allocation,
+            None => (0, AllocationKind::Stolen),
+        };
+        let slot = &mut self.slots[voice];
+        slot.note = note;
+        slot.is_active = true;
+        slot.is_held = true;
+        slot.age = self.counter;
+        Allocation { voice, kind }
+    }
+    // Mark the voice playing this note as released, returning its index.
+    pub fn note_off(&mut self, note: u8) -> Option<usize> {
+        let voice = self
+            .slots
+            .iter()
+            .position(|slot| slot.is_active && slot.is_held && slot.note == note)?;
+        self.slots[voice].is_held = false;
+        Some(voice)
+    }
+    // Called by the instrument once a voice has finished sounding.
+    pub fn voice_finished(&mut self, voice: usize) {
+        self.slots[voice].is_active = false;
+        self.slots[voice].is_held = false;
+        self.slots[voice].is_pending = false;
+    }
+    // Instruments that fade out stolen voices before starting the new note
+    // mark the voice as pending until the note starts, so that the waiting
+    // note isn't replaced by another steal.
+    pub fn set_pending(&mut self, voice: usize, is_pending: bool) {
+        self.slots[voice].is_pending = is_pending;
+    }
+    fn find_voice(&self, note: u8, levels: &[f32]) -> Option<(usize, AllocationKind)> {
+        // Voices above the polyphony limit may still be sounding the note.
+        if let Some(voice) = self
+            .slots
+            .iter()
+            .position(|slot| slot.is_active && slot.note == note)
+        {
+            return Some((voice, AllocationKind::Retrigger));
+        }
+        let slots = &self.slots[..self.polyphony];
+        if let Some(voice) = slots.iter().position(|slot| !slot.is_active) {
+            return Some((voice, AllocationKind::Free));
+        }
+        // Prefer stealing voices that are already releasing, and never steal
+        // a voice whose note is still waiting to start unless there is no
+        // other choice.
+        let any_ready = slots.iter().any(|slot| !slot.is_pending);
+        let any_released = slots
+            .iter()
+            .any(|slot| !slot.is_held && (!any_ready || !slot.is_pending));
+        let candidates = slots.iter().enumerate().filter(|(_, slot)| {
+            (!any_ready || !slot.is_pending) && (!any_released || !slot.is_held)
+        });
+        let voice = match self.policy {
+            StealPolicy::Oldest => candidates.min_by_key(|(_, slot)| slot.age),
+            StealPolicy::Lowest => candidates.min_by_key(|(_, slot)| slot.note),
+            StealPolicy::Highest => candidates.max_by_key(|(_, slot)| slot.note),
+            StealPolicy::Quietest => candidates.min_by(|(a, _), (b, _)| {
+                let level_a = levels.get(*a).copied().unwrap_or(0.0);
+                let level_b = levels.get(*b).copied().unwrap_or(0.0);
+                level_a
+                    .partial_cmp(&level_b)
+                    .unwrap_or(std::cmp::Ordering::Equal)
+            }),
+        };
+        voice.map(|(voice, _)| (voice, AllocationKind::Stolen))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Fill every voice with the given notes, in order.
+    fn full_allocator(policy: StealPolicy, notes: &[u8]) -> VoiceAllocator {
+        let mut allocator = VoiceAllocator::new(notes.len(), policy);
+        for note in notes.iter() {
+            let allocation = allocator.note_on(*note, &[]);
+            assert!(allocation.kind == AllocationKind::Free);
+        }
+        allocator
+    }
+
+    #[test]
+    fn free_voices_are_used_first() {
+        let mut allocator = VoiceAllocator::new(4, StealPolicy::Oldest);
+        for (i, note) in [60, 62, 64, 65].iter().enumerate() {
+            let allocation = allocator.note_on(*note, &[]);
+            assert_eq!(allocation.voice, i);
+            assert!(allocation.kind == AllocationKind::Free);
+        }
+    }
+
+    #[test]
+    fn same_note_retriggers() {
+        let mut allocator = full_allocator(StealPolicy::Oldest, &[60, 62]);
+        let allocation = allocator.note_on(62, &[]);
+        assert_eq!(allocation.voice, 1);
+        assert!(allocation.kind == AllocationKind::Retrigger);
+    }
+
+    #[test]
+    fn retrigger_finds_voices_above_the_polyphony_limit() {
+        let mut allocator = full_allocator(StealPolicy::Oldest, &[60, 62, 64, 65]);
+        allocator.set_polyphony(2);
+        let allocation = allocator.note_on(65, &[]);
+        assert_eq!(allocation.voice, 3);
+        assert!(allocation.kind == AllocationKind::Retrigger);
+    }
+
+    #[test]
+    fn oldest_policy_steals_the_oldest_note() {
+        let mut allocator = full_allocator(StealPolicy::Oldest, &[64, 60, 67]);
+        let allocation = allocator.note_on(72, &[]);
+        assert_eq!(allocation.voice, 0);
+        assert!(allocation.kind == AllocationKind::Stolen);
+        assert_eq!(allocator.note_on(74, &[]).voice, 1);
+    }
+
+    #[test]
+    fn quietest_policy_steals_the_quietest_voice() {
+        let mut allocator = full_allocator(StealPolicy::Quietest, &[64, 60, 67]);
+        let allocation = allocator.note_on(72, &[0.8, 0.9, 0.1]);
+        assert_eq!(allocation.voice, 2);
+        assert!(allocation.kind == AllocationKind::Stolen);
+    }
+
+    #[test]
+    fn lowest_policy_steals_the_lowest_note() {
+        let mut allocator = full_allocator(StealPolicy::Lowest, &[64, 60, 67]);
+        let allocation = allocator.note_on(72, &[]);
+        assert_eq!(allocation.voice, 1);
+        assert!(allocation.kind == AllocationKind::Stolen);
+    }
+
+    #[test]
+    fn highest_policy_steals_the_highest_note() {
+        let mut allocator = full_allocator(StealPolicy::Highest, &[64, 60, 67]);
+        let allocation = allocator.note_on(72, &[]);
+        assert_eq!(allocation.voice, 2);
+        assert!(allocation.kind == AllocationKind::Stolen);
+    }
+
+    #[test]
+    fn released_voices_are_stolen_before_held_ones() {
+        for policy in [
+            StealPolicy::Oldest,
+            StealPolicy::Quietest,
+            StealPolicy::Lowest,
+            StealPolicy::Highest,
+        ]
+        .iter()
+        {
+            let mut allocator = full_allocator(*policy, &[64, 60, 67]);
+            assert_eq!(allocator.note_off(67), Some(2));
+            let allocation = allocator.note_on(72, &[0.1, 0.1, 0.9]);
+            assert_eq!(allocation.voice, 2);
+            assert!(allocation.kind == AllocationKind::Stolen);
+        }
+    }
+
+    #[test]
+    fn pending_voices_are_not_stolen_again() {
+        for policy in [
+            StealPolicy::Oldest,
+            StealPolicy::Quietest,
+            StealPolicy::Lowest,
+            StealPolicy::Highest,
+        ]
+        .iter()
+        {
+            let mut allocator = full_allocator(*policy, &[64, 60, 67]);
+            let first = allocator.note_on(72, &[0.5, 0.5, 0.5]).voice;
+            allocator.set_pending(first, true);
+            let second = allocator.note_on(74, &[0.5, 0.5, 0.5]).voice;
+            assert_ne!(first, second);
+            allocator.set_pending(second, true);
+            let third = allocator.note_on(76, &[0.5, 0.5, 0.5]).voice;
+            assert_ne!(third, first);
+            assert_ne!(third, second);
+        }
+    }
+
+    #[test]
+    fn pending_voices_are_stolen_when_there_is_no_other_choice() {
+        let mut allocator = full_allocator(StealPolicy::Oldest, &[60]);
+        allocator.note_on(62, &[]);
+        allocator.set_pending(0, true);
+        let allocation = allocator.note_on(64, &[]);
+        assert_eq!(allocation.voice, 0);
+        assert!(allocation.kind == AllocationKind::Stolen);
+    }
+
+    #[test]
+    fn finished_voices_become_free() {
+        let mut allocator = full_allocator(StealPolicy::Oldest, &[60, 62]);
+        allocator.note_off(60);
+        allocator.voice_finished(0);
+        let allocation = allocator.note_on(64, &[]);
+        assert_eq!(allocation.voice, 0);
+        assert!(allocation.kind == AllocationKind::Free);
+    }
+
+    #[test]
+    fn zero_voices_is_raised_to_one() {
+        let mut allocator = VoiceAllocator::new(0, StealPolicy::Oldest);
+        assert_eq!(allocator.max_voices(), 1);
+        allocator.set_polyphony(4);
+        assert_eq!(allocator.polyphony(), 1);
+        assert_eq!(allocator.note_on(60, &[]).voice, 0);
+        assert!(allocator.note_on(62, &[]).kind == AllocationKind::Stolen);
+    }
+}
@@ -0,0 +1,544 @@
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::wavetable::Wavetable;
+
+// Reading and writing WAV files, with no dependencies. Supports 8, 16, 24 and
+// 32-bit PCM and 32 and 64-bit float, any number of channels, and the smpl
+// (loop points) and cue chunks. Other chunks are skipped when reading.
+//
+// Samples are stored as interleaved f32, scaled to the range -1.0 to 1.0
+// whatever the file's format.
+
+#[derive(Debug)]
+pub enum WavError {
+    Io(io::Error),
+    // The data is not a well-formed WAV file.
+    Invalid(&'static str),
+    // The file is valid, but uses a format this module doesn't handle.
+    Unsupported(String),
+}
+
+impl fmt::Display for WavError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WavError::Io(error) => write!(f, "WAV I/O error: {}", error),
+            WavError::Invalid(reason) => write!(f, "invalid WAV file: {}", reason),
+            WavError::Unsupported(reason) => write!(f, "unsupported WAV file: {}", reason),
+        }
+    }
+}
+
+impl Error for WavError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            WavError::Io(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for WavError {
+    fn from(error: io::Error) -> Self {
+        WavError::Io(error)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum SampleFormat {
+    // Unsigned, as the WAV format requires for 8-bit samples
+    Pcm8,
+    Pcm16,
+    Pcm24,
+    Pcm32,
+    Float32,
+    Float64,
+}
+
+impl SampleFormat {
+    pub fn bytes_per_sample(&self) -> usize {
+        match self {
+            SampleFormat::Pcm8 => 1,
+            SampleFormat::Pcm16 => 2,
+            SampleFormat::Pcm24 => 3,
+            SampleFormat::Pcm32 | SampleFormat::Float32 => 4,
+            SampleFormat::Float64 => 8,
+        }
+    }
+    fn is_float(&self) -> bool {
+        matches!(self, SampleFormat::Float32 | SampleFormat::Float64)
+    }
+}
+
+// A sustain loop from the smpl chunk. Positions are in frames, and end is the
+// last frame of the loop.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Loop {
+    pub start: u32,
+    pub end: u32,
+    // 0 loops forever.
+    pub play_count: u32,
+}
+
+// A marker from the cue chunk, at a position in frames.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct CuePoint {
+    pub id: u32,
+    pub position: u32,
+}
+
+#[derive(Clone)]
+pub struct Wav {
+    pub sample_rate: u32,
+    pub channels: u16,
+    // Format the file was read from, and is written in
+    pub format: SampleFormat,
+    // Interleaved samples
+    pub samples: Vec<f32>,
+    pub loops: Vec<Loop>,
+    pub cues: Vec<CuePoint>,
+    // MIDI note the sample plays at its original pitch, from the smpl chunk.
+    // The smpl chunk always has a unity note, so a file written with loops
+    // but no unity note gets 60 (C4), and reads back as Some(60).
+    pub unity_note: Option<u8>,
+}
+
+const WAVE_FORMAT_PCM: u16 = 1;
+const WAVE_FORMAT_IEEE_FLOAT: u16 = 3;
+const WAVE_FORMAT_EXTENSIBLE: u16 = 0xfffe;
+
+impl Wav {
+    pub fn new(sample_rate: u32, channels: u16, format: SampleFormat) -> Wav {
+        Wav {
+            sample_rate,
+            channels: channels.max(1),
+            format,
+            samples: Vec::new(),
+            loops: Vec::new(),
+            cues: Vec::new(),
+            unity_note: None,
+        }
+    }
+    pub fn from_mono(samples: &[f32], sample_rate: u32, format: SampleFormat) -> Wav {
+        let mut wav = Wav::new(sample_rate, 1, format);
+        wav.samples = samples.to_vec();
+        wav
+    }
+    // Interleave equal-length channels. Shorter channels are padded with
+    // silence.
+    pub fn from_channels(channels: &[&[f32]], sample_rate: u32, format: SampleFormat) -> Wav {
+        let mut wav = Wav::new(sample_rate, channels.len() as u16, format);
+        let frames = channels.iter().map(|c| c.len()).max().unwrap_or(0);
+        wav.samples = (0..frames * channels.len())
+            .map(|i| {
+                let channel = channels[i % channels.len()];
+                channel.get(i / channels.len()).copied().unwrap_or(0.0)
+            })
+            .collect();
+        wav
+    }
+    pub fn frames(&self) -> usize {
+        self.samples.len() / self.channels as usize
+    }
+    pub fn channel(&self, index: usize) -> Vec<f32> {
+        self.samples
+            .iter()
+            .skip(index)
+            .step_by(self.channels as usize)
+            .copied()
+            .collect()
+    }
+    // Average of all channels
+    pub fn to_mono(&self) -> Vec<f32> {
+        let channels = self.channels as usize;
+        self.samples
+            .chunks(channels)
+            .map(|frame| frame.iter().sum::<f32>() / channels as f32)
+            .collect()
+    }
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Wav, WavError> {
+        Wav::from_bytes(&fs::read(path)?)
+    }
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), WavError> {
+        fs::write(path, self.to_bytes())?;
+        Ok(())
+    }
+    pub fn from_bytes(bytes: &[u8]) -> Result<Wav, WavError> {
+        if bytes.len() < 12 || &bytes[0..4] != b"RIFF" || &bytes[8..12] != b"WAVE" {
+            return Err(WavError::Invalid("missing RIFF/WAVE header"));
+        }
+        let mut wav: Option<Wav> = None;
+        let mut data: Option<&[u8]> = None;
+        let mut loops = Vec::new();
+        let mut cues = Vec::new();
+        let mut unity_note = None;
+
+        let mut position = 12;
+        while position + 8 <= bytes.len() {
+            let id = &bytes[position..position + 4];
+            let size = read_u32(bytes, position + 4) as usize;
+            let start = position + 8;
+            // Some writers get the size of the last chunk wrong, so trust the
+            // file length over it.
+            let end = start.saturating_add(size).min(bytes.len());
+            let chunk = &bytes[start..end];
+            match id {
+                b"fmt " => wav = Some(parse_fmt(chunk)?),
+                b"data" => data = Some(chunk),
+                b"smpl" if chunk.len() >= 36 => {
+                    unity_note = Some(read_u32(chunk, 12).min(127) as u8);
+                    loops = parse_loops(chunk);
+                }
+                b"cue " if chunk.len() >= 4 => cues = parse_cues(chunk),
+                _ => {}
+            }
+            // Chunks are padded to an even length.
+            position = start.saturating_add(size + (size & 1));
+        }
+
+        let mut wav = wav.ok_or(WavError::Invalid("missing fmt chunk"))?;
+        let data = data.ok_or(WavError::Invalid("missing data chunk"))?;
+        let width = wav.format.bytes_per_sample();
+        wav.samples = data
+            .chunks_exact(width)
+            .map(|bytes| decode_sample(bytes, wav.format))
+            .collect();
+        // Drop any partial frame at the end.
+        let whole = wav.frames() * wav.channels as usize;
+        wav.samples.truncate(whole);
+        wav.loops = loops;
+        wav.cues = cues;
+        wav.unity_note = unity_note;
+        Ok(wav)
+    }
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let width = self.format.bytes_per_sample();
+        let mut bytes = Vec::with_capacity(44 + self.samples.len() * width);
+        bytes.extend_from_slice(b"RIFF");
+        // RIFF size, filled in at the end
+        push_u32(&mut bytes, 0);
+        bytes.extend_from_slice(b"WAVE");
+
+        bytes.extend_from_slice(b"fmt ");
+        push_u32(&mut bytes, 16);
+        let tag = if self.format.is_float() {
+            WAVE_FORMAT_IEEE_FLOAT
+        } else {
+            WAVE_FORMAT_PCM
+        };
+        push_u16(&mut bytes, tag);
+        push_u16(&mut bytes, self.channels);
+        push_u32(&mut bytes, self.sample_rate);
+        let block_align = self.channels as usize * width;
+        push_u32(&mut bytes, (self.sample_rate as usize * block_align) as u32);
+        push_u16(&mut bytes, block_align as u16);
+        push_u16(&mut bytes, (width * 8) as u16);
+
+        bytes.extend_from_slice(b"data");
+        let data_size = self.samples.len() * width;
+        push_u32(&mut bytes, data_size as u32);
+        for sample in self.samples.iter() {
+            encode_sample(&mut bytes, *sample, self.format);
+        }
+        if data_size & 1 == 1 {
+            bytes.push(0);
+        }
+
+        if !self.loops.is_empty() || self.unity_note.is_some() {
+            bytes.extend_from_slice(b"smpl");
+            push_u32(&mut bytes, (36 + self.loops.len() * 24) as u32);
+            // Manufacturer and product
+            push_u32(&mut bytes, 0);
+            push_u32(&mut bytes, 0);
+            // Sample period in nanoseconds
+            push_u32(&mut bytes, (1e9 / self.sample_rate.max(1) as f64) as u32);
+            push_u32(&mut bytes, self.unity_note.unwrap_or(60) as u32);
+            // Pitch fraction, SMPTE format and offset
+            push_u32(&mut bytes, 0);
+            push_u32(&mut bytes, 0);
+            push_u32(&mut bytes, 0);
+            push_u32(&mut bytes, self.loops.len() as u32);
+            // Sampler data size
+            push_u32(&mut bytes, 0);
+            for (i, sample_loop) in self.loops.iter().enumerate() {
+                push_u32(&mut bytes, i as u32);
+                // Forward loop
+                push_u32(&mut bytes, 0);
+                push_u32(&mut bytes, sample_loop.start);
+                push_u32(&mut bytes, sample_loop.end);
+                // Fraction
+                push_u32(&mut bytes, 0);
+                push_u32(&mut bytes, sample_loop.play_count);
+            }
+        }
+
+        if !self.cues.is_empty() {
+            bytes.extend_from_slice(b"cue ");
+            push_u32(&mut bytes, (4 + self.cues.len() * 24) as u32);
+            push_u32(&mut bytes, self.cues.len() as u32);
+            for cue in self.cues.iter() {
+                push_u32(&mut bytes, cue.id);
+                push_u32(&mut bytes, cue.position);
+                bytes.extend_from_slice(b"data");
+                // Chunk start and block start, for uncompressed data
+                push_u32(&mut bytes, 0);
+                push_u32(&mut bytes, 0);
+                push_u32(&mut bytes, cue.position);
+            }
+        }
+
+        let riff_size = (bytes.len() - 8) as u32;
+        bytes[4..8].copy_from_slice(&riff_size.to_le_bytes());
+        bytes
+    }
+    // Resample the whole file, mixed to mono, to a wavetable of table_size
+    // samples. The file should hold exactly one cycle of the waveform.
+    pub fn to_wavetable(&self, table_size: usize) -> Wavetable {
+        let mono = self.to_mono();
+        if mono.is_empty() {
+            return vec![0.0; table_size];
+        }
+        let step = mono.len() as f32 / table_size as f32;
+        (0..table_size)
+            .map(|i| {
+                let position = i as f32 * step;
+                let index = position as usize;
+                let fraction = position - index as f32;
+                let next = (index + 1) % mono.len();
+                mono[index] * (1.0 - fraction) + mono[next] * fraction
+            })
+            .collect()
+    }
+}
+
+// Load a single-cycle waveform as a wavetable of table_size samples.
+pub fn load_wavetable<P: AsRef<Path>>(path: P, table_size: usize) -> Result<Wavetable, WavError> {
+    Ok(Wav::open(path)?.to_wavetable(table_size))
+}
+
+fn parse_fmt(chunk: &[u8]) -> Result<Wav, WavError> {
+    if chunk.len() < 16 {
+        return Err(WavError::Invalid("fmt chunk is too short"));
+    }
+    let mut tag = read_u16(chunk, 0);
+    let channels = read_u16(chunk, 2);
+    let sample_rate = read_u32(chunk, 4);
+    let bits = read_u16(chunk, 14);
+    if tag == WAVE_FORMAT_EXTENSIBLE {
+        if chunk.len() < 26 {
+            return Err(WavError::Invalid("extensible fmt chunk is too short"));
+        }
+        // The first two bytes of the sub-format GUID hold the format tag.
+        tag = read_u16(chunk, 24);
+    }
+    if channels == 0 {
+        return Err(WavError::Invalid("no channels"));
+    }
+    let format = match (tag, bits) {
+        (WAVE_FORMAT_PCM, 8) => SampleFormat::Pcm8,
+        (WAVE_FORMAT_PCM, 16) => SampleFormat::Pcm16,
+        (WAVE_FORMAT_PCM, 24) => SampleFormat::Pcm24,
+        (WAVE_FORMAT_PCM, 32) => SampleFormat::Pcm32,
+        (WAVE_FORMAT_IEEE_FLOAT, 32) => SampleFormat::Float32,
+        (WAVE_FORMAT_IEEE_FLOAT, 64) => SampleFormat::Float64,
+        _ => {
+            return Err(WavError::Unsupported(format!(
+                "format tag {} with {} bits per sample",
+                tag, bits
+            )))
+        }
+    };
+    Ok(Wav::new(sample_rate, channels, format))
+}
+
+fn parse_loops(chunk: &[u8]) -> Vec<Loop> {
+    let count = read_u32(chunk, 28) as usize;
+    chunk[36..]
+        .chunks_exact(24)
+        .take(count)
+        .map(|record| Loop {
+            start: read_u32(record, 8),
+            end: read_u32(record, 12),
+            play_count: read_u32(record, 20),
+        })
+        .collect()
+}
+
+fn parse_cues(chunk: &[u8]) -> Vec<CuePoint> {
+    let count = read_u32(chunk, 0) as usize;
+    chunk[4..]
+        .chunks_exact(24)
+        .take(count)
+        .map(|record| CuePoint {
+            id: read_u32(record, 0),
+            position: read_u32(record, 20),
+        })
+        .collect()
+}
+
+fn decode_sample(bytes: &[u8], format: SampleFormat) -> f32 {
+    match format {
+        SampleFormat::Pcm8 => (bytes[0] as f32 - 128.0) / 128.0,
+        SampleFormat::Pcm16 => i16::from_le_bytes([bytes[0], bytes[1]]) as f32 / 32768.0,
+        SampleFormat::Pcm24 => {
+            // Sign-extend by placing the sample in the top three bytes.
+            i32::from_le_bytes([0, bytes[0], bytes[1], bytes[2]]) as f32 / 2_147_483_648.0
+        }
+        SampleFormat::Pcm32 => {
+            i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as f32 / 2_147_483_648.0
+        }
+        SampleFormat::Float32 => f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+        SampleFormat::Float64 => {
+            let mut array = [0; 8];
+            array.copy_from_slice(&bytes[..8]);
+            f64::from_le_bytes(array) as f32
+        }
+    }
+}
+
+// PCM samples are clipped to the range -1.0 to 1.0.
+fn encode_sample(bytes: &mut Vec<u8>, sample: f32, format: SampleFormat) {
+    let clipped = sample.clamp(-1.0, 1.0) as f64;
+    match format {
+        SampleFormat::Pcm8 => bytes.push((clipped * 127.0 + 128.0).round() as u8),
+        SampleFormat::Pcm16 => {
+            bytes.extend_from_slice(&((clipped * 32767.0).round() as i16).to_le_bytes())
+        }
+        SampleFormat::Pcm24 => {
+            let value = (clipped * 8_388_607.0).round() as i32;
+            bytes.extend_from_slice(&value.to_le_bytes()[..3]);
+        }
+        SampleFormat::Pcm32 => {
+            bytes.extend_from_slice(&((clipped * 2_147_483_647.0).round() as i32).to_le_bytes())
+        }
+        SampleFormat::Float32 => bytes.extend_from_slice(&sample.to_le_bytes()),
+        SampleFormat::Float64 => bytes.extend_from_slice(&(sample as f64).to_le_bytes()),
+    }
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        bytes[offset],
+        bytes[offset + 1],
+        bytes[offset + 2],
+        bytes[offset + 3],
+    ])
+}
+
+fn push_u16(bytes: &mut Vec<u8>, value: u16) {
+    bytes.extend_from_slice(&value.to_le_bytes());
+}
+
+fn push_u32(bytes: &mut Vec<u8>, value: u32) {
+    bytes.extend_from_slice(&value.to_le_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A sweep across the full range, including both extremes.
+    fn test_signal(length: usize, offset: f32) -> Vec<f32> {
+        (0..length)
+            .map(|i| ((i as f32 * 0.37 + offset).sin() * 1.1).clamp(-1.0, 1.0))
+            .collect()
+    }
+
+    // Write and read back mono and stereo files, checking every sample is
+    // within the format's quantization step.
+    fn check_round_trip(format: SampleFormat, tolerance: f32) {
+        let left = test_signal(101, 0.0);
+        let right = test_signal(101, 1.0);
+        for wav in [
+            Wav::from_mono(&left, 44100, format),
+            Wav::from_channels(&[&left, &right], 48000, format),
+        ]
+        .iter()
+        {
+            let read = Wav::from_bytes(&wav.to_bytes()).unwrap();
+            assert_eq!(read.sample_rate, wav.sample_rate);
+            assert_eq!(read.channels, wav.channels);
+            assert_eq!(read.format, format);
+            assert_eq!(read.frames(), 101);
+            for (i, (a, b)) in wav.samples.iter().zip(read.samples.iter()).enumerate() {
+                assert!(
+                    (a - b).abs() <= tolerance,
+                    "{:?} sample {}: wrote {}, read {}",
+                    format,
+                    i,
+                    a,
+                    b
+                );
+            }
+            assert!(read.loops.is_empty());
+            assert!(read.cues.is_empty());
+            assert_eq!(read.unity_note, None);
+        }
+    }
+
+    #[test]
+    fn round_trip_pcm16() {
+        check_round_trip(SampleFormat::Pcm16, 2.0 / 32768.0);
+    }
+
+    #[test]
+    fn round_trip_pcm24() {
+        check_round_trip(SampleFormat::Pcm24, 2.0 / 8_388_608.0);
+    }
+
+    #[test]
+    fn round_trip_pcm32() {
+        check_round_trip(SampleFormat::Pcm32, 1e-7);
+    }
+
+    #[test]
+    fn round_trip_float32() {
+        check_round_trip(SampleFormat::Float32, 0.0);
+    }
+
+    #[test]
+    fn round_trip_loops_and_cues() {
+        let mut wav = Wav::from_mono(&test_signal(64, 0.0), 44100, SampleFormat::Pcm16);
+        wav.loops = vec![
+            Loop {
+                start: 4,
+                end: 31,
+                play_count: 0,
+            },
+            Loop {
+                start: 32,
+                end: 63,
+                play_count: 2,
+            },
+        ];
+        wav.cues = vec![CuePoint {
+            id: 1,
+            position: 10,
+        }];
+        wav.unity_note = Some(48);
+        let read = Wav::from_bytes(&wav.to_bytes()).unwrap();
+        assert_eq!(read.loops, wav.loops);
+        assert_eq!(read.cues, wav.cues);
+        assert_eq!(read.unity_note, Some(48));
+    }
+
+    #[test]
+    fn loop_without_unity_note_reads_as_c4() {
+        let mut wav = Wav::from_mono(&test_signal(64, 0.0), 44100, SampleFormat::Pcm16);
+        wav.loops = vec![Loop {
+            start: 0,
+            end: 63,
+            play_count: 0,
+        }];
+        let read = Wav::from_bytes(&wav.to_bytes()).unwrap();
+        assert_eq!(read.loops, wav.loops);
+        assert_eq!(read.unity_note, Some(60));
+    }
+}
@@ -1,6 +1,29 @@
 use crate::constants::TWO_PI;
 
-type Wavetable = Vec<f32>;
+pub type Wavetable = Vec<f32>;
+
+// Lowest fundamental frequency covered by a mipmap, MIDI note 0.
+pub const MIPMAP_BASE_FREQ: f32 = 8.175_799;
+
+// A set of band-limited tables for the same waveform, one per octave. Level N
+// is used for fundamentals from MIPMAP_BASE_FREQ * 2^N up to
+// MIPMAP_BASE_FREQ * 2^(N + 1), and only contains partials that stay below
+// Nyquist across that whole range. Tables are built for a specific sample
+// rate, and need rebuilding when it changes.
+#[derive(Clone)]
+pub struct MipmapTable {
+    levels: Vec<Wavetable>,
+    sample_rate: u32,
+}
+
+impl MipmapTable {
+    pub fn levels(&self) -> &[Wavetable] {
+        &self.levels
+    }
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+}
 
 #[derive(Clone)]
 pub struct Partial {
@@ -23,6 +46,66 @@ pub fn make_sin_saw_table(table_size: usize, num_partials: usize) -> Vec<Wavetab
     )
 }
 
+// Mipmapped version of make_sin_saw_table, with as many partials as each
+// level can hold below Nyquist.
+pub fn make_sin_saw_mipmaps(table_size: usize, sample_rate: u32) -> Vec<MipmapTable> {
+    let num_partials = table_size / 2;
+    vec![
+        make_mipmap(table_size, make_sine_partial(), sample_rate),
+        make_mipmap(
+            table_size,
+            make_triangle_partials(num_partials),
+            sample_rate,
+        ),
+        make_mipmap(table_size, make_square_partials(num_partials), sample_rate),
+        make_mipmap(
+            table_size,
+            make_sawtooth_partials(num_partials),
+            sample_rate,
+        ),
+    ]
+}
+
+pub fn make_mipmap(table_size: usize, partials: Vec<Partial>, sample_rate: u32) -> MipmapTable {
+    let nyquist = sample_rate as f32 / 2.0;
+    // Partials at or above half the table size can't be represented by the
+    // table.
+    let max_partial = (table_size / 2) as f32;
+    let mut levels = Vec::new();
+    let mut level_base = MIPMAP_BASE_FREQ;
+    // Always build at least one level, even when Nyquist is below the base
+    // frequency, so readers always have a table.
+    loop {
+        let level_top = level_base * 2.0;
+        let level_partials: Vec<Partial> = partials
+            .iter()
+            .enumerate()
+            // Always keep the first partial so that no level is silent.
+            .filter(|(i, p)| *i == 0 || (p.freq * level_top < nyquist && p.freq < max_partial))
+            .map(|(_, p)| p.clone())
+            .collect();
+        levels.push(make_fourier_table(table_size, &level_partials));
+        level_base = level_top;
+        if level_base >= nyquist {
+            break;
+        }
+    }
+    // Normalize every level by the gain of the base level, which has the
+    // most partials, so loudness doesn't jump between levels.
+    let peak = levels.first().map(|level| peak_level(level)).unwrap_or(0.0);
+    if peak > 0.0 {
+        for level in levels.iter_mut() {
+            for sample in level.iter_mut() {
+                *sample /= peak;
+            }
+        }
+    }
+    MipmapTable {
+        levels,
+        sample_rate,
+    }
+}
+
 pub fn make_wavetable(table_size: usize, partial_sets: Vec<Vec<Partial>>) -> Vec<Wavetable> {
     partial_sets
         .into_iter()
@@ -30,6 +113,10 @@ pub fn make_wavetable(table_size: usize, partial_sets: Vec<Vec<Partial>>) -> Vec
         .collect()
 }
 
+pub fn make_sine_table(table_size: usize) -> Wavetable {
+    make_fourier_table_norm(table_size, make_sine_partial())
+}
+
 pub fn make_exp_envelope(table_size: usize, curve: f32) -> Wavetable {
     let mut wavetable: Vec<f32> = Vec::new();
     let ts: f32 = 1.0 / table_size as f32;
@@ -98,13 +185,59 @@ fn make_sawtooth_partials(num_partials: usize) -> Vec<Partial> {
     partials
 }
 
-// This function combines creating a wavetable from a list of Partials, and
-// normalizing the output vector so it's maximum absolute value is 1.0. These
-// operations are combined here for computational efficiency.
+// Create a wavetable from a list of Partials, normalized so its maximum
+// absolute value is 1.0.
 pub fn make_fourier_table_norm(table_size: usize, partials: Vec<Partial>) -> Wavetable {
-    // Track maximum amplitude while creating wavetable, since we need to know
-    // that to be able to normalize the wavetable.
-    let mut max_value = 0.0;
+    let mut wavetable = make_fourier_table(table_size, &partials);
+    let max_value = peak_level(&wavetable);
+    if max_value == 0.0 {
+        return wavetable;
+    }
+    let norm_factor = 1.0 / max_value;
+    for sample in wavetable.iter_mut() {
+        *sample *= norm_factor;
+    }
+    wavetable
+}
+
+// Sum a list of Partials into a wavetable, without normalizing. Whole-number
+// partials repeat exactly over the table, so their samples are looked up in
+// one cycle of sine and cosine instead of calling sin per partial per sample,
+// which is far cheaper for rich waveforms. Other partials are summed
+// directly.
+pub fn make_fourier_table(table_size: usize, partials: &[Partial]) -> Wavetable {
+    let use_lookup = partials
+        .iter()
+        .all(|partial| partial.freq >= 0.0 && partial.freq.fract() == 0.0);
+    if !use_lookup {
+        return sum_partials(table_size, partials);
+    }
+    let ts = 1.0 / table_size as f32;
+    let sine: Vec<f32> = (0..table_size)
+        .map(|i| (TWO_PI * i as f32 * ts).sin())
+        .collect();
+    let cosine: Vec<f32> = (0..table_size)
+        .map(|i| (TWO_PI * i as f32 * ts).cos())
+        .collect();
+    let mut wavetable = vec![0.0; table_size];
+    for partial in partials.iter() {
+        let harmonic = partial.freq as usize % table_size;
+        // amp * sin(angle + phase), split so that only the angle varies
+        let sin_gain = partial.amp * partial.phase.cos();
+        let cos_gain = partial.amp * partial.phase.sin();
+        let mut index = 0;
+        for sample in wavetable.iter_mut() {
+            *sample += sine[index] * sin_gain + cosine[index] * cos_gain;
+            index += harmonic;
+            if index >= table_size {
+                index -= table_size;
+            }
+        }
+    }
+    wavetable
+}
+
+fn sum_partials(table_size: usize, partials: &[Partial]) -> Wavetable {
     let ts: f32 = 1.0 / table_size as f32;
     let mut wavetable: Vec<f32> = Vec::new();
     for i in 0..table_size {
@@ -113,12 +246,82 @@ pub fn make_fourier_table_norm(table_size: usize, partials: Vec<Partial>) -> Wav
             let angle = TWO_PI * partial.freq * i as f32 * ts + partial.phase;
             sample += angle.sin() * partial.amp;
         }
-        if sample.abs() > max_value { max_value = sample.abs(); }
         wavetable.push(sample);
     }
-    let norm_factor = 1.0 / max_value;
-    for sample in wavetable.iter_mut() {
-        *sample *= norm_factor;
-    }
     wavetable
 }
+
+fn peak_level(table: &[f32]) -> f32 {
+    table
+        .iter()
+        .fold(0.0, |max_value: f32, sample| max_value.max(sample.abs()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TABLE_SIZE: usize = 256;
+
+    // Estimate the amplitude of each harmonic in a table with a naive DFT.
+    fn harmonics(table: &[f32]) -> Vec<f32> {
+        let n = table.len();
+        (0..n / 2)
+            .map(|k| {
+                let (mut re, mut im) = (0.0f32, 0.0f32);
+                for (i, sample) in table.iter().enumerate() {
+                    let angle = TWO_PI * (k * i % n) as f32 / n as f32;
+                    re += sample * angle.cos();
+                    im += sample * angle.sin();
+                }
+                (re * re + im * im).sqrt() * 2.0 / n as f32
+            })
+            .collect()
+    }
+
+    #[test]
+    fn levels_stay_below_nyquist() {
+        let sample_rate = 44100;
+        let nyquist = sample_rate as f32 / 2.0;
+        let mipmap = make_mipmap(
+            TABLE_SIZE,
+            make_sawtooth_partials(TABLE_SIZE / 2),
+            sample_rate,
+        );
+        assert_eq!(mipmap.sample_rate(), sample_rate);
+        assert!(mipmap.levels().len() > 1);
+        for (level, table) in mipmap.levels().iter().enumerate() {
+            let level_top = MIPMAP_BASE_FREQ * 2.0f32.powi(level as i32 + 1);
+            for (harmonic, amp) in harmonics(table).iter().enumerate().skip(2) {
+                if harmonic as f32 * level_top >= nyquist {
+                    assert!(*amp < 1e-4, "level {} harmonic {}", level, harmonic);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn level_gains_are_normalized() {
+        let mipmap = make_mipmap(TABLE_SIZE, make_square_partials(TABLE_SIZE / 2), 48000);
+        let levels = mipmap.levels();
+        assert!((peak_level(&levels[0]) - 1.0).abs() < 1e-6);
+        for table in levels.iter() {
+            // Every level keeps the fundamental at the same amplitude.
+            let fundamental = harmonics(table)[1];
+            let base = harmonics(&levels[0])[1];
+            assert!((fundamental - base).abs() < 1e-3);
+        }
+    }
+
+    #[test]
+    fn low_sample_rates_build_one_level() {
+        for &sample_rate in &[0, 8, 16] {
+            let mipmap = make_mipmap(
+                TABLE_SIZE,
+                make_sawtooth_partials(TABLE_SIZE / 2),
+                sample_rate,
+            );
+            assert_eq!(mipmap.levels().len(), 1);
+        }
+    }
+}